@@ -0,0 +1,528 @@
+use std::{borrow::Cow, fmt, hash::{DefaultHasher, Hasher}, iter};
+
+use itertools::Itertools;
+use png::{BitDepth, ColorType};
+
+use crate::{histogram::{color_histogram, Histogram}, Image, Reductions};
+
+/// Largest per-channel difference between neighbors still counted as a smooth step.
+pub const SMOOTH_STEP: u16 = 6;
+/// [`Analysis::smooth_gradients`] above which an image with many colors is likely to band.
+pub const BANDING_SMOOTH_GRADIENTS: f32 = 0.5;
+/// Unique colors per pixel from which an image may be a photograph rather than drawn art.
+pub const PHOTO_COLOR_DENSITY: f32 = 0.05;
+/// [`gradient_energy`] from which an image may be a photograph: fine texture and noise
+/// everywhere rather than flat areas and smooth ramps.
+pub const PHOTO_GRADIENT_ENERGY: f32 = 3.0;
+/// Pixels below which an image is too small to tell from art, e.g. an icon.
+pub const PHOTO_MIN_PIXELS: u64 = 64 * 64;
+/// Unique colors below which a palette serves better than any lossy format.
+pub const PHOTO_MIN_COLORS: usize = 256;
+/// About the smallest JFIF headers, with quantization and Huffman tables.
+const JPEG_HEADER_BYTES: u64 = 600;
+/// About the RIFF and VP8 frame headers of a lossy WebP.
+const WEBP_HEADER_BYTES: u64 = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlphaUsage {
+    Opaque,
+    Binary,
+    Full,
+}
+
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    pub color_type: ColorType,
+    pub unique_colors: usize,
+    pub alpha: AlphaUsage,
+    /// Share of pixels that are fully transparent.
+    pub transparent_pixels: f32,
+    /// Share of pixels that are neither fully transparent nor opaque.
+    pub translucent_pixels: f32,
+    pub grayscale: bool,
+    pub layout: Layout,
+    /// Channels carrying no information of their own.
+    pub channels: Vec<ChannelIssue>,
+    /// Share of differing neighbor pixels that differ by a small step, high for smooth gradients.
+    pub smooth_gradients: f32,
+    pub texture: Texture,
+    /// Every how many rows were looked at; above 1 the figures are estimates and
+    /// `unique_colors` a lower bound.
+    pub sample: u32,
+}
+
+/// What a texture looks like it is for, judged from its colors; see [`Analysis::texture`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Texture {
+    /// Tangent-space normals: colors decode to vectors of about unit length pointing out of the
+    /// surface, so blue dominates.
+    NormalMap,
+    /// A single channel of data such as roughness, height or a cutout mask: gray, or color with
+    /// only one channel that varies, and no translucent pixels.
+    Mask,
+    /// Colors meant to be seen.
+    Albedo,
+}
+
+impl Texture {
+    /// The block compression format GPU pipelines commonly use for this kind of texture.
+    pub fn bcn_format(self, alpha: AlphaUsage) -> &'static str {
+        match (self, alpha) {
+            (Texture::NormalMap, _) => "BC5",
+            (Texture::Mask, _) => "BC4",
+            (Texture::Albedo, AlphaUsage::Full) => "BC7",
+            (Texture::Albedo, _) => "BC1",
+        }
+    }
+}
+
+impl fmt::Display for Texture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Texture::NormalMap => "normal-map",
+            Texture::Mask => "mask",
+            Texture::Albedo => "albedo",
+        })
+    }
+}
+
+/// Share of pixels that must decode to a unit-length, outward vector for a normal map.
+pub const NORMAL_MAP_PIXELS: f32 = 0.9;
+
+/// A degenerate channel, named by one of `r`, `g`, `b` or `a`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelIssue {
+    /// Every pixel has the same value in this channel.
+    Constant { channel: char, value: u8 },
+    /// The channel always equals an earlier one.
+    Duplicate { channel: char, of: char },
+}
+
+impl fmt::Display for ChannelIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelIssue::Constant { channel, value } => write!(f, "{}={}", channel, value),
+            ChannelIssue::Duplicate { channel, of } => write!(f, "{}={}", channel, of),
+        }
+    }
+}
+
+/// Repetition in the pixel layout; repeated rows are what the Up filter compresses best.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    /// Left half mirrors the right half.
+    pub mirror_horizontal: bool,
+    /// Top half mirrors the bottom half.
+    pub mirror_vertical: bool,
+    /// Smallest tile the image repeats, when it repeats at least twice along some axis.
+    pub tile: Option<(u32, u32)>,
+    /// Scanlines identical to the one above, out of `rows`.
+    pub repeated_rows: usize,
+    pub rows: usize,
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mirror = match (self.mirror_horizontal, self.mirror_vertical) {
+            (false, false) => "none",
+            (true, false) => "h",
+            (false, true) => "v",
+            (true, true) => "h,v",
+        };
+        write!(f, "mirror={} tile=", mirror)?;
+        match self.tile {
+            Some((w, h)) => write!(f, "{}x{}", w, h)?,
+            None => f.write_str("none")?,
+        }
+        write!(f, " repeated_rows={}/{}", self.repeated_rows, self.rows)
+    }
+}
+
+impl Analysis {
+    /// Whether merging or palettizing colors is likely to leave visible bands: many colors
+    /// that mostly change in small steps.
+    pub fn likely_to_band(&self) -> bool {
+        self.unique_colors > 256 && self.smooth_gradients >= BANDING_SMOOTH_GRADIENTS
+    }
+
+    pub fn suggestions(&self) -> Vec<String> {
+        let mut out = Vec::new();
+        let has_color = matches!(self.color_type, ColorType::Rgb | ColorType::Rgba);
+        let has_alpha = matches!(self.color_type, ColorType::GrayscaleAlpha | ColorType::Rgba);
+        if has_color && self.grayscale {
+            out.push("grayscale".to_string());
+        }
+        match self.alpha {
+            AlphaUsage::Opaque if has_alpha => out.push("strip-alpha".to_string()),
+            AlphaUsage::Binary if has_alpha => out.push("tRNS".to_string()),
+            _ => {}
+        }
+        if self.unique_colors <= 256 && self.color_type != ColorType::Indexed && !(self.grayscale && self.alpha == AlphaUsage::Opaque) {
+            out.push(format!("palette({}-bit)", min_index_depth(self.unique_colors)));
+        }
+        out
+    }
+
+    /// Why [`Image::reduce_with`] found nothing to reduce, e.g. "14,302 unique colors, 8%
+    /// translucent pixels".
+    pub fn why_kept(&self, reductions: &Reductions) -> String {
+        let has_color = matches!(self.color_type, ColorType::Rgb | ColorType::Rgba);
+        let has_alpha = matches!(self.color_type, ColorType::GrayscaleAlpha | ColorType::Rgba);
+        let mut reasons = Vec::new();
+        if has_color && self.grayscale && !reductions.gray {
+            reasons.push("gray reduction disabled".to_string());
+        }
+        if has_color || has_alpha {
+            if self.unique_colors > 256 {
+                reasons.push(format!("{} unique colors", thousands(self.unique_colors)));
+            } else if !reductions.palette {
+                reasons.push(format!("{} unique colors, palette disabled", self.unique_colors));
+            } else {
+                reasons.push(format!("{} unique colors, smaller without a palette", self.unique_colors));
+            }
+        } else {
+            reasons.push(format!("{} gray levels", self.unique_colors));
+        }
+        if has_alpha {
+            match self.alpha {
+                AlphaUsage::Full => reasons.push(format!("{:.0}% translucent pixels", 100.0 * self.translucent_pixels)),
+                AlphaUsage::Binary => reasons.push(format!("{:.0}% transparent pixels", 100.0 * self.transparent_pixels)),
+                AlphaUsage::Opaque if !reductions.alpha_strip => reasons.push("alpha strip disabled".to_string()),
+                AlphaUsage::Opaque => {}
+            }
+        }
+        reasons.join(", ")
+    }
+}
+
+/// `n` with thousands separated by commas.
+fn thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let alpha = match self.alpha {
+            AlphaUsage::Opaque => "opaque",
+            AlphaUsage::Binary => "binary",
+            AlphaUsage::Full => "full",
+        };
+        let suggestions = self.suggestions();
+        let channels = self.channels.iter().map(ToString::to_string).collect::<Vec<_>>();
+        if self.sample > 1 {
+            write!(f, "sample=1/{} ", self.sample)?;
+        }
+        write!(
+            f,
+            "colors{}{} alpha={} grayscale={} texture={}({}) {} channels={} gradients={:.2} banding={} suggest={}",
+            if self.sample > 1 { ">=" } else { "=" },
+            self.unique_colors,
+            alpha,
+            if self.grayscale { "yes" } else { "no" },
+            self.texture,
+            self.texture.bcn_format(self.alpha),
+            self.layout,
+            if channels.is_empty() { "ok".to_string() } else { channels.join(",") },
+            self.smooth_gradients,
+            if self.likely_to_band() { "likely" } else { "unlikely" },
+            if suggestions.is_empty() { "none".to_string() } else { suggestions.join(",") },
+        )
+    }
+}
+
+fn min_index_depth(colors: usize) -> u8 {
+    match colors {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    }
+}
+
+/// Analyzes the colors of `image`; 16-bit images are judged by the high byte of each sample.
+pub fn analyze(image: &Image) -> Analysis {
+    let histogram = match image.bit_depth {
+        BitDepth::Sixteen => color_histogram(&image.data.iter().step_by(2).copied().collect::<Vec<_>>(), image.color_type),
+        _ => color_histogram(&image.data, image.color_type),
+    };
+    let (mut translucent, mut transparent) = (0, 0);
+    let mut grayscale = true;
+    for ([r, g, b, a], n) in histogram.iter() {
+        match a {
+            0xFF => {}
+            0 => transparent += n,
+            _ => translucent += n,
+        }
+        grayscale &= r == g && r == b;
+    }
+    let alpha = if translucent > 0 {
+        AlphaUsage::Full
+    } else if transparent > 0 {
+        AlphaUsage::Binary
+    } else {
+        AlphaUsage::Opaque
+    };
+    let channels = channel_issues(&histogram, image.color_type, grayscale);
+    Analysis {
+        color_type: image.color_type,
+        unique_colors: histogram.len(),
+        alpha,
+        transparent_pixels: transparent as f32 / histogram.total().max(1) as f32,
+        translucent_pixels: translucent as f32 / histogram.total().max(1) as f32,
+        grayscale,
+        layout: layout(image),
+        texture: texture(&histogram, grayscale, alpha, &channels),
+        channels,
+        smooth_gradients: smooth_gradients(image),
+        sample: 1,
+    }
+}
+
+/// Like [`analyze`], but only looks at every `every`-th row, for a quick estimate on huge
+/// images. Colors and alpha values missing from the sampled rows go unnoticed.
+pub fn analyze_sampled(image: &Image, every: u32) -> Analysis {
+    if every <= 1 || image.width == 0 || image.height == 0 {
+        return analyze(image);
+    }
+    let row_len = image.data.len() / image.height as usize;
+    let data = image.data.chunks(row_len).step_by(every as usize).flatten().copied().collect::<Vec<_>>();
+    let sampled = Image {
+        width: image.width,
+        height: image.height.div_ceil(every),
+        color_type: image.color_type,
+        bit_depth: image.bit_depth,
+        palette: image.palette.clone(),
+        trns: image.trns.clone(),
+        data: Cow::Owned(data),
+        text: Vec::new(),
+        chunks: Vec::new(),
+        premultiplied: image.premultiplied,
+    };
+    Analysis { sample: every, ..analyze(&sampled) }
+}
+
+/// Share of horizontally or vertically adjacent pixel pairs, among those that differ at all,
+/// where no channel steps by more than [`SMOOTH_STEP`] (in 8-bit units).
+pub fn smooth_gradients(image: &Image) -> f32 {
+    let (mut smooth, mut differing) = (0u64, 0u64);
+    neighbor_steps(image, |step, differs| {
+        if differs {
+            differing += 1;
+            smooth += (step <= SMOOTH_STEP) as u64;
+        }
+    });
+    if differing == 0 {
+        0.0
+    } else {
+        smooth as f32 / differing as f32
+    }
+}
+
+/// Mean of the largest per-channel step (in 8-bit units) between horizontally or vertically
+/// adjacent pixels: near 0 for flat art, a few units for smooth renders, higher for photographs.
+pub fn gradient_energy(image: &Image) -> f32 {
+    let (mut total, mut pairs) = (0u64, 0u64);
+    neighbor_steps(image, |step, _| {
+        total += step as u64;
+        pairs += 1;
+    });
+    if pairs == 0 {
+        0.0
+    } else {
+        total as f32 / pairs as f32
+    }
+}
+
+/// Calls `f` for every horizontally or vertically adjacent pixel pair with the largest
+/// per-channel step between them, in 8-bit units, and whether they differ at all.
+fn neighbor_steps(image: &Image, mut f: impl FnMut(u16, bool)) {
+    let (width, height) = (image.width as usize, image.height as usize);
+    if width == 0 || height == 0 {
+        return;
+    }
+    let wide = image.bit_depth == BitDepth::Sixteen;
+    let samples = image.color_type.samples();
+    let pixel_len = samples * if wide { 2 } else { 1 };
+    let row_len = width * pixel_len;
+    let mut compare = |a: &[u8], b: &[u8]| {
+        let step = (0..samples)
+            .map(|k| match wide {
+                true => (u16::from_be_bytes([a[2 * k], a[2 * k + 1]]) >> 8).abs_diff(u16::from_be_bytes([b[2 * k], b[2 * k + 1]]) >> 8),
+                false => a[k].abs_diff(b[k]) as u16,
+            })
+            .max()
+            .unwrap_or(0);
+        f(step, a != b);
+    };
+    let rows = image.data.chunks_exact(row_len).collect::<Vec<_>>();
+    for (y, row) in rows.iter().enumerate() {
+        for (a, b) in row.chunks_exact(pixel_len).tuple_windows() {
+            compare(a, b);
+        }
+        if let Some(below) = rows.get(y + 1) {
+            for (a, b) in row.chunks_exact(pixel_len).zip(below.chunks_exact(pixel_len)) {
+                compare(a, b);
+            }
+        }
+    }
+}
+
+/// Rough sizes an image would have in a lossy format at a typical web quality (JPEG 85,
+/// WebP 80), for images that look photographic; see [`lossy_estimate`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LossyEstimate {
+    /// Unique colors per pixel.
+    pub color_density: f32,
+    /// See [`gradient_energy`].
+    pub gradient_energy: f32,
+    /// `None` when the image has transparency, which JPEG cannot keep.
+    pub jpeg: Option<u64>,
+    pub webp: u64,
+}
+
+impl fmt::Display for LossyEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "colors/pixel={:.2} gradient_energy={:.1}", self.color_density, self.gradient_energy)?;
+        if let Some(jpeg) = self.jpeg {
+            write!(f, " jpeg~{}", jpeg)?;
+        }
+        write!(f, " webp~{}", self.webp)
+    }
+}
+
+/// Estimates lossy sizes when `image` looks photographic: at least [`PHOTO_MIN_PIXELS`],
+/// [`PHOTO_MIN_COLORS`], [`PHOTO_COLOR_DENSITY`] unique colors per pixel and
+/// [`PHOTO_GRADIENT_ENERGY`]. The bits per pixel grow with the gradient energy, from about 1
+/// for smooth photographs to 4 for noisy ones; WebP takes about 70% of JPEG plus a quarter bit
+/// per pixel for a translucent alpha plane. Both include their fixed headers.
+pub fn lossy_estimate(image: &Image, analysis: &Analysis) -> Option<LossyEstimate> {
+    let pixels = image.width as u64 * image.height as u64;
+    if pixels < PHOTO_MIN_PIXELS || analysis.unique_colors < PHOTO_MIN_COLORS || image.color_type == ColorType::Indexed {
+        return None;
+    }
+    let color_density = analysis.unique_colors as f32 / pixels as f32 * analysis.sample as f32;
+    let gradient_energy = gradient_energy(image);
+    if color_density < PHOTO_COLOR_DENSITY || gradient_energy < PHOTO_GRADIENT_ENERGY {
+        return None;
+    }
+    let jpeg_bpp = (0.4 + 0.2 * gradient_energy).min(4.0);
+    let alpha_bpp = match analysis.alpha {
+        AlphaUsage::Opaque => 0.0,
+        AlphaUsage::Binary => 0.05,
+        AlphaUsage::Full => 0.25,
+    };
+    let bytes = |bpp: f32| (pixels as f64 * bpp as f64 / 8.0).ceil() as u64;
+    Some(LossyEstimate {
+        color_density: color_density.min(1.0),
+        gradient_energy,
+        jpeg: (analysis.alpha == AlphaUsage::Opaque).then(|| JPEG_HEADER_BYTES + bytes(jpeg_bpp)),
+        webp: WEBP_HEADER_BYTES + bytes(0.7 * jpeg_bpp + alpha_bpp),
+    })
+}
+
+/// Tells normal maps, masks and albedo apart. A normal map's colors decode, as `2c/255 - 1` per
+/// channel, to vectors of length 0.8 to 1.2 with a positive z for [`NORMAL_MAP_PIXELS`] of the
+/// pixels; a mask has at most one color channel carrying information and no translucency.
+fn texture(histogram: &Histogram, grayscale: bool, alpha: AlphaUsage, channels: &[ChannelIssue]) -> Texture {
+    let color_issues = channels.iter().filter(|issue| match issue {
+        ChannelIssue::Constant { channel, .. } | ChannelIssue::Duplicate { channel, .. } => *channel != 'a',
+    });
+    if alpha != AlphaUsage::Full && (grayscale || color_issues.count() >= 2) {
+        return Texture::Mask;
+    }
+    let unit = histogram
+        .iter()
+        .filter(|([r, g, b, _], _)| {
+            let [x, y, z] = [r, g, b].map(|c| *c as f32 / 127.5 - 1.0);
+            z > 0.0 && (0.64..=1.44).contains(&(x * x + y * y + z * z))
+        })
+        .map(|(_, n)| n)
+        .sum::<u64>();
+    if unit as f32 >= NORMAL_MAP_PIXELS * histogram.total().max(1) as f32 {
+        Texture::NormalMap
+    } else {
+        Texture::Albedo
+    }
+}
+
+/// Finds constant and duplicated channels. Gray images and a constant opaque alpha are left
+/// out, being already covered by the grayscale and alpha reports.
+fn channel_issues(histogram: &Histogram, color: ColorType, grayscale: bool) -> Vec<ChannelIssue> {
+    const NAMES: [char; 4] = ['r', 'g', 'b', 'a'];
+    let channels: &[usize] = match color {
+        ColorType::Rgb if !grayscale => &[0, 1, 2],
+        ColorType::Rgba if !grayscale => &[0, 1, 2, 3],
+        ColorType::GrayscaleAlpha | ColorType::Rgba => &[3],
+        _ => &[],
+    };
+    let Some((first, _)) = histogram.iter().next() else {
+        return Vec::new();
+    };
+    let mut issues = Vec::new();
+    for (k, &c) in channels.iter().enumerate() {
+        if histogram.iter().all(|(rgba, _)| rgba[c] == first[c]) {
+            if !(c == 3 && first[c] == 0xFF) {
+                issues.push(ChannelIssue::Constant { channel: NAMES[c], value: first[c] });
+            }
+        } else if let Some(&of) = channels[..k].iter().find(|&&o| histogram.iter().all(|(rgba, _)| rgba[c] == rgba[o])) {
+            issues.push(ChannelIssue::Duplicate { channel: NAMES[c], of: NAMES[of] });
+        }
+    }
+    issues
+}
+
+pub fn layout(image: &Image) -> Layout {
+    let (width, height) = (image.width as usize, image.height as usize);
+    if width == 0 || height == 0 {
+        return Layout { mirror_horizontal: false, mirror_vertical: false, tile: None, repeated_rows: 0, rows: height };
+    }
+    let row_len = image.data.len() / height;
+    let pixel_len = row_len / width;
+    let rows = image.data.chunks(row_len).collect::<Vec<_>>();
+    let pixel = |x: usize, y: usize| &rows[y][x * pixel_len..][..pixel_len];
+    let column = |x: usize| (0..height).map(move |y| pixel(x, y));
+
+    let mirror_horizontal = (0..width / 2).all(|x| column(x).eq(column(width - 1 - x)));
+    let mirror_vertical = (0..height / 2).all(|y| rows[y] == rows[height - 1 - y]);
+
+    let column_hashes = (0..width).map(|x| hash(column(x))).collect::<Vec<_>>();
+    let row_hashes = rows.iter().map(|&row| hash(iter::once(row))).collect::<Vec<_>>();
+    let tile_width = repeat_period(&column_hashes).filter(|&p| (p..width).all(|x| column(x).eq(column(x - p))));
+    let tile_height = repeat_period(&row_hashes).filter(|&p| (p..height).all(|y| rows[y] == rows[y - p]));
+    let tile = match (tile_width, tile_height) {
+        (None, None) => None,
+        (w, h) => Some((w.unwrap_or(width) as u32, h.unwrap_or(height) as u32)),
+    };
+    Layout { mirror_horizontal, mirror_vertical, tile, repeated_rows: image.repeated_rows(), rows: height }
+}
+
+fn hash<'a>(parts: impl Iterator<Item = &'a [u8]>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    parts.for_each(|part| hasher.write(part));
+    hasher.finish()
+}
+
+/// Smallest period of `seq` (via the KMP failure function), if it repeats at least twice.
+fn repeat_period(seq: &[u64]) -> Option<usize> {
+    let mut border = vec![0; seq.len()];
+    for i in 1..seq.len() {
+        let mut k = border[i - 1];
+        while k > 0 && seq[i] != seq[k] {
+            k = border[k - 1];
+        }
+        if seq[i] == seq[k] {
+            k += 1;
+        }
+        border[i] = k;
+    }
+    let period = seq.len() - border.last().copied().unwrap_or(0);
+    (period * 2 <= seq.len()).then_some(period)
+}
@@ -0,0 +1,145 @@
+//! Simulated annealing over palette orders.
+
+use std::borrow::Cow;
+
+use png::{BitDepth, ColorType};
+
+use crate::{compress_scanlines, with_scratch, Control, FilterMode, Image, Strategy, TrialConfig};
+
+/// Proposals tried per image.
+const STEPS: usize = 1500;
+/// Rows are sampled as this many evenly spaced bands, so Up and Paeth still see real neighbors.
+const BANDS: usize = 8;
+const BAND_ROWS: usize = 8;
+/// Starting and final temperature as a share of the sample's initial compressed size.
+const START_TEMPERATURE: f64 = 0.002;
+const END_TEMPERATURE: f64 = 0.000_02;
+
+/// Order of the palette of `image`, as old indices by new index, whose indices compress best on
+/// a sample of rows. Entries only move within the part covered by tRNS or the part after it, so
+/// tRNS stays as short as it was. `None` if there is nothing to reorder.
+pub(crate) fn palette_order(image: &Image, seed: u64) -> Option<Vec<u8>> {
+    let palette = image.palette.as_ref().filter(|_| image.color_type == ColorType::Indexed && image.bit_depth == BitDepth::Eight)?;
+    let n = palette.len() / 3;
+    let translucent = image.trns.as_ref().map_or(0, |t| t.len().min(n));
+    let group = |i: usize| if i < translucent { 0..translucent } else { translucent..n };
+    if n < 2 || group(0).len() < 2 && group(n - 1).len() < 2 {
+        return None;
+    }
+    let (width, height) = (image.width as usize, image.height as usize);
+    let sample = if height <= BANDS * BAND_ROWS {
+        image.data.to_vec()
+    } else {
+        (0..BANDS).flat_map(|b| &image.data[b * (height - BAND_ROWS) / (BANDS - 1) * width..][..BAND_ROWS * width]).copied().collect()
+    };
+    let cost = |order: &[u8]| {
+        let mut to_new = [0u8; 256];
+        for (new, &old) in order.iter().enumerate() {
+            to_new[old as usize] = new as u8;
+        }
+        let sampled = Image {
+            height: (sample.len() / width.max(1)) as u32,
+            palette: None,
+            trns: None,
+            data: Cow::Owned(sample.iter().map(|&i| to_new[i as usize]).collect()),
+            text: Vec::new(),
+            chunks: Vec::new(),
+            ..*image
+        };
+        let config = TrialConfig { filter: FilterMode::Adaptive, strategy: Strategy::Default, packed: false };
+        with_scratch(|scratch| compress_scanlines(&sampled, config, Control::NONE, scratch).map_or(usize::MAX, <[u8]>::len))
+    };
+
+    let mut rng = Rng::new(seed);
+    let mut order = (0..n).map(|i| i as u8).collect::<Vec<_>>();
+    let mut current = cost(&order);
+    let (mut best, mut best_order) = (current, order.clone());
+    let start = current as f64 * START_TEMPERATURE;
+    let cooling = (END_TEMPERATURE / START_TEMPERATURE).powf(1.0 / STEPS as f64);
+    let mut temperature = start;
+    for _ in 0..STEPS {
+        let i = rng.below(n);
+        let range = group(i);
+        if range.len() < 2 {
+            continue;
+        }
+        let j = range.start + rng.below(range.len());
+        if i == j {
+            continue;
+        }
+        let (lo, hi) = (i.min(j), i.max(j));
+        // Swaps move single colors far; reversals keep runs of related colors together.
+        let swap = rng.below(2) == 0;
+        if swap {
+            order.swap(lo, hi);
+        } else {
+            order[lo..=hi].reverse();
+        }
+        let proposed = cost(&order);
+        let delta = proposed as f64 - current as f64;
+        if delta <= 0.0 || rng.unit() < (-delta / temperature).exp() {
+            current = proposed;
+            if current < best {
+                (best, best_order) = (current, order.clone());
+            }
+        } else if swap {
+            order.swap(lo, hi);
+        } else {
+            order[lo..=hi].reverse();
+        }
+        temperature *= cooling;
+    }
+    Some(best_order)
+}
+
+/// xorshift64*: small, fast and the same on every platform, which is all randomized searches
+/// need to stay reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng((seed ^ 0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// Uniform in `0.0..1.0`.
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_palette_order_is_a_permutation() {
+        let (width, height) = (32, 32);
+        let image = Image {
+            width,
+            height,
+            color_type: ColorType::Indexed,
+            bit_depth: BitDepth::Eight,
+            palette: Some((0..256 * 3).map(|i| (i * 7 % 256) as u8).collect()),
+            trns: None,
+            data: Cow::Owned((0..width * height).map(|i| (i * 31 % 256) as u8).collect()),
+            text: Vec::new(),
+            chunks: Vec::new(),
+            premultiplied: false,
+        };
+        let mut order = palette_order(&image, 0).unwrap();
+        assert_eq!(order.len(), 256);
+        order.sort_unstable();
+        assert!(order.iter().enumerate().all(|(i, &old)| i == old as usize));
+    }
+}
@@ -0,0 +1,419 @@
+use std::{borrow::Cow, io::Write};
+
+use png::{BitDepth, BlendOp, ColorType, Decoder, DisposeOp, Transformations};
+
+use crate::{chunks::{self, text_chunk, write_chunk, SIGNATURE}, compress_best, rgba_samples, Effort, Error, Image, OptimizeOptions, Result};
+
+/// One frame of an animation, composited onto the full canvas.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// RGBA with 8-bit samples, the size of the canvas.
+    pub image: Image<'static>,
+    pub delay_num: u16,
+    /// Zero means 100, as in `fcTL`.
+    pub delay_den: u16,
+}
+
+impl Frame {
+    pub fn delay_ms(&self) -> u32 {
+        let den = if self.delay_den == 0 { 100 } else { self.delay_den as u32 };
+        (self.delay_num as u32 * 1000 + den / 2) / den
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Animation {
+    pub width: u32,
+    pub height: u32,
+    /// Zero means forever.
+    pub plays: u32,
+    pub frames: Vec<Frame>,
+}
+
+/// Whether an APNG has 16-bit samples, which [`decode_animation`] refuses.
+pub fn is_16_bit(src: &[u8]) -> bool {
+    chunks::parse(src).chunks.iter().find(|c| &c.kind == b"IHDR").is_some_and(|c| c.data.get(8) == Some(&16))
+}
+
+/// Decodes every frame of an APNG, applying blend and dispose operations. Frames are 8-bit, so
+/// animations with 16-bit samples are an error rather than silently reduced.
+pub fn decode_animation(src: &[u8]) -> Result<Animation> {
+    let mut decoder = Decoder::new(src);
+    decoder.set_transformations(Transformations::EXPAND);
+    let mut reader = decoder.read_info()?;
+    let info = reader.info();
+    if info.bit_depth == BitDepth::Sixteen {
+        return Err(Error::Format("16-bit animations are not supported: their frames would lose precision".to_string()));
+    }
+    let (width, height) = (info.width, info.height);
+    let Some(control) = info.animation_control else {
+        return Err(Error::Format("not an animated PNG".to_string()));
+    };
+    let mut buf = vec![0; reader.output_buffer_size()];
+    if info.frame_control.is_none() {
+        // The default image is not part of the animation.
+        reader.next_frame(&mut buf)?;
+    }
+    let stride = width as usize * 4;
+    let mut canvas = vec![0u8; stride * height as usize];
+    let mut frames = Vec::with_capacity(control.num_frames as usize);
+    for i in 0..control.num_frames {
+        let out = reader.next_frame(&mut buf)?;
+        let fc = *reader.info().frame_control().ok_or_else(|| Error::Format(format!("frame {} has no fcTL", i)))?;
+        if fc.x_offset + fc.width > width || fc.y_offset + fc.height > height {
+            return Err(Error::Format(format!("frame {} lies outside the canvas", i)));
+        }
+        let region = rgba_samples(&buf[..out.line_size * out.height as usize], out.color_type);
+        let previous = (fc.dispose_op == DisposeOp::Previous && i > 0).then(|| canvas.clone());
+        let region_stride = fc.width as usize * 4;
+        for (y, src_row) in region.chunks_exact(region_stride).enumerate() {
+            let at = (fc.y_offset as usize + y) * stride + fc.x_offset as usize * 4;
+            let dst_row = &mut canvas[at..at + region_stride];
+            match fc.blend_op {
+                BlendOp::Source => dst_row.copy_from_slice(src_row),
+                BlendOp::Over => dst_row.chunks_exact_mut(4).zip(src_row.chunks_exact(4)).for_each(|(d, s)| over(d, s)),
+            }
+        }
+        frames.push(Frame {
+            image: Image {
+                width,
+                height,
+                color_type: ColorType::Rgba,
+                bit_depth: BitDepth::Eight,
+                palette: None,
+                trns: None,
+                data: Cow::Owned(canvas.clone()),
+                text: Vec::new(),
+                chunks: Vec::new(),
+                premultiplied: false,
+            },
+            delay_num: fc.delay_num,
+            delay_den: fc.delay_den,
+        });
+        match (fc.dispose_op, previous) {
+            (DisposeOp::Previous, Some(previous)) => canvas = previous,
+            (DisposeOp::Background | DisposeOp::Previous, _) => {
+                for y in fc.y_offset..fc.y_offset + fc.height {
+                    let at = y as usize * stride + fc.x_offset as usize * 4;
+                    canvas[at..at + region_stride].fill(0);
+                }
+            }
+            (DisposeOp::None, _) => {}
+        }
+    }
+    Ok(Animation { width, height, plays: control.num_plays, frames })
+}
+
+/// Composites `src` over `dst`, both non-premultiplied RGBA.
+fn over(dst: &mut [u8], src: &[u8]) {
+    match src[3] {
+        0xFF => dst.copy_from_slice(src),
+        0 => {}
+        sa => {
+            let (sa, da) = (sa as u32, dst[3] as u32);
+            let da = da * (255 - sa) / 255;
+            let a = sa + da;
+            for i in 0..3 {
+                dst[i] = ((src[i] as u32 * sa + dst[i] as u32 * da + a / 2) / a) as u8;
+            }
+            dst[3] = a as u8;
+        }
+    }
+}
+
+/// A frame as it will be stored: a region of the canvas and how to combine it.
+struct Planned {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    rgba: Vec<u8>,
+    delay_num: u16,
+    delay_den: u16,
+    dispose: DisposeOp,
+    blend: BlendOp,
+}
+
+/// Encodes an animation, storing for each frame only the region that differs from what the
+/// previous frame leaves behind; for each frame the dispose operation of its predecessor is
+/// chosen to make that region smallest, and unchanged frames extend the previous delay.
+///
+/// Regions are also tried blended over the canvas with their unchanged pixels transparent;
+/// since that can force an alpha channel on every frame, the smaller of both encodings is kept.
+pub fn encode_animation<W: Write>(mut w: W, animation: &Animation, opts: &OptimizeOptions) -> Result<()> {
+    let (width, height) = (animation.width, animation.height);
+    if animation.frames.is_empty() {
+        return Err(Error::Format("an animation needs at least one frame".to_string()));
+    }
+    for (i, frame) in animation.frames.iter().enumerate() {
+        if (frame.image.width, frame.image.height) != (width, height) || frame.image.color_type != ColorType::Rgba || frame.image.bit_depth != BitDepth::Eight {
+            return Err(Error::Format(format!("frame {} is not {}x{} 8-bit RGBA", i, width, height)));
+        }
+    }
+    let mut source = Vec::new();
+    write_planned(&mut source, animation, &plan(animation, false, opts)?, opts)?;
+    let mut over = Vec::new();
+    write_planned(&mut over, animation, &plan(animation, true, opts)?, opts)?;
+    w.write_all(if over.len() < source.len() { &over } else { &source })?;
+    Ok(())
+}
+
+fn plan(animation: &Animation, blend_over: bool, opts: &OptimizeOptions) -> Result<Vec<Planned>> {
+    let (width, height) = (animation.width, animation.height);
+    let first = &animation.frames[0];
+    let estimate_opts = OptimizeOptions { effort: Effort::Fast, ..opts.clone() };
+    let mut planned = vec![Planned {
+        x: 0,
+        y: 0,
+        width,
+        height,
+        rgba: first.image.data.to_vec(),
+        delay_num: first.delay_num,
+        delay_den: first.delay_den,
+        dispose: DisposeOp::None,
+        blend: BlendOp::Source,
+    }];
+    // Canvas before and after drawing the last planned frame.
+    let mut before = vec![0; first.image.data.len()];
+    let mut after = first.image.data.to_vec();
+    for frame in &animation.frames[1..] {
+        let target = &frame.image.data[..];
+        let last = planned.last_mut().unwrap();
+        if after == target {
+            (last.delay_num, last.delay_den) = add_delays((last.delay_num, last.delay_den), (frame.delay_num, frame.delay_den));
+            continue;
+        }
+        let mut cleared = after.clone();
+        clear(&mut cleared, width, last);
+        let mut bases = vec![(DisposeOp::None, after), (DisposeOp::Background, cleared)];
+        if planned.len() > 1 {
+            bases.push((DisposeOp::Previous, before));
+        }
+        let (dispose, base, bounds) = bases
+            .into_iter()
+            .map(|(dispose, base)| {
+                let bounds = changed_bounds(&base, target, width, height);
+                (dispose, base, bounds)
+            })
+            .min_by_key(|(_, _, bounds)| bounds.map_or(0, |(_, _, w, h)| w as u64 * h as u64))
+            .unwrap();
+        // A frame needs at least one pixel even when disposal alone produces the target.
+        let (x, y, w, h) = bounds.unwrap_or((0, 0, 1, 1));
+        planned.last_mut().unwrap().dispose = dispose;
+        let mut rgba = crop(target, width, x, y, w, h);
+        let mut blend = BlendOp::Source;
+        if let Some(masked) = blend_over.then(|| mask_unchanged(&crop(&base, width, x, y, w, h), &rgba)).flatten() {
+            if estimate(&masked, w, h, &estimate_opts)? < estimate(&rgba, w, h, &estimate_opts)? {
+                rgba = masked;
+                blend = BlendOp::Over;
+            }
+        }
+        planned.push(Planned {
+            x,
+            y,
+            width: w,
+            height: h,
+            rgba,
+            delay_num: frame.delay_num,
+            delay_den: frame.delay_den,
+            dispose: DisposeOp::None,
+            blend,
+        });
+        before = base;
+        after = target.to_vec();
+    }
+    Ok(planned)
+}
+
+fn write_planned<W: Write>(mut w: W, animation: &Animation, planned: &[Planned], opts: &OptimizeOptions) -> Result<()> {
+    let (width, height) = (animation.width, animation.height);
+
+    // All frames share one color type and palette, so reduce their pixels together.
+    let pixels = planned.iter().map(|p| p.rgba.len() / 4).sum::<usize>();
+    let all = Image {
+        width: pixels as u32,
+        height: 1,
+        color_type: ColorType::Rgba,
+        bit_depth: BitDepth::Eight,
+        palette: None,
+        trns: None,
+        data: Cow::Owned(planned.iter().flat_map(|p| p.rgba.iter().copied()).collect()),
+        text: Vec::new(),
+        chunks: Vec::new(),
+        premultiplied: false,
+    };
+    let reduced = all.reduce_for(opts);
+    let pixel_len = reduced.color_type.samples();
+
+    w.write_all(&SIGNATURE)?;
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend(width.to_be_bytes());
+    ihdr.extend(height.to_be_bytes());
+    ihdr.extend([8, reduced.color_type as u8, 0, 0, 0]);
+    write_chunk(&mut w, b"IHDR", &ihdr)?;
+    let mut actl = (planned.len() as u32).to_be_bytes().to_vec();
+    actl.extend(animation.plays.to_be_bytes());
+    write_chunk(&mut w, b"acTL", &actl)?;
+    // Chunks of the first frame, such as pHYs or tIME, go before PLTE as sRGB must.
+    for (kind, data) in &animation.frames[0].image.chunks {
+        write_chunk(&mut w, kind, data)?;
+    }
+    if let Some(palette) = &reduced.palette {
+        write_chunk(&mut w, b"PLTE", palette)?;
+    }
+    if let Some(trns) = reduced.trns_chunk() {
+        write_chunk(&mut w, b"tRNS", trns)?;
+    }
+    for (keyword, text) in &animation.frames[0].image.text {
+        let (kind, data) = text_chunk(keyword, text);
+        write_chunk(&mut w, &kind, &data)?;
+    }
+    let mut sequence = 0u32;
+    let mut offset = 0;
+    for (i, p) in planned.iter().enumerate() {
+        let len = (p.width * p.height) as usize * pixel_len;
+        let region = Image {
+            width: p.width,
+            height: p.height,
+            color_type: reduced.color_type,
+            bit_depth: reduced.bit_depth,
+            palette: None,
+            trns: None,
+            data: Cow::Borrowed(&reduced.data[offset..offset + len]),
+            text: Vec::new(),
+            chunks: Vec::new(),
+            premultiplied: false,
+        };
+        offset += len;
+        let mut fctl = sequence.to_be_bytes().to_vec();
+        for v in [p.width, p.height, p.x, p.y] {
+            fctl.extend(v.to_be_bytes());
+        }
+        fctl.extend(p.delay_num.to_be_bytes());
+        fctl.extend(p.delay_den.to_be_bytes());
+        fctl.extend([p.dispose as u8, p.blend as u8]);
+        write_chunk(&mut w, b"fcTL", &fctl)?;
+        sequence += 1;
+        let data = compress_best(&region, opts)?;
+        if i == 0 {
+            write_chunk(&mut w, b"IDAT", &data)?;
+        } else {
+            write_chunk(&mut w, b"fdAT", &[&sequence.to_be_bytes()[..], &data].concat())?;
+            sequence += 1;
+        }
+    }
+    write_chunk(&mut w, b"IEND", &[])?;
+    Ok(())
+}
+
+/// The region for blending over `base`: unchanged pixels become transparent. `None` when a
+/// changed pixel is translucent over a visible one, which blending would mix.
+fn mask_unchanged(base: &[u8], target: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(target.len());
+    for (b, t) in base.chunks_exact(4).zip(target.chunks_exact(4)) {
+        if b == t {
+            out.extend([0; 4]);
+        } else if t[3] == 0xFF || b[3] == 0 && t[3] != 0 {
+            out.extend_from_slice(t);
+        } else {
+            return None;
+        }
+    }
+    Some(out)
+}
+
+fn estimate(rgba: &[u8], width: u32, height: u32, opts: &OptimizeOptions) -> Result<usize> {
+    let image = Image {
+        width,
+        height,
+        color_type: ColorType::Rgba,
+        bit_depth: BitDepth::Eight,
+        palette: None,
+        trns: None,
+        data: Cow::Borrowed(rgba),
+        text: Vec::new(),
+        chunks: Vec::new(),
+        premultiplied: false,
+    };
+    Ok(compress_best(&image, opts)?.len())
+}
+
+/// Smallest rectangle (x, y, width, height) outside of which `a` and `b` agree.
+fn changed_bounds(a: &[u8], b: &[u8], width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+    let stride = width as usize * 4;
+    let rows = (0..height as usize).filter(|&y| a[y * stride..][..stride] != b[y * stride..][..stride]);
+    let (mut top, mut bottom) = (None, 0);
+    for y in rows {
+        top.get_or_insert(y);
+        bottom = y;
+    }
+    let top = top?;
+    let (mut left, mut right) = (width as usize, 0);
+    for y in top..=bottom {
+        let (ra, rb) = (&a[y * stride..][..stride], &b[y * stride..][..stride]);
+        for x in (0..width as usize).filter(|&x| ra[x * 4..x * 4 + 4] != rb[x * 4..x * 4 + 4]) {
+            left = left.min(x);
+            right = right.max(x);
+        }
+    }
+    Some((left as u32, top as u32, (right - left + 1) as u32, (bottom - top + 1) as u32))
+}
+
+fn crop(rgba: &[u8], width: u32, x: u32, y: u32, w: u32, h: u32) -> Vec<u8> {
+    let stride = width as usize * 4;
+    (y..y + h).flat_map(|row| &rgba[row as usize * stride + x as usize * 4..][..w as usize * 4]).copied().collect()
+}
+
+fn clear(canvas: &mut [u8], width: u32, region: &Planned) {
+    for y in region.y..region.y + region.height {
+        let at = (y * width + region.x) as usize * 4;
+        canvas[at..at + region.width as usize * 4].fill(0);
+    }
+}
+
+fn add_delays(a: (u16, u16), b: (u16, u16)) -> (u16, u16) {
+    let den = |d: u16| if d == 0 { 100 } else { d };
+    if den(a.1) == den(b.1) {
+        if let Some(num) = a.0.checked_add(b.0) {
+            return (num, a.1);
+        }
+    }
+    let ms = |(num, d): (u16, u16)| num as u32 * 1000 / den(d) as u32;
+    ((ms(a) + ms(b)).min(u16::MAX as u32) as u16, 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn animation(depth: BitDepth) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, 2, 2);
+        encoder.set_color(ColorType::Grayscale);
+        encoder.set_depth(depth);
+        encoder.set_animated(2, 0).unwrap();
+        let mut writer = encoder.write_header().unwrap();
+        let frame_len = if depth == BitDepth::Sixteen { 8 } else { 4 };
+        for value in [0x12, 0x34] {
+            writer.write_image_data(&vec![value; frame_len]).unwrap();
+        }
+        writer.finish().unwrap();
+        data
+    }
+
+    #[test]
+    fn decodes_8_bit_frames() {
+        let data = animation(BitDepth::Eight);
+        assert!(!is_16_bit(&data));
+        let decoded = decode_animation(&data).unwrap();
+        assert_eq!(decoded.frames.len(), 2);
+        assert_eq!(&decoded.frames[1].image.data[..4], &[0x34, 0x34, 0x34, 0xFF]);
+    }
+
+    #[test]
+    fn refuses_16_bit_frames() {
+        let data = animation(BitDepth::Sixteen);
+        assert!(is_16_bit(&data));
+        assert!(decode_animation(&data).is_err());
+    }
+}
@@ -0,0 +1,416 @@
+//! Release archives as inputs and outputs instead of directory trees.
+//!
+//! Entries are streamed to the output file as they come; zip entries are stored rather than
+//! deflated, since PNG data does not compress any further. Input archives are read whole, zip
+//! entries stored or deflated, tar with GNU and pax long names.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use compress_png::chunks;
+use flate2::{
+    read::{DeflateDecoder, GzDecoder},
+    write::GzEncoder,
+    Compression,
+};
+
+use crate::paths;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Zip,
+    Tar,
+}
+
+enum Sink {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(w) => w.write(buf),
+            Sink::Gzip(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(w) => w.flush(),
+            Sink::Gzip(w) => w.flush(),
+        }
+    }
+}
+
+/// The format and whether it is gzipped, from the extension of `path`.
+fn format(path: &Path) -> Option<(Format, bool)> {
+    let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+    if name.ends_with(".zip") {
+        Some((Format::Zip, false))
+    } else if name.ends_with(".tar") {
+        Some((Format::Tar, false))
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some((Format::Tar, true))
+    } else {
+        None
+    }
+}
+
+/// Whether `path` names an archive rather than an image.
+pub fn is_archive(path: &Path) -> bool {
+    format(path).is_some()
+}
+
+/// A `.zip`, `.tar`, `.tar.gz` or `.tgz` archive being written.
+pub struct Writer {
+    sink: Sink,
+    format: Format,
+    /// Bytes written so far, the offset of the next zip entry.
+    offset: u64,
+    /// Zip central directory, written by [`Writer::finish`].
+    central: Vec<u8>,
+    names: HashSet<String>,
+}
+
+impl Writer {
+    /// Creates the archive, in the format its extension names.
+    pub fn create(path: &Path) -> io::Result<Writer> {
+        let Some((format, gzip)) = format(path) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{}: expected a .zip, .tar, .tar.gz or .tgz archive", path.display())));
+        };
+        let file = BufWriter::new(File::create(paths::long(path))?);
+        let sink = if gzip { Sink::Gzip(GzEncoder::new(file, Compression::best())) } else { Sink::Plain(file) };
+        Ok(Writer { sink, format, offset: 0, central: Vec::new(), names: HashSet::new() })
+    }
+
+    /// Number of entries added so far.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Adds a file at the relative path `rel`.
+    pub fn add(&mut self, rel: &Path, data: &[u8], modified: SystemTime) -> io::Result<()> {
+        let name = entry_name(rel)?;
+        if !self.names.insert(name.clone()) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{}: already in the archive", name)));
+        }
+        let header = match self.format {
+            Format::Zip => self.zip_header(&name, data, modified)?,
+            Format::Tar => tar_header(&name, data.len() as u64, modified)?.to_vec(),
+        };
+        let padding = match self.format {
+            Format::Zip => 0,
+            Format::Tar => (512 - data.len() % 512) % 512,
+        };
+        self.sink.write_all(&header)?;
+        self.sink.write_all(data)?;
+        self.sink.write_all(&vec![0; padding])?;
+        self.offset += (header.len() + data.len() + padding) as u64;
+        Ok(())
+    }
+
+    /// Writes the end of the archive.
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.format {
+            Format::Zip => {
+                let entries = u16::try_from(self.names.len()).map_err(|_| too_large("more than 65535 entries"))?;
+                let offset = u32::try_from(self.offset).map_err(|_| too_large("over 4 GiB"))?;
+                let mut end = Vec::with_capacity(22);
+                end.extend(0x0605_4b50u32.to_le_bytes());
+                end.extend([0; 4]); // This disk and the one the central directory starts on.
+                end.extend(entries.to_le_bytes());
+                end.extend(entries.to_le_bytes());
+                end.extend((self.central.len() as u32).to_le_bytes());
+                end.extend(offset.to_le_bytes());
+                end.extend([0; 2]); // Comment length.
+                self.sink.write_all(&self.central)?;
+                self.sink.write_all(&end)?;
+            }
+            Format::Tar => self.sink.write_all(&[0; 1024])?,
+        }
+        match self.sink {
+            Sink::Plain(mut w) => w.flush(),
+            Sink::Gzip(w) => w.finish()?.flush(),
+        }
+    }
+
+    /// The local header of a stored entry, recording its central directory entry on the way.
+    fn zip_header(&mut self, name: &str, data: &[u8], modified: SystemTime) -> io::Result<Vec<u8>> {
+        let size = u32::try_from(data.len()).map_err(|_| too_large(&format!("{} is over 4 GiB", name)))?;
+        let offset = u32::try_from(self.offset).map_err(|_| too_large("over 4 GiB"))?;
+        let (time, date) = dos_time(modified);
+        // Version 2.0, UTF-8 names, stored, time, date, CRC and both sizes.
+        let mut common = Vec::with_capacity(26);
+        common.extend(20u16.to_le_bytes());
+        common.extend(0x0800u16.to_le_bytes());
+        common.extend(0u16.to_le_bytes());
+        common.extend(time.to_le_bytes());
+        common.extend(date.to_le_bytes());
+        common.extend(crc32fast::hash(data).to_le_bytes());
+        common.extend(size.to_le_bytes());
+        common.extend(size.to_le_bytes());
+        common.extend((name.len() as u16).to_le_bytes());
+        common.extend(0u16.to_le_bytes()); // Extra field length.
+
+        self.central.extend(0x0201_4b50u32.to_le_bytes());
+        // Made by Unix, so that the external attributes below are read as a file mode.
+        self.central.extend((3u16 << 8 | 20).to_le_bytes());
+        self.central.extend(&common);
+        self.central.extend([0; 6]); // Comment length, disk, internal attributes.
+        self.central.extend((0o100644u32 << 16).to_le_bytes());
+        self.central.extend(offset.to_le_bytes());
+        self.central.extend(name.as_bytes());
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend(0x0403_4b50u32.to_le_bytes());
+        header.extend(common);
+        header.extend(name.as_bytes());
+        Ok(header)
+    }
+}
+
+/// A file of an input archive.
+pub struct Entry {
+    /// Path inside the archive.
+    pub rel: PathBuf,
+    pub data: Vec<u8>,
+    pub modified: SystemTime,
+}
+
+/// The files of a zip, tar or gzipped tar archive, told apart by their content. Directories
+/// are left out; links and other special entries are skipped with a warning.
+pub fn read(data: &[u8]) -> io::Result<Vec<Entry>> {
+    if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+        return read_zip(data);
+    }
+    if data.starts_with(&[0x1F, 0x8B]) {
+        let mut tar = Vec::new();
+        GzDecoder::new(data).read_to_end(&mut tar)?;
+        return read_tar(&tar);
+    }
+    read_tar(data)
+}
+
+fn read_zip(data: &[u8]) -> io::Result<Vec<Entry>> {
+    // The end of central directory record is followed by at most a 64 KiB comment.
+    let end = (0..=data.len().saturating_sub(22))
+        .rev()
+        .take(65536 + 1)
+        .find(|&at| data[at..].starts_with(&0x0605_4b50u32.to_le_bytes()))
+        .ok_or_else(|| invalid("no zip end of central directory"))?;
+    let count = u16_at(data, end + 10)?;
+    let mut at = u32_at(data, end + 16)? as usize;
+    let mut entries = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        if u32_at(data, at)? != 0x0201_4b50 {
+            return Err(invalid("bad zip central directory"));
+        }
+        let (flags, method, time, date) = (u16_at(data, at + 8)?, u16_at(data, at + 10)?, u16_at(data, at + 12)?, u16_at(data, at + 14)?);
+        let (crc, compressed, size) = (u32_at(data, at + 16)?, u32_at(data, at + 20)? as usize, u32_at(data, at + 24)? as usize);
+        let name_len = u16_at(data, at + 28)? as usize;
+        let local = u32_at(data, at + 42)? as usize;
+        let name = String::from_utf8_lossy(slice(data, at + 46, name_len)?).into_owned();
+        at += 46 + name_len + u16_at(data, at + 30)? as usize + u16_at(data, at + 32)? as usize;
+        if name.ends_with('/') {
+            continue;
+        }
+        if flags & 1 != 0 {
+            return Err(invalid(&format!("{}: encrypted", name)));
+        }
+        let start = local + 30 + u16_at(data, local + 26)? as usize + u16_at(data, local + 28)? as usize;
+        let raw = slice(data, start, compressed)?;
+        let contents = match method {
+            0 => raw.to_vec(),
+            8 => {
+                // Deflate expands at most about 1032 to 1, whatever size the header claims, and one
+                // byte past the declared size is enough to tell it was wrong.
+                let mut contents = Vec::with_capacity(size.min(raw.len().saturating_mul(1032)));
+                DeflateDecoder::new(raw).take(size as u64 + 1).read_to_end(&mut contents)?;
+                contents
+            }
+            _ => return Err(invalid(&format!("{}: unsupported zip compression method {}", name, method))),
+        };
+        if contents.len() != size || crc32fast::hash(&contents) != crc {
+            return Err(invalid(&format!("{}: bad CRC", name)));
+        }
+        entries.push(Entry { rel: entry_path(&name)?, data: contents, modified: from_dos_time(time, date) });
+    }
+    Ok(entries)
+}
+
+fn read_tar(data: &[u8]) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let (mut at, mut long_name) = (0, None);
+    while let Ok(header) = slice(data, at, 512) {
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let checksum = header.iter().enumerate().map(|(i, &b)| if (148..156).contains(&i) { b' ' as u32 } else { b as u32 }).sum::<u32>();
+        if octal(&header[148..156])? != checksum as u64 {
+            return Err(invalid("bad tar header checksum"));
+        }
+        let size = octal(&header[124..136])? as usize;
+        let body = slice(data, at + 512, size)?;
+        at += 512 + size.div_ceil(512) * 512;
+        let name = long_name.take().unwrap_or_else(|| {
+            let (prefix, name) = (c_str(&header[345..500]), c_str(&header[..100]));
+            if &header[257..262] == b"ustar" && !prefix.is_empty() { format!("{}/{}", prefix, name) } else { name }
+        });
+        match header[156] {
+            b'0' | b'\0' | b'7' => entries.push(Entry { rel: entry_path(&name)?, data: body.to_vec(), modified: UNIX_EPOCH + Duration::from_secs(octal(&header[136..148])?) }),
+            b'5' | b'g' => {}
+            b'L' => long_name = Some(c_str(body)),
+            // Records of "<length> <key>=<value>\n"; only the name matters here.
+            b'x' => long_name = String::from_utf8_lossy(body).lines().find_map(|record| record.split_once(" path=").map(|(_, path)| path.to_string())),
+            _ => eprintln!("{}: not a regular file, skipped", name),
+        }
+    }
+    Ok(entries)
+}
+
+/// A name as stored in an archive, refusing ones that would escape the output directory.
+fn entry_path(name: &str) -> io::Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for part in name.split('/').filter(|part| !part.is_empty() && *part != ".") {
+        if part == ".." {
+            return Err(invalid(&format!("{}: entry outside the archive", name)));
+        }
+        path.push(part);
+    }
+    Ok(path)
+}
+
+fn slice(data: &[u8], at: usize, len: usize) -> io::Result<&[u8]> {
+    data.get(at..at.checked_add(len).ok_or_else(|| invalid("truncated archive"))?).ok_or_else(|| invalid("truncated archive"))
+}
+
+fn u16_at(data: &[u8], at: usize) -> io::Result<u16> {
+    Ok(u16::from_le_bytes(slice(data, at, 2)?.try_into().unwrap()))
+}
+
+fn u32_at(data: &[u8], at: usize) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(slice(data, at, 4)?.try_into().unwrap()))
+}
+
+/// A tar number field: octal digits padded with spaces or NULs.
+fn octal(field: &[u8]) -> io::Result<u64> {
+    let digits = String::from_utf8_lossy(field);
+    let digits = digits.trim_matches(|c: char| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| invalid("bad tar number"))
+}
+
+fn c_str(field: &[u8]) -> String {
+    String::from_utf8_lossy(field.split(|&b| b == 0).next().unwrap_or_default()).into_owned()
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+/// `rel` with `/` separators, as both formats store names.
+fn entry_name(rel: &Path) -> io::Result<String> {
+    let mut parts = Vec::new();
+    for component in rel.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: not a UTF-8 path", rel.display())))?),
+            Component::CurDir => {}
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{}: not a relative path inside the archive", rel.display()))),
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+/// A ustar header for a regular file; names over 100 bytes are split into the prefix field.
+fn tar_header(name: &str, size: u64, modified: SystemTime) -> io::Result<[u8; 512]> {
+    let (prefix, name) = if name.len() <= 100 {
+        ("", name)
+    } else {
+        name.match_indices('/')
+            .map(|(i, _)| (&name[..i], &name[i + 1..]))
+            .find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100)
+            .ok_or_else(|| too_large(&format!("{}: name too long for tar", name)))?
+    };
+    if size >= 1 << 33 {
+        return Err(too_large(&format!("{} is over 8 GiB", name)));
+    }
+    let mtime = modified.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()).min(0o77777777777);
+    let mut header = [0; 512];
+    let mut field = |at: usize, value: &[u8]| header[at..at + value.len()].copy_from_slice(value);
+    field(0, name.as_bytes());
+    field(100, b"0000644\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", size).as_bytes());
+    field(136, format!("{:011o}\0", mtime).as_bytes());
+    field(148, b"        ");
+    field(156, b"0");
+    field(257, b"ustar\0");
+    field(263, b"00");
+    field(345, prefix.as_bytes());
+    let checksum = header.iter().map(|&b| b as u32).sum::<u32>();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    Ok(header)
+}
+
+/// MS-DOS time and date, which cannot hold anything before 1980.
+fn dos_time(t: SystemTime) -> (u16, u16) {
+    let [y0, y1, month, day, hour, minute, second] = chunks::time(t);
+    let year = u16::from_be_bytes([y0, y1]);
+    if year < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+    let time = (hour as u16) << 11 | (minute as u16) << 5 | (second as u16 / 2);
+    let date = (year - 1980).min(127) << 9 | (month as u16) << 5 | day as u16;
+    (time, date)
+}
+
+fn from_dos_time(time: u16, date: u16) -> SystemTime {
+    let (year, month, day) = (1980 + (date >> 9) as u64, ((date >> 5) & 15).clamp(1, 12) as u64, (date & 31).max(1) as u64);
+    let secs = (time >> 11) as u64 * 3600 + ((time >> 5) & 63) as u64 * 60 + (time & 31) as u64 * 2;
+    chunks::from_civil(year, month, day, secs)
+}
+
+fn too_large(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("archive too large: {}", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let dir = std::env::temp_dir().join(format!("compress-png-archive-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // Over 100 bytes, so that tar puts the directories into the ustar prefix.
+        let long = format!("{}/{}.png", "sprites/".repeat(10).trim_end_matches('/'), "walk-cycle-".repeat(5));
+        assert!(long.len() > 100);
+        let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let files = [("icon.png", &b"first"[..]), (long.as_str(), &[0x89, b'P', b'N', b'G', 0, 0xFF][..])];
+        for name in ["out.zip", "out.tar", "out.tgz"] {
+            let path = dir.join(name);
+            let mut writer = Writer::create(&path).unwrap();
+            for (rel, data) in files {
+                writer.add(Path::new(rel), data, modified).unwrap();
+            }
+            writer.finish().unwrap();
+            let entries = read(&std::fs::read(&path).unwrap()).unwrap();
+            assert_eq!(entries.len(), files.len(), "{}", name);
+            for (entry, (rel, data)) in entries.iter().zip(files) {
+                assert_eq!(entry.rel, Path::new(rel), "{}", name);
+                assert_eq!(entry.data, data, "{}", name);
+                assert_eq!(entry.modified, modified, "{}", name);
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
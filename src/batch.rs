@@ -0,0 +1,1041 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File, TryLockError},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    process,
+    time::{Instant, SystemTime},
+};
+
+#[cfg(feature = "quantize")]
+use compress_png::quantize;
+#[cfg(feature = "quantize")]
+use compress_png::{color_histogram, Histogram};
+use compress_png::{analysis, channels::Swizzle, apng, check, chunks, diff, ico, mng, resize, srgb, DecodeOptions, Image, OptimizeOptions, PngStats};
+use png::ColorType;
+
+#[cfg(feature = "compare-external")]
+use crate::external;
+#[cfg(feature = "archive")]
+use crate::archive;
+use crate::{emit_candidates, github, interrupt, palette, paths, throttle::Throttle, walk::Input, LinkKind, Opts, Report, SetTime};
+
+const MARKER_KEYWORD: &str = "compress-png";
+/// SSIM that `--auto-lossy` requires unless `--min-ssim` is given.
+#[cfg(feature = "quantize")]
+const AUTO_LOSSY_MIN_SSIM: f64 = 0.98;
+/// Distinct colors above which `--auto-lossy` leaves an image alone: noise and photographs have
+/// too many colors for merging to pay off, and each one costs a search.
+#[cfg(feature = "quantize")]
+const AUTO_LOSSY_MAX_COLORS: usize = 65_536;
+
+pub struct Batch<'a> {
+    opts: &'a Opts,
+    lib_opts: OptimizeOptions,
+    /// Channel rearrangement of `--swizzle` or `--extract-channel`.
+    swizzle: Option<Swizzle>,
+    /// Text of the `--mark` chunk for the current options.
+    marker: String,
+    /// Output written for each processed input.
+    written: HashMap<PathBuf, PathBuf>,
+    /// Pixels of each distinct image so far and the first input that had them, by pixel hash.
+    by_pixels: HashMap<u64, Vec<(Image<'static>, PathBuf)>>,
+    duplicates: Vec<(PathBuf, PathBuf)>,
+    perceptual: Vec<(PathBuf, u64)>,
+    /// Optimizers run for `--compare-external`.
+    #[cfg(feature = "compare-external")]
+    external: Vec<(&'static external::Tool, PathBuf)>,
+    /// Total input size, our total and each external tool's total over files every tool handled.
+    #[cfg(feature = "compare-external")]
+    compared: (u64, u64, Vec<u64>),
+    /// Palette of `--shared-palette` or `--palette-in` that every image is indexed against.
+    #[cfg(feature = "quantize")]
+    palette: Option<Vec<[u8; 4]>>,
+    /// Colors of the last indexed output, for `--palette-out`.
+    last_palette: Option<Vec<[u8; 4]>>,
+    /// Inputs whose output stays above `--max-size`, with that size.
+    over_budget: Vec<(PathBuf, u64)>,
+    /// Files optimized from each color type (rows, in [`COLOR_TYPES`] order) to each other.
+    transitions: [[usize; 5]; 5],
+    /// Whether outputs replace their inputs, as for `--staged`.
+    in_place: bool,
+    /// `--out-archive` being written.
+    #[cfg(feature = "archive")]
+    archive: Option<archive::Writer>,
+    /// Lock on the input being processed.
+    lock: Option<Lock>,
+    /// Modification time of the input or archive entry being processed, kept in `--out-archive`.
+    modified: Option<SystemTime>,
+    /// Pacing for `--io-throttle`.
+    throttle: Option<Throttle>,
+    processed: usize,
+    failed: usize,
+    /// Inputs left alone because they were locked or the run was interrupted.
+    skipped: usize,
+}
+
+impl<'a> Batch<'a> {
+    pub fn new(opts: &'a Opts) -> io::Result<Self> {
+        let lib_opts = OptimizeOptions::builder()
+            .time_limit(opts.max_time_per_image)
+            .effort(opts.effort.into())
+            .trials(opts.trials.clone())
+            .gray_reduction(!opts.no_gray_reduction)
+            .alpha_strip(!opts.no_alpha_strip)
+            .palette(!opts.no_palette)
+            .seed(opts.seed)
+            .cancel(interrupt::flag())
+            .build();
+        #[cfg(feature = "quantize")]
+        let mut lossy = format!("merge_close_colors={:?} lossless_region={:?}", opts.merge_close_colors, opts.lossless_region);
+        #[cfg(feature = "quantize")]
+        if let Some(min_ssim) = opts.min_ssim {
+            lossy += &format!(" min_ssim={}", min_ssim);
+        }
+        #[cfg(feature = "quantize")]
+        if opts.shared_palette {
+            lossy += " shared_palette";
+        }
+        #[cfg(feature = "quantize")]
+        if let Some(path) = &opts.palette_in {
+            lossy += &format!(" palette_in={}", paths::display(path));
+        }
+        #[cfg(feature = "quantize")]
+        if opts.auto_lossy {
+            lossy += &format!(" auto_lossy_saving={}", opts.auto_lossy_saving);
+        }
+        #[cfg(not(feature = "quantize"))]
+        let lossy = "merge_close_colors=None lossless_region=[]";
+        // Only options that change the output go into the hash.
+        let mut options = format!(
+            "effort={:?} trials={:?} time_limit={:?} reductions={:?} {} bleed_alpha={} palette_anneal={} seed={}",
+            lib_opts.effort, lib_opts.trials.as_ref().map(ToString::to_string), lib_opts.time_limit, lib_opts.reductions, lossy, opts.bleed_alpha, opts.palette_anneal, opts.seed
+        );
+        if let Some([r, g, b]) = opts.colorkey {
+            options += &format!(" colorkey=#{:02x}{:02x}{:02x}", r, g, b);
+        }
+        let swizzle = opts.swizzle.clone().or(opts.extract_channel.map(|channel| Swizzle::extract(channel as usize)));
+        if let Some(swizzle) = &swizzle {
+            options += &format!(" swizzle={}", swizzle);
+        }
+        if opts.split_alpha {
+            options += " split_alpha";
+        }
+        options += &format!(
+            " no_expand={} premultiply={} unpremultiply={} convert_to_srgb={} keep_text={} set_text={:?} remove_text={:?} dpi={:?} offset={:?} set_time={:?}",
+            opts.no_expand, opts.premultiply, opts.unpremultiply, opts.convert_to_srgb, opts.keep_text, opts.set_text, opts.remove_text, opts.dpi, opts.offset, opts.set_time
+        );
+        let marker = format!("v{} options={:08x}", env!("CARGO_PKG_VERSION"), crc32fast::hash(options.as_bytes()));
+        #[cfg(feature = "compare-external")]
+        let external = if opts.compare_external { external::detect() } else { Vec::new() };
+        #[cfg(feature = "compare-external")]
+        if opts.compare_external {
+            let names = external.iter().map(|(tool, _)| tool.name).collect::<Vec<_>>();
+            println!("external optimizers: {}", if names.is_empty() { "none found".to_string() } else { names.join(", ") });
+        }
+        Ok(Batch {
+            opts,
+            lib_opts,
+            swizzle,
+            marker,
+            written: HashMap::new(),
+            by_pixels: HashMap::new(),
+            duplicates: Vec::new(),
+            perceptual: Vec::new(),
+            #[cfg(feature = "compare-external")]
+            compared: (0, 0, vec![0; external.len()]),
+            #[cfg(feature = "compare-external")]
+            external,
+            #[cfg(feature = "quantize")]
+            palette: None,
+            last_palette: None,
+            over_budget: Vec::new(),
+            transitions: [[0; 5]; 5],
+            in_place: opts.staged,
+            #[cfg(feature = "archive")]
+            archive: opts.out_archive.as_deref().map(archive::Writer::create).transpose()?,
+            lock: None,
+            modified: None,
+            throttle: opts.io_throttle.map(Throttle::new),
+            processed: 0,
+            failed: 0,
+            skipped: 0,
+        })
+    }
+
+    pub fn process(&mut self, input: &Input) -> io::Result<()> {
+        #[cfg(feature = "archive")]
+        if archive::is_archive(&input.path) && input.same_as.is_none() {
+            return self.process_archive(input);
+        }
+        self.processed += 1;
+        // Archive entries are named by the relative path alone.
+        let out = match &self.opts.out_dir {
+            Some(dir) => dir.join(&input.rel),
+            None if self.opts.writes_archive() => input.rel.clone(),
+            None if self.in_place => input.path.clone(),
+            None => self.opts.output.clone(),
+        };
+        if let Some(first) = &input.same_as {
+            println!("{}: same file as {}, skipped", paths::display(&input.path), paths::display(first));
+            if let Some(first_out) = self.written.get(first).filter(|_| self.opts.writes_files()) {
+                link(LinkKind::Hard, first_out, &out)?;
+            }
+            return Ok(());
+        }
+        if self.opts.out_dir.is_some() || self.opts.writes_archive() || self.in_place {
+            println!("{}", paths::display(&input.path));
+        }
+        if !self.lock_input(&input.path, !self.opts.writes_archive() && same_file(&input.path, &out)) {
+            return Ok(());
+        }
+        let result = self.read_input(&input.path).and_then(|data| self.optimize_file(&input.path, data, &out));
+        self.lock = None;
+        let read = fs::metadata(paths::long(&input.path)).map_or(0, |m| m.len());
+        self.record(&input.path, out, read, result);
+        Ok(())
+    }
+
+    /// Optimizes the PNG and ICO images of a zip or tar input and copies its other files
+    /// through, into the output directory or archive at the archive's relative location.
+    #[cfg(feature = "archive")]
+    fn process_archive(&mut self, input: &Input) -> io::Result<()> {
+        println!("{}", paths::display(&input.path));
+        if !self.lock_input(&input.path, false) {
+            return Ok(());
+        }
+        let entries = match self.read_input(&input.path).and_then(|data| archive::read(&data)) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("{}: {}", paths::display(&input.path), e);
+                self.failed += 1;
+                self.lock = None;
+                return Ok(());
+            }
+        };
+        let dir = input.rel.parent().unwrap_or(Path::new(""));
+        let base = self.opts.out_dir.as_ref().map_or_else(|| dir.to_path_buf(), |out_dir| out_dir.join(dir));
+        for (i, entry) in entries.into_iter().enumerate() {
+            if interrupt::requested() {
+                println!("{}: interrupted after {} entries", paths::display(&input.path), i);
+                self.skipped += 1;
+                break;
+            }
+            let (src, out) = (input.path.join(&entry.rel), base.join(&entry.rel));
+            self.modified = Some(entry.modified);
+            if !entry.data.starts_with(&chunks::SIGNATURE) && !ico::is_ico(&entry.data) {
+                if !self.opts.check && !self.opts.dry_run {
+                    if let Err(e) = self.write_output(&out, &entry.data) {
+                        eprintln!("{}: {}", paths::display(&src), e);
+                        self.failed += 1;
+                    }
+                }
+                continue;
+            }
+            self.processed += 1;
+            println!("{}", paths::display(&src));
+            let read = entry.data.len() as u64;
+            let result = self.optimize_file(&src, entry.data, &out);
+            self.record(&src, out, read, result);
+        }
+        self.lock = None;
+        Ok(())
+    }
+
+    /// Computes the `--shared-palette` over the still PNG images among `inputs`, archive entries
+    /// included. Animated images keep their own colors.
+    #[cfg(feature = "quantize")]
+    pub fn share_palette(&mut self, inputs: &[Input]) -> io::Result<()> {
+        let decode_opts = DecodeOptions { expand: true, ignore_checksums: self.opts.permissive };
+        let (mut histogram, mut images) = (Histogram::default(), 0);
+        for input in inputs.iter().filter(|input| input.same_as.is_none()) {
+            let data = fs::read(paths::long(&input.path))?;
+            #[cfg(feature = "archive")]
+            let files = if archive::is_archive(&input.path) { archive::read(&data)?.into_iter().map(|entry| (input.path.join(entry.rel), entry.data)).collect() } else { vec![(input.path.clone(), data)] };
+            #[cfg(not(feature = "archive"))]
+            let files = [(input.path.clone(), data)];
+            for (src, data) in files.iter().filter(|(_, data)| data.starts_with(&chunks::SIGNATURE)) {
+                if chunks::parse(data).chunks.iter().any(|c| &c.kind == b"acTL") {
+                    continue;
+                }
+                match compress_png::decode_with(data, &decode_opts) {
+                    Ok(image) => {
+                        histogram.merge(&color_histogram(&image.to_rgba8().data, ColorType::Rgba));
+                        images += 1;
+                    }
+                    // Reported again when the file itself is processed.
+                    Err(e) => eprintln!("warning: {}: {}, left out of the shared palette", paths::display(src), e),
+                }
+            }
+        }
+        let palette = quantize::shared_palette(&histogram);
+        println!("shared palette: {} entries for {} colors in {} images", palette.len(), histogram.len(), images);
+        self.index_with(palette);
+        Ok(())
+    }
+
+    /// Indexes every image against `palette`, as for `--palette-in`.
+    #[cfg(feature = "quantize")]
+    pub fn index_with(&mut self, palette: Vec<[u8; 4]>) {
+        self.palette = Some(palette);
+    }
+
+    /// Locks an input, reporting it as skipped or failed when that is not possible.
+    fn lock_input(&mut self, src: &Path, in_place: bool) -> bool {
+        match Lock::acquire(src, in_place) {
+            Ok(lock) => {
+                self.modified = lock.file.metadata().and_then(|m| m.modified()).ok();
+                self.lock = Some(lock);
+                true
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                eprintln!("warning: {}: locked by another process, skipped", paths::display(src));
+                self.skipped += 1;
+                false
+            }
+            Err(e) => {
+                eprintln!("{}: {}", paths::display(src), e);
+                self.failed += 1;
+                false
+            }
+        }
+    }
+
+    /// Counts the outcome of optimizing `src`, of which `read` bytes were read.
+    fn record(&mut self, src: &Path, out: PathBuf, read: u64, result: io::Result<()>) {
+        match result {
+            Ok(()) => {
+                if let Some(throttle) = &mut self.throttle {
+                    throttle.pace(read + if self.opts.writes_files() { fs::metadata(paths::long(&out)).map_or(0, |m| m.len()) } else { 0 });
+                }
+                if self.opts.writes_files() {
+                    self.written.insert(src.to_path_buf(), out);
+                }
+            }
+            // Outputs are only written once complete, so an interrupted file has at most the
+            // main output and no partial one.
+            Err(_) if interrupt::requested() => {
+                println!("{}: interrupted", paths::display(src));
+                self.skipped += 1;
+            }
+            Err(e) => {
+                eprintln!("{}: {}", paths::display(src), e);
+                self.failed += 1;
+            }
+        }
+    }
+
+    /// Reads the input, through the locked handle when optimizing in place.
+    fn read_input(&self, src: &Path) -> io::Result<Vec<u8>> {
+        match &self.lock {
+            Some(lock) if lock.in_place => lock.read(),
+            _ => fs::read(paths::long(src)),
+        }
+    }
+
+    /// Writes an output file, or adds it to the archive with the input's modification time.
+    /// Files are written whole to a temporary file beside the output and renamed over it, so
+    /// that a failed or interrupted write, in place included, never leaves a truncated output.
+    fn write_output(&mut self, out: &Path, data: &[u8]) -> io::Result<()> {
+        #[cfg(feature = "archive")]
+        if let Some(archive) = &mut self.archive {
+            return archive.add(out, data, self.modified.unwrap_or(SystemTime::UNIX_EPOCH));
+        }
+        replace(out, data)
+    }
+
+    /// Makes outputs replace their inputs.
+    pub fn write_in_place(&mut self) {
+        self.in_place = true;
+    }
+
+    /// Number of inputs that failed or were skipped so far, which a resumed run must retry.
+    pub fn unfinished(&self) -> usize {
+        self.failed + self.skipped
+    }
+
+    /// Every output written so far.
+    pub fn outputs(&self) -> Vec<PathBuf> {
+        self.written.values().cloned().collect()
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        #[cfg(feature = "archive")]
+        if let (Some(archive), Some(path)) = (self.archive, &self.opts.out_archive) {
+            let entries = archive.len();
+            archive.finish()?;
+            println!("archive={} entries={}", paths::display(path), entries);
+        }
+        if let Some(path) = &self.opts.palette_out {
+            #[cfg(feature = "quantize")]
+            let colors = self.palette.as_ref().or(self.last_palette.as_ref());
+            #[cfg(not(feature = "quantize"))]
+            let colors = self.last_palette.as_ref();
+            match colors {
+                Some(colors) => {
+                    palette::write(path, colors)?;
+                    println!("palette={} colors={}", paths::display(path), colors.len());
+                }
+                None => eprintln!("warning: no indexed output, {} not written", paths::display(path)),
+            }
+        }
+        if (self.opts.find_duplicates || self.opts.link_duplicates.is_some()) && !self.duplicates.is_empty() {
+            println!("duplicates:");
+            for (dup, first) in &self.duplicates {
+                println!("  {} = {}", paths::display(dup), paths::display(first));
+            }
+        }
+        if self.transitions.iter().flatten().sum::<usize>() > 1 {
+            print_transitions(&self.transitions);
+        }
+        if let Some(max_distance) = self.opts.find_similar {
+            let mut similar = Vec::new();
+            for (i, (a, ha)) in self.perceptual.iter().enumerate() {
+                for (b, hb) in &self.perceptual[i + 1..] {
+                    let distance = compress_png::hamming_distance(*ha, *hb);
+                    if distance <= max_distance {
+                        similar.push((distance, a, b));
+                    }
+                }
+            }
+            similar.sort_by_key(|&(distance, ..)| distance);
+            println!("similar images (max distance {}):", max_distance);
+            for (distance, a, b) in similar {
+                println!("  {:2} {} ~ {}", distance, paths::display(a), paths::display(b));
+            }
+        }
+        #[cfg(feature = "compare-external")]
+        if !self.external.is_empty() {
+            let (original, ours, theirs) = &self.compared;
+            println!("total original={} compress-png={}", original, ours);
+            for ((tool, _), size) in self.external.iter().zip(theirs) {
+                println!("total {}={}", tool.name, size);
+            }
+        }
+        if let Some(max_size) = self.opts.max_size {
+            if !self.over_budget.is_empty() {
+                println!("over {} bytes even when optimized:", max_size);
+                for (src, size) in &self.over_budget {
+                    println!("  {} {}", paths::display(src), size);
+                }
+                return Err(io::Error::other(format!("{} of {} files exceed --max-size", self.over_budget.len(), self.processed)));
+            }
+        }
+        if self.failed > 0 {
+            return Err(io::Error::other(format!("{} of {} files failed", self.failed, self.processed)));
+        }
+        Ok(())
+    }
+
+    fn optimize_file(&mut self, src: &Path, mut src_data: Vec<u8>, out: &Path) -> io::Result<()> {
+        let opts = self.opts;
+        let started = Instant::now();
+        if ico::is_ico(&src_data) {
+            return self.optimize_ico(src, &src_data, out);
+        }
+        if let Some(container) = mng::container(&src_data) {
+            let hint = if container == mng::Container::Mng { "; the extract subcommand saves its PNG images" } else { "" };
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported container: {}{}", container, hint)));
+        }
+        if opts.mark && self.is_marked(&src_data) {
+            println!("{}: already optimized with these options, skipped", paths::display(src));
+            // Keeps the output tree complete.
+            return if !self.opts.writes_archive() && same_file(src, out) { Ok(()) } else { self.write_output(out, &src_data) };
+        }
+        if opts.strict {
+            if let Some(violation) = check::check(&src_data).first() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("rejected by --strict: {}", violation)));
+            }
+        }
+        if opts.repair || opts.permissive {
+            let repaired = compress_png::repair::repair(&src_data)?;
+            for fix in &repaired.fixes {
+                eprintln!("{}: repaired: {}", paths::display(src), fix);
+            }
+            src_data = repaired.data;
+        }
+        if chunks::parse(&src_data).chunks.iter().any(|c| &c.kind == b"acTL") {
+            return self.optimize_apng(src, &src_data, out);
+        }
+
+        let decode_opts = DecodeOptions { expand: !opts.no_expand, ignore_checksums: opts.permissive };
+        let mut image = compress_png::decode_with(&src_data, &decode_opts)?;
+        self.check_dimensions(src, image.width, image.height)?;
+        // To tell whether the steps below changed the pixels.
+        let decoded = image.clone();
+        if let Some(swizzle) = &self.swizzle {
+            image = swizzle.apply(&image)?;
+            println!("swizzle {}", swizzle);
+        }
+        let original = opts.diff_image.is_some().then(|| image.clone());
+        if let Some(rgb) = opts.colorkey {
+            let keyed = image.key_out(rgb);
+            println!("colorkey: {} pixels made transparent", keyed);
+        }
+        if opts.convert_to_srgb {
+            let source = srgb::convert_to_srgb(&mut image, &src_data)?;
+            println!("color space {} -> sRGB", source);
+        }
+        if opts.bleed_alpha {
+            image.bleed_alpha();
+        }
+        if opts.premultiply {
+            image.premultiply();
+        } else if opts.unpremultiply {
+            image.unpremultiply();
+        }
+        if opts.split_alpha {
+            let mask = Swizzle::extract(3).apply(&image)?;
+            image = "rgba->rgb".parse::<Swizzle>()?.apply(&image)?;
+            if !opts.check && !opts.dry_run {
+                let mask_out = companion(out, ".alpha.png");
+                let size = self.write_best(&mask, &mask_out)?;
+                println!("alpha={} size={}", paths::display(&mask_out), size);
+            }
+        }
+        #[cfg(feature = "quantize")]
+        if let Some(max_delta_e) = opts.merge_close_colors.or(opts.auto_lossy.then_some(quantize::JUST_NOTICEABLE)) {
+            let transfer = if opts.convert_to_srgb { quantize::Transfer::Srgb } else { quantize::Transfer::from_png(&src_data) };
+            let colors = opts.merge_close_colors.is_none().then(|| image.unique_colors()).flatten();
+            if let Some(colors) = colors.filter(|&colors| colors > AUTO_LOSSY_MAX_COLORS) {
+                println!("not merging close colors: {} colors is over {}", colors, AUTO_LOSSY_MAX_COLORS);
+            } else if analysis::analyze(&image).likely_to_band() {
+                println!("not merging close colors: smooth gradients would band");
+            } else {
+                let lossless = image.clone();
+                let merged = quantize::merge_close_colors(&mut image, max_delta_e, transfer);
+                if !opts.lossless_region.is_empty() {
+                    quantize::restore_regions(&mut image, &lossless, &opts.lossless_region);
+                }
+                let quality = diff::quality(&lossless, &image)?;
+                println!("merged {} close colors {}", merged, quality);
+                let min_ssim = opts.min_ssim.or(opts.auto_lossy.then_some(AUTO_LOSSY_MIN_SSIM));
+                if let Some(min_ssim) = min_ssim.filter(|&min_ssim| quality.ssim < min_ssim) {
+                    println!("ssim below {}, kept lossless", min_ssim);
+                    image = lossless;
+                } else if opts.auto_lossy && merged > 0 {
+                    let (lossy_size, lossless_size) = (self.encode_best(&image)?.len(), self.encode_best(&lossless)?.len());
+                    let saved = 100.0 * (1.0 - lossy_size as f64 / lossless_size as f64);
+                    if saved < opts.auto_lossy_saving {
+                        println!("lossy saves {:.1}%, below --auto-lossy-saving {}%, kept lossless", saved, opts.auto_lossy_saving);
+                        image = lossless;
+                    } else {
+                        println!("lossy saves {:.1}%, kept lossy", saved);
+                    }
+                }
+            }
+        }
+        if let (Some(path), Some(original)) = (&opts.diff_image, &original) {
+            let difference = diff::difference(original, &image)?;
+            fs::write(paths::long(path), self.encode_best(&difference.image)?)?;
+            println!("diff={} {}", paths::display(path), difference);
+        }
+        if opts.find_duplicates || opts.link_duplicates.is_some() {
+            // Equal hashes only make a duplicate likely.
+            let seen = self.by_pixels.entry(image.pixel_hash()).or_default();
+            if let Some((_, first)) = seen.iter().find(|(pixels, _)| pixels.same_pixels(&image)) {
+                println!("{}: same pixels as {}", paths::display(src), paths::display(first));
+                self.duplicates.push((src.to_path_buf(), first.clone()));
+                if let (Some(kind), Some(first_out)) = (opts.link_duplicates.filter(|_| opts.writes_files()), self.written.get(first)) {
+                    return link(kind, first_out, out);
+                }
+            } else {
+                seen.push((image.clone(), src.to_path_buf()));
+            }
+        }
+        if opts.find_similar.is_some() {
+            self.perceptual.push((src.to_path_buf(), image.perceptual_hash()));
+        }
+        println!("width={} height={} color={:?} bit_depth={:?}", image.width, image.height, image.color_type, image.bit_depth);
+        let analysis = (image.color_type != ColorType::Indexed).then(|| analysis::analyze(&image));
+        if let Some(analysis) = &analysis {
+            eprintln!("{}", analysis);
+        }
+        if !opts.sizes.is_empty() {
+            return self.write_sizes(&image, out);
+        }
+
+        let mut reduced = image.reduce_for(&self.lib_opts);
+        #[cfg(feature = "quantize")]
+        let palette = self.palette.as_ref();
+        #[cfg(not(feature = "quantize"))]
+        let palette: Option<&Vec<[u8; 4]>> = None;
+        #[cfg(feature = "quantize")]
+        if let Some(palette) = palette {
+            reduced = quantize::index_with_palette(&image, palette);
+            // Compared as RGBA, which indexed samples are not.
+            let colors = Image { data: Cow::Owned(reduced.data.iter().flat_map(|&i| palette[i as usize]).collect()), ..image.to_rgba8() };
+            println!("palette {}", diff::quality(&image, &colors)?);
+        }
+        if let Some(analysis) = analysis.as_ref().filter(|_| (reduced.color_type, reduced.bit_depth) == (image.color_type, image.bit_depth)) {
+            println!("kept {}: {}", color_name(image.color_type), analysis.why_kept(&self.lib_opts.reductions));
+        }
+        if opts.palette_anneal {
+            if let Some((before, after)) = reduced.anneal_palette(&self.lib_opts)? {
+                println!("palette anneal: {} -> {}", before, after);
+            }
+        }
+        self.add_metadata(&mut reduced, &src_data);
+        let collisions = reduced.palette_collisions();
+        if collisions > 0 {
+            println!("warning: {} palette entries look identical once alpha is premultiplied", collisions);
+        }
+        let trials = reduced.trials(&self.lib_opts)?;
+        match &self.lib_opts.trials {
+            Some(spec) => println!("trials={}", spec),
+            None => println!("effort={:?}", trials.effort),
+        }
+        for trial in &trials.results {
+            println!("{} size={}", trial.config, trial.size);
+        }
+        if trials.skipped > 0 {
+            println!("time limit reached, skipped {} trials", trials.skipped);
+        }
+        let mut best = trials.best()?;
+        // Only an image with the pixels as decoded and no palette imposed may fall back to the
+        // stored representation.
+        let stored = if image.same_pixels(&decoded) && palette.is_none() { compress_png::smaller_as_stored(&src_data, &image, best, &self.lib_opts)? } else { None };
+        let written = match stored {
+            Some((mut stored, trial)) => {
+                println!("kept the stored {}/{:?}: {} bytes smaller than re-encoded", color_name(stored.color_type), stored.bit_depth, best.size - trial.size);
+                stored.text = reduced.text.clone();
+                stored.chunks = reduced.chunks.clone();
+                best = trial;
+                Cow::Owned(stored)
+            }
+            None => Cow::Borrowed(&reduced),
+        };
+        // Trial sizes leave out what was added to a stored representation afterwards.
+        let mut data = written.encode(best.config)?;
+        let mut to = (written.color_type, written.bit_depth_for(best.config));
+        // Nothing beat the input as it is, and no option asked for anything it lacks or rules out.
+        if data.len() >= src_data.len() && image.same_pixels(&decoded) && palette.is_none() && !opts.edits_metadata() {
+            let stored = compress_png::decode_stored(&src_data)?;
+            if self.lib_opts.reductions.allows(decoded.color_type, stored.color_type) {
+                println!("re-encoded not smaller, kept the original");
+                (data, to) = (src_data.clone(), (stored.color_type, stored.bit_depth));
+            }
+        }
+        let size = data.len();
+        self.check_budget(src, size as u64);
+        if opts.palette_out.is_some() {
+            self.last_palette = palette_colors(&written).or(self.last_palette.take());
+        }
+        if opts.check {
+            return Ok(());
+        }
+        if opts.dry_run {
+            print_chunk_diff(&src_data, &data);
+            return Ok(());
+        }
+        if let Some(dir) = &opts.emit_candidates {
+            let candidates = trials.results.iter().map(|t| Ok((t.config, reduced.bit_depth_for(t.config), reduced.encode(t.config)?))).collect::<compress_png::Result<Vec<_>>>()?;
+            emit_candidates(dir, src, reduced.color_type, reduced.bit_depth, &candidates)?;
+        }
+        self.write_output(out, &data)?;
+        let stats = PngStats {
+            original_size: src_data.len(),
+            new_size: size,
+            config: best.config,
+            from: (image.color_type, image.bit_depth),
+            to,
+            unique_colors: image.unique_colors(),
+            vertical_redundancy: image.vertical_redundancy(),
+            elapsed: started.elapsed(),
+        };
+        println!("{}", stats);
+        // Only worth a warning when even an optimized PNG is far bigger than the lossy file.
+        if let Some(estimate) = analysis.as_ref().and_then(|a| analysis::lossy_estimate(&image, a)).filter(|e| e.webp * 2 < size as u64) {
+            println!("warning: looks photographic, a lossy format would be far smaller: {} png={}", estimate, size);
+            if opts.report == Report::Github {
+                let message = format!("about {} bytes as lossy WebP instead of {} as PNG ({})", estimate.webp, size, estimate);
+                println!("{}", github::warning(&paths::display(src), "Photographic PNG", &message));
+            }
+        }
+        self.transitions[color_index(stats.from.0)][color_index(stats.to.0)] += 1;
+        if opts.report == Report::Github {
+            annotate(src, stats.original_size, stats.new_size, opts);
+        }
+        #[cfg(feature = "compare-external")]
+        self.compare_external(&src_data, size as u64);
+
+        if let Some(max_edge) = opts.thumbnail {
+            let (width, height) = resize::fit(image.width, image.height, max_edge);
+            let thumb_out = companion(out, ".thumb.png");
+            let size = self.write_best(&resize::resize(&image, width, height), &thumb_out)?;
+            println!("thumbnail={} {}x{} size={}", paths::display(&thumb_out), width, height, size);
+        }
+        Ok(())
+    }
+
+    /// Adds the text and chunks the options ask for: kept or set text, the `--mark` text,
+    /// `pHYs`, `oFFs`, `sRGB` and `tIME`.
+    fn add_metadata(&self, image: &mut Image, src_data: &[u8]) {
+        if self.opts.keep_text {
+            for (keyword, text) in chunks::parse(src_data).chunks.iter().filter_map(|c| c.decoded_text()) {
+                if !self.opts.remove_text.contains(&keyword) && keyword != MARKER_KEYWORD {
+                    image.text.push((keyword, text));
+                }
+            }
+        }
+        for (keyword, text) in &self.opts.set_text {
+            image.text.retain(|(k, _)| k != keyword);
+            image.text.push((keyword.clone(), text.clone()));
+        }
+        if self.opts.mark {
+            image.text.push((MARKER_KEYWORD.to_string(), self.marker.clone()));
+        }
+        if let Some(dpi) = self.opts.dpi {
+            // Pixels per meter on both axes, unit 1 (meter).
+            let ppm = (dpi as f64 / 0.0254).round() as u32;
+            image.chunks.push((*b"pHYs", [ppm.to_be_bytes(), ppm.to_be_bytes()].concat().into_iter().chain([1]).collect()));
+        }
+        if let Some((x, y)) = self.opts.offset {
+            // Unit 0 (pixels).
+            image.chunks.push((*b"oFFs", [x.to_be_bytes(), y.to_be_bytes()].concat().into_iter().chain([0]).collect()));
+        }
+        if self.opts.convert_to_srgb {
+            // Perceptual rendering intent.
+            image.chunks.push((*b"sRGB", vec![0]));
+        }
+        match self.opts.set_time {
+            SetTime::Strip => {}
+            SetTime::Keep => {
+                if let Some(time) = chunks::parse(src_data).chunks.iter().find(|c| &c.kind == b"tIME") {
+                    image.chunks.push((time.kind, time.data.to_vec()));
+                }
+            }
+            SetTime::Now => image.chunks.push((*b"tIME", chunks::time(SystemTime::now()).to_vec())),
+        }
+    }
+
+    /// Re-encodes an animation frame by frame instead of keeping only its first frame, keeping
+    /// the original when that is not smaller or would lose 16-bit samples. Metadata options
+    /// apply as to still images; options that change pixels are refused.
+    fn optimize_apng(&mut self, src: &Path, src_data: &[u8], out: &Path) -> io::Result<()> {
+        if let Some(option) = self.opts.still_only() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} does not apply to animations", option)));
+        }
+        let data = if apng::is_16_bit(src_data) {
+            // Frames are re-encoded with 8-bit samples.
+            if self.opts.edits_metadata() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "16-bit animations are kept unchanged and cannot take metadata options"));
+            }
+            println!("16-bit animation, kept unchanged");
+            src_data.to_vec()
+        } else {
+            let mut animation = apng::decode_animation(src_data)?;
+            self.check_dimensions(src, animation.width, animation.height)?;
+            if let Some(first) = animation.frames.first_mut() {
+                self.add_metadata(&mut first.image, src_data);
+            }
+            let mut data = Vec::new();
+            apng::encode_animation(&mut data, &animation, &self.lib_opts)?;
+            println!("frames={} size={}->{}", animation.frames.len(), src_data.len(), data.len());
+            if data.len() < src_data.len() || self.opts.edits_metadata() {
+                data
+            } else {
+                println!("re-encoded animation not smaller, kept the original");
+                src_data.to_vec()
+            }
+        };
+        self.check_budget(src, data.len() as u64);
+        if self.opts.report == Report::Github {
+            annotate(src, src_data.len(), data.len(), self.opts);
+        }
+        if self.opts.check {
+            return Ok(());
+        }
+        if self.opts.dry_run {
+            print_chunk_diff(src_data, &data);
+            return Ok(());
+        }
+        self.write_output(out, &data)
+    }
+
+    /// Optimizes every image of an icon, keeping the original where it is smaller.
+    fn optimize_ico(&mut self, src: &Path, src_data: &[u8], out: &Path) -> io::Result<()> {
+        let mut entries = ico::parse_ico(src_data)?;
+        for entry in &mut entries {
+            let format = if entry.is_png() { "png" } else { "bmp" };
+            let data = self.encode_best(&entry.decode()?)?;
+            println!("{}x{} {} {} -> png {}", entry.width, entry.height, format, entry.data.len(), data.len());
+            if data.len() < entry.data.len() {
+                entry.data = data;
+                entry.bit_count = 32;
+            }
+        }
+        let mut data = Vec::new();
+        ico::write_ico(&mut data, &entries)?;
+        println!("size={}->{}", src_data.len(), data.len());
+        self.check_budget(src, data.len() as u64);
+        if self.opts.check || self.opts.dry_run {
+            return Ok(());
+        }
+        self.write_output(out, &data)
+    }
+
+    /// Reduces and encodes `image` with its best trial.
+    fn encode_best(&self, image: &Image) -> io::Result<Vec<u8>> {
+        let mut reduced = image.reduce_for(&self.lib_opts);
+        if self.opts.mark {
+            reduced.text.push((MARKER_KEYWORD.to_string(), self.marker.clone()));
+        }
+        let best = reduced.trials(&self.lib_opts)?.best()?;
+        Ok(reduced.encode(best.config)?)
+    }
+
+    /// Writes `image` with its best trial, returning the written size.
+    fn write_best(&mut self, image: &Image, out: &Path) -> io::Result<usize> {
+        let data = self.encode_best(image)?;
+        self.write_output(out, &data)?;
+        Ok(data.len())
+    }
+
+    /// Writes one resized copy per `--sizes` entry, as `<stem>.<size>.png` or as the images of an `.ico` output.
+    fn write_sizes(&mut self, image: &Image, out: &Path) -> io::Result<()> {
+        let as_ico = out.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("ico"));
+        let mut entries = Vec::new();
+        for &size in &self.opts.sizes {
+            let (width, height) = resize::fit(image.width, image.height, size);
+            let resized = if (width, height) == (image.width, image.height) { image.clone() } else { resize::resize(image, width, height) };
+            let data = self.encode_best(&resized)?;
+            println!("size={} {}x{} bytes={}", size, width, height, data.len());
+            if as_ico {
+                entries.push(ico::IcoEntry { width, height, bit_count: 32, data });
+            } else {
+                self.write_output(&companion(out, &format!(".{}.png", size)), &data)?;
+            }
+        }
+        if as_ico {
+            let mut data = Vec::new();
+            ico::write_ico(&mut data, &entries)?;
+            self.write_output(out, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Fails, or with `--warn-dimensions` warns, when the size breaks `--require-pot` or
+    /// `--require-dimensions`.
+    fn check_dimensions(&self, src: &Path, width: u32, height: u32) -> io::Result<()> {
+        let mut problems = Vec::new();
+        if self.opts.require_pot && !(width.is_power_of_two() && height.is_power_of_two()) {
+            problems.push(format!("{}x{} is not a power of two", width, height));
+        }
+        if let Some((w, h)) = self.opts.require_dimensions.filter(|&required| required != (width, height)) {
+            problems.push(format!("{}x{} instead of the required {}x{}", width, height, w, h));
+        }
+        if problems.is_empty() {
+            return Ok(());
+        }
+        let message = problems.join("; ");
+        if !self.opts.warn_dimensions {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        println!("warning: {}", message);
+        if self.opts.report == Report::Github {
+            println!("{}", github::warning(&paths::display(src), "Texture dimensions", &message));
+        }
+        Ok(())
+    }
+
+    /// Records `src` when its optimized `size` is above `--max-size`.
+    fn check_budget(&mut self, src: &Path, size: u64) {
+        if self.opts.max_size.is_some_and(|max_size| size > max_size) {
+            self.over_budget.push((src.to_path_buf(), size));
+        }
+    }
+
+    /// Runs every external optimizer on the input and reports its size next to ours.
+    #[cfg(feature = "compare-external")]
+    fn compare_external(&mut self, src_data: &[u8], ours: u64) {
+        let mut sizes = Vec::with_capacity(self.external.len());
+        for (tool, exe) in &self.external {
+            match external::run(tool, exe, src_data, &self.opts.temp_dir()) {
+                Ok(size) => {
+                    println!("{} size={} ({:+} bytes vs compress-png)", tool.name, size, size as i64 - ours as i64);
+                    sizes.push(size);
+                }
+                Err(e) => println!("{} failed: {}", tool.name, e),
+            }
+        }
+        if !self.external.is_empty() && sizes.len() == self.external.len() {
+            self.compared.0 += src_data.len() as u64;
+            self.compared.1 += ours;
+            self.compared.2.iter_mut().zip(sizes).for_each(|(total, size)| *total += size);
+        }
+    }
+
+    fn is_marked(&self, src: &[u8]) -> bool {
+        chunks::parse(src).chunks.iter().filter_map(|c| c.text()).any(|(keyword, text)| keyword == MARKER_KEYWORD.as_bytes() && text == self.marker.as_bytes())
+    }
+}
+
+/// Prints GitHub warnings for an input over the `--warn-size` or `--warn-savings` thresholds.
+fn annotate(src: &Path, original_size: usize, new_size: usize, opts: &Opts) {
+    let file = paths::display(src);
+    if let Some(limit) = opts.warn_size.filter(|&limit| original_size as u64 > limit) {
+        println!("{}", github::warning(&file, "Large PNG", &format!("{} bytes exceeds the limit of {} bytes", original_size, limit)));
+    }
+    let saved = original_size.saturating_sub(new_size);
+    let percent = 100.0 * saved as f64 / original_size.max(1) as f64;
+    if saved > 0 && percent >= opts.warn_savings {
+        println!("{}", github::warning(&file, "Unoptimized PNG", &format!("optimizing would save {} bytes ({:.1}%): {} -> {}", saved, percent, original_size, new_size)));
+    }
+}
+
+/// The RGBA colors of an indexed image's palette.
+fn palette_colors(image: &Image) -> Option<Vec<[u8; 4]>> {
+    let palette = image.palette.as_ref().filter(|_| image.color_type == ColorType::Indexed)?;
+    let trns = image.trns.as_deref().unwrap_or_default();
+    Some(palette.chunks_exact(3).enumerate().map(|(i, rgb)| [rgb[0], rgb[1], rgb[2], trns.get(i).copied().unwrap_or(0xFF)]).collect())
+}
+
+const COLOR_TYPES: [ColorType; 5] = [ColorType::Grayscale, ColorType::GrayscaleAlpha, ColorType::Rgb, ColorType::Rgba, ColorType::Indexed];
+
+fn color_index(color: ColorType) -> usize {
+    COLOR_TYPES.iter().position(|&c| c == color).unwrap()
+}
+
+/// Prints how many files went from each color type (rows) to each other (columns), leaving
+/// out color types that no file started from, so that authoring habits such as exporting gray
+/// art as RGBA stand out.
+fn print_transitions(transitions: &[[usize; 5]; 5]) {
+    println!("color types (from \\ to):");
+    let header = COLOR_TYPES.iter().map(|&c| format!("{:>11}", color_name(c))).collect::<String>();
+    println!("  {:<10}{}", "", header);
+    for (from, row) in COLOR_TYPES.iter().zip(transitions) {
+        if row.iter().any(|&n| n > 0) {
+            println!("  {:<10}{}", color_name(*from), row.iter().map(|n| format!("{:>11}", n)).collect::<String>());
+        }
+    }
+}
+
+fn color_name(color: ColorType) -> &'static str {
+    match color {
+        ColorType::Grayscale => "gray",
+        ColorType::GrayscaleAlpha => "gray+alpha",
+        ColorType::Rgb => "RGB",
+        ColorType::Rgba => "RGBA",
+        ColorType::Indexed => "indexed",
+    }
+}
+
+/// An advisory lock on an input against other instances: exclusive when the output replaces
+/// it, shared otherwise so that only in-place runs exclude each other and readers.
+pub(crate) struct Lock {
+    file: File,
+    in_place: bool,
+}
+
+impl Lock {
+    /// Fails with [`io::ErrorKind::WouldBlock`] when another process holds a conflicting lock.
+    pub(crate) fn acquire(src: &Path, in_place: bool) -> io::Result<Lock> {
+        let file = File::options().read(true).write(in_place).open(paths::long(src))?;
+        let locked = if in_place { file.try_lock() } else { file.try_lock_shared() };
+        match locked {
+            Ok(()) => Ok(Lock { file, in_place }),
+            Err(TryLockError::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    /// The whole locked file.
+    pub(crate) fn read(&self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        (&self.file).rewind()?;
+        (&self.file).read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+/// The `--dry-run` report of what writing `after` in place of `before` would change.
+fn print_chunk_diff(before: &[u8], after: &[u8]) {
+    println!("dry run, not written: size={}->{}", before.len(), after.len());
+    for change in chunks::diff(before, after) {
+        println!("  {}", change);
+    }
+}
+
+/// `out` with its extension replaced by `suffix`, e.g. `icon.png` to `icon.thumb.png`.
+fn companion(out: &Path, suffix: &str) -> PathBuf {
+    let mut name = out.file_stem().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    out.with_file_name(name)
+}
+
+/// Whether both paths exist and are the same file.
+pub(crate) fn same_file(a: &Path, b: &Path) -> bool {
+    matches!((fs::canonicalize(paths::long(a)), fs::canonicalize(paths::long(b))), (Ok(a), Ok(b)) if a == b)
+}
+
+/// Points `out` at an already written output, falling back to a copy where links are unsupported.
+fn link(kind: LinkKind, first: &Path, out: &Path) -> io::Result<()> {
+    let out = paths::long(out);
+    if let Some(parent) = out.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Made under a temporary name and renamed over `out`, which stays intact when linking fails.
+    let temp = temp_name(&out);
+    let _ = fs::remove_file(&temp);
+    let first = paths::long(first);
+    let linked = match kind {
+        LinkKind::Hard => fs::hard_link(&first, &temp),
+        LinkKind::Sym => fs::canonicalize(&first).and_then(|target| symlink(&target, &temp)),
+    };
+    match linked.or_else(|_| fs::copy(&first, &temp).map(|_| ())).and_then(|()| fs::rename(&temp, &out)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+/// Writes `data` to `out` under a temporary name and renames it into place, so that readers see
+/// either the old file or the whole new one.
+pub(crate) fn replace(out: &Path, data: &[u8]) -> io::Result<()> {
+    let mut out = paths::long(out);
+    if let Some(parent) = out.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    // Replace what a symlink points to rather than the link itself.
+    if fs::symlink_metadata(&out).is_ok_and(|m| m.file_type().is_symlink()) {
+        out = Cow::Owned(fs::canonicalize(&out)?);
+    }
+    let temp = temp_name(&out);
+    let written = File::create(&temp).and_then(|mut file| {
+        file.write_all(data)?;
+        if let Ok(metadata) = fs::metadata(&out) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.sync_all()
+    });
+    match written.and_then(|()| fs::rename(&temp, &out)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+/// A hidden name beside `out` for writing it before renaming it into place.
+fn temp_name(out: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(out.file_name().unwrap_or_default());
+    name.push(format!(".{}.tmp", process::id()));
+    out.with_file_name(name)
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
@@ -0,0 +1,171 @@
+//! Rearranging color channels before optimizing, for texture pipelines that pack masks into the
+//! channels of one image or need another channel order.
+//!
+//! A [`Swizzle`] is written like `rgba->bgra`: the left side names the channels of the input,
+//! read as RGBA, and the right side lists the output channels by those names, `0` and `1`
+//! standing for constant black and white. One output channel makes a gray image, two gray with
+//! alpha, three RGB and four RGBA.
+
+use std::{borrow::Cow, fmt, str::FromStr};
+
+use png::{BitDepth, ColorType};
+
+use crate::{Error, Image, Result};
+
+/// Where an output channel comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// Channel of the input as RGBA: 0 red to 3 alpha. Gray images have gray in red, green and
+    /// blue, and images without alpha are opaque.
+    Channel(usize),
+    Zero,
+    One,
+}
+
+/// An output channel list; see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Swizzle {
+    pub sources: Vec<Source>,
+}
+
+impl Swizzle {
+    /// A gray image of one channel of the input, 0 red to 3 alpha.
+    pub fn extract(channel: usize) -> Swizzle {
+        Swizzle { sources: vec![Source::Channel(channel)] }
+    }
+
+    /// Rearranges the channels of an 8 or 16-bit true color or gray image.
+    pub fn apply(&self, image: &Image) -> Result<Image<'static>> {
+        let bytes = match (image.color_type, image.bit_depth) {
+            (ColorType::Indexed, _) => return Err(Error::Format("cannot swizzle indexed samples; expand them first".to_string())),
+            (_, BitDepth::Eight) => 1,
+            (_, BitDepth::Sixteen) => 2,
+            (_, depth) => return Err(Error::Format(format!("cannot swizzle {}-bit samples; expand them first", depth as u8))),
+        };
+        let color_type = match self.sources.len() {
+            1 => ColorType::Grayscale,
+            2 => ColorType::GrayscaleAlpha,
+            3 => ColorType::Rgb,
+            4 => ColorType::Rgba,
+            n => return Err(Error::Format(format!("{} output channels; a swizzle makes 1 to 4", n))),
+        };
+        // Where each RGBA channel is within a pixel, or None for opaque alpha.
+        let offsets: [Option<usize>; 4] = match image.color_type {
+            ColorType::Grayscale => [Some(0), Some(0), Some(0), None],
+            ColorType::GrayscaleAlpha => [Some(0), Some(0), Some(0), Some(1)],
+            ColorType::Rgb => [Some(0), Some(1), Some(2), None],
+            _ => [Some(0), Some(1), Some(2), Some(3)],
+        };
+        let pixel_len = image.color_type.samples() * bytes;
+        let (zero, one) = ([0u8; 2], [0xFFu8; 2]);
+        let mut data = Vec::with_capacity(image.data.len() / pixel_len * self.sources.len() * bytes);
+        for px in image.data.chunks_exact(pixel_len) {
+            for source in &self.sources {
+                let sample = match *source {
+                    Source::Channel(c) => offsets[c].map_or(&one[..bytes], |k| &px[k * bytes..][..bytes]),
+                    Source::Zero => &zero[..bytes],
+                    Source::One => &one[..bytes],
+                };
+                data.extend_from_slice(sample);
+            }
+        }
+        Ok(Image {
+            color_type,
+            palette: None,
+            trns: None,
+            data: Cow::Owned(data),
+            text: image.text.clone(),
+            chunks: image.chunks.clone(),
+            // Colors multiplied by an alpha that moved or went away no longer mean anything.
+            premultiplied: image.premultiplied && self.sources.len() == 4 && self.sources[3] == Source::Channel(3),
+            ..*image
+        })
+    }
+}
+
+impl FromStr for Swizzle {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let format = |msg: String| Error::Format(format!("swizzle {:?}: {}", s, msg));
+        let (from, to) = s.split_once("->").ok_or_else(|| format("expected INPUT->OUTPUT like rgba->bgra".to_string()))?;
+        let (from, to) = (from.trim().to_ascii_lowercase(), to.trim().to_ascii_lowercase());
+        if from.is_empty() || from.len() > 4 || !from.chars().all(|c| "rgba".contains(c)) {
+            return Err(format(format!("input channels {:?} must be 1 to 4 of r, g, b and a", from)));
+        }
+        if let Some(c) = from.chars().find(|&c| from.matches(c).count() > 1) {
+            return Err(format(format!("input channel {} named twice", c)));
+        }
+        let sources = to
+            .chars()
+            .map(|c| match c {
+                '0' => Ok(Source::Zero),
+                '1' => Ok(Source::One),
+                _ => from.find(c).map(Source::Channel).ok_or_else(|| format(format!("output channel {} is not among the input channels {:?}", c, from))),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if sources.is_empty() || sources.len() > 4 {
+            return Err(format(format!("{} output channels; a swizzle makes 1 to 4", sources.len())));
+        }
+        Ok(Swizzle { sources })
+    }
+}
+
+impl fmt::Display for Swizzle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("rgba->")?;
+        for source in &self.sources {
+            f.write_str(match source {
+                Source::Channel(c) => &"rgba"[*c..*c + 1],
+                Source::Zero => "0",
+                Source::One => "1",
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_swizzles() {
+        let swizzle = "BGRA -> rgb1".parse::<Swizzle>().unwrap();
+        assert_eq!(swizzle.sources, [Source::Channel(2), Source::Channel(1), Source::Channel(0), Source::One]);
+        assert_eq!(swizzle.to_string(), "rgba->bgr1");
+        assert_eq!("rgba->a0".parse::<Swizzle>().unwrap().sources, [Source::Channel(3), Source::Zero]);
+    }
+
+    #[test]
+    fn rejects_invalid_swizzles() {
+        for spec in ["rgba", "->rgb", "rgbx->rgb", "rgbar->rgb", "rgba->", "rrgb->rgb", "rgb->rgba", "rgba->rgbaa", "rgba->rgb2"] {
+            assert!(spec.parse::<Swizzle>().is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn split_alpha_recombines_to_the_original() {
+        for (bit_depth, bytes) in [(BitDepth::Eight, 1), (BitDepth::Sixteen, 2)] {
+            let data = (0..3 * 4 * bytes).map(|i| (i * 37 % 256) as u8).collect::<Vec<_>>();
+            let image = Image {
+                width: 3,
+                height: 1,
+                color_type: ColorType::Rgba,
+                bit_depth,
+                palette: None,
+                trns: None,
+                data: Cow::Owned(data.clone()),
+                text: Vec::new(),
+                chunks: Vec::new(),
+                premultiplied: false,
+            };
+            // As `--split-alpha` does it.
+            let color = "rgba->rgb".parse::<Swizzle>().unwrap().apply(&image).unwrap();
+            let mask = Swizzle::extract(3).apply(&image).unwrap();
+            assert_eq!((color.color_type, mask.color_type), (ColorType::Rgb, ColorType::Grayscale));
+            let recombined = color.data.chunks_exact(3 * bytes).zip(mask.data.chunks_exact(bytes)).flat_map(|(rgb, a)| [rgb, a].concat()).collect::<Vec<_>>();
+            assert_eq!(recombined, data);
+        }
+    }
+}
@@ -0,0 +1,368 @@
+use std::{fmt, io::Read};
+
+use flate2::read::ZlibDecoder;
+
+use crate::{chunks::{self, Chunk}, pixel};
+
+const ADAM7: [(u32, u32, u32, u32); 7] = [(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)];
+
+pub(crate) const BEFORE_PLTE: [&[u8; 4]; 5] = [b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB"];
+pub(crate) const AFTER_PLTE: [&[u8; 4]; 3] = [b"bKGD", b"hIST", b"tRNS"];
+pub(crate) const BEFORE_IDAT: [&[u8; 4]; 7] = [b"pHYs", b"sPLT", b"oFFs", b"pCAL", b"sCAL", b"acTL", b"eXIf"];
+const ANYWHERE: [&[u8; 4]; 6] = [b"tIME", b"tEXt", b"zTXt", b"iTXt", b"fcTL", b"fdAT"];
+pub(crate) const UNIQUE: [&[u8; 4]; 16] = [
+    b"IHDR", b"PLTE", b"IEND", b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB", b"bKGD", b"hIST", b"tRNS", b"pHYs", b"tIME", b"acTL", b"oFFs", b"eXIf",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub offset: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "offset {:#x}: {}", offset, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Header {
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: u8,
+    pub color_type: u8,
+    pub interlaced: bool,
+}
+
+impl Header {
+    pub fn parse(data: &[u8]) -> Option<Header> {
+        if data.len() != 13 {
+            return None;
+        }
+        Some(Header {
+            width: u32::from_be_bytes(data[0..4].try_into().unwrap()),
+            height: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+            bit_depth: data[8],
+            color_type: data[9],
+            interlaced: data[12] == 1,
+        })
+    }
+
+    fn samples(&self) -> u64 {
+        match self.color_type {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1,
+        }
+    }
+
+    fn row_bytes(&self, width: u32) -> u64 {
+        pixel::stride(width, self.samples() as usize * self.bit_depth as usize) as u64
+    }
+
+    /// Size of the decompressed IDAT stream, filter type bytes included.
+    pub fn raw_size(&self) -> u64 {
+        if !self.interlaced {
+            return self.height as u64 * (1 + self.row_bytes(self.width));
+        }
+        ADAM7
+            .iter()
+            .map(|&(x0, y0, dx, dy)| {
+                let w = self.width.saturating_sub(x0).div_ceil(dx);
+                let h = self.height.saturating_sub(y0).div_ceil(dy);
+                if w == 0 { 0 } else { h as u64 * (1 + self.row_bytes(w)) }
+            })
+            .sum()
+    }
+
+    /// Row lengths (without the filter byte) in stream order.
+    fn rows(&self) -> Vec<(u64, u32)> {
+        if !self.interlaced {
+            return vec![(self.row_bytes(self.width), self.height)];
+        }
+        ADAM7
+            .iter()
+            .map(|&(x0, y0, dx, dy)| (self.width.saturating_sub(x0).div_ceil(dx), self.height.saturating_sub(y0).div_ceil(dy)))
+            .filter(|&(w, _)| w > 0)
+            .map(|(w, h)| (self.row_bytes(w), h))
+            .collect()
+    }
+}
+
+/// Default for the largest decompressed image data that [`check_with`] inflates: 256 MiB.
+pub const MAX_IMAGE_DATA: u64 = 256 << 20;
+
+/// What [`check_with`] found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checked {
+    pub violations: Vec<Violation>,
+    /// Why the image data was left unverified, which is not a violation.
+    pub unverified: Option<String>,
+}
+
+/// Validates the structure of a PNG file without decoding the image. The image data is
+/// inflated however large it is, in constant memory.
+pub fn check(src: &[u8]) -> Vec<Violation> {
+    check_with(src, u64::MAX).violations
+}
+
+/// Like [`check`], leaving image data that would decompress to more than `max_image_data`
+/// bytes unverified instead of inflating it.
+pub fn check_with(src: &[u8], max_image_data: u64) -> Checked {
+    let mut out = Vec::new();
+    let mut unverified = None;
+    let mut report = |offset: Option<usize>, message: String| out.push(Violation { offset, message });
+    let parsed = chunks::parse(src);
+    if !parsed.signature_ok {
+        report(Some(0), "bad PNG signature".to_string());
+        return Checked { violations: out, unverified };
+    }
+
+    let mut header = None;
+    let mut palette_entries = None;
+    let mut seen: Vec<[u8; 4]> = Vec::new();
+    let mut idat = Vec::new();
+    let mut idat_ended = false;
+    let mut has_srgb_and_iccp = false;
+    for (i, chunk) in parsed.chunks.iter().enumerate() {
+        let at = Some(chunk.offset);
+        let name = chunk.name();
+        if !chunk.kind.iter().all(u8::is_ascii_alphabetic) {
+            report(at, format!("invalid chunk type {:?}", name));
+            continue;
+        }
+        if !chunk.crc_ok() {
+            report(at, format!("{}: CRC mismatch (stored {:#010x}, computed {:#010x})", name, chunk.crc, chunk.computed_crc()));
+        }
+        if chunk.kind[2].is_ascii_lowercase() {
+            report(at, format!("{}: reserved bit set", name));
+        }
+        if i == 0 && &chunk.kind != b"IHDR" {
+            report(at, format!("first chunk is {}, expected IHDR", name));
+        }
+        if UNIQUE.contains(&&chunk.kind) && seen.contains(&chunk.kind) {
+            report(at, format!("duplicate {} chunk", name));
+        }
+        let after_idat = !idat.is_empty();
+        if &chunk.kind == b"IDAT" {
+            if idat_ended {
+                report(at, "IDAT chunks are not consecutive".to_string());
+            }
+            idat.push(*chunk);
+        } else if after_idat {
+            idat_ended = true;
+        }
+        let has_plte = seen.contains(b"PLTE");
+        match &chunk.kind {
+            b"IHDR" => header = check_header(chunk, &mut report),
+            b"PLTE" => {
+                if after_idat {
+                    report(at, "PLTE after IDAT".to_string());
+                }
+                if chunk.data.is_empty() || chunk.data.len() % 3 != 0 || chunk.data.len() > 256 * 3 {
+                    report(at, format!("PLTE length {} is not a multiple of 3 in 3..=768", chunk.data.len()));
+                }
+                palette_entries = Some(chunk.data.len() / 3);
+                match header {
+                    Some(Header { color_type: 0 | 4, .. }) => report(at, "PLTE in a grayscale image".to_string()),
+                    Some(Header { color_type: 3, bit_depth, .. }) if chunk.data.len() / 3 > 1 << bit_depth => {
+                        report(at, format!("PLTE has {} entries, more than {}-bit indices can address", chunk.data.len() / 3, bit_depth))
+                    }
+                    _ => {}
+                }
+            }
+            b"IEND" if !chunk.is_empty() => report(at, "IEND is not empty".to_string()),
+            b"tRNS" => check_trns(chunk, header, palette_entries, &mut report),
+            b"hIST" if !has_plte => report(at, "hIST without PLTE".to_string()),
+            kind if BEFORE_PLTE.contains(&kind) && (has_plte || after_idat) => report(at, format!("{} must precede PLTE and IDAT", name)),
+            kind if AFTER_PLTE.contains(&kind) || BEFORE_IDAT.contains(&kind) || BEFORE_PLTE.contains(&kind) || ANYWHERE.contains(&kind) => {}
+            _ if chunk.is_critical() && !matches!(&chunk.kind, b"IDAT" | b"IEND") => report(at, format!("unknown critical chunk {}", name)),
+            _ => {}
+        }
+        if (AFTER_PLTE.contains(&&chunk.kind) || BEFORE_IDAT.contains(&&chunk.kind)) && after_idat {
+            report(at, format!("{} must precede IDAT", name));
+        }
+        has_srgb_and_iccp |= (&chunk.kind == b"sRGB" && seen.contains(b"iCCP")) || (&chunk.kind == b"iCCP" && seen.contains(b"sRGB"));
+        seen.push(chunk.kind);
+    }
+
+    if has_srgb_and_iccp {
+        report(None, "both sRGB and iCCP present".to_string());
+    }
+    if let Some(Header { color_type: 3, .. }) = header {
+        if palette_entries.is_none() {
+            report(None, "indexed image without PLTE".to_string());
+        }
+    }
+    match parsed.truncated_at {
+        Some(at) => report(Some(at), format!("truncated chunk ({} bytes left)", src.len() - at)),
+        None if parsed.chunks.last().is_none_or(|c| &c.kind != b"IEND") => report(None, "missing IEND".to_string()),
+        None => {}
+    }
+    if !parsed.trailing.is_empty() {
+        report(Some(src.len() - parsed.trailing.len()), format!("{} bytes after IEND", parsed.trailing.len()));
+    }
+    match (header, idat.first()) {
+        (_, None) => report(None, "no IDAT chunk".to_string()),
+        (Some(header), Some(_)) if header.raw_size() > max_image_data => {
+            unverified = Some(format!("image data of {} bytes is over the limit of {}", header.raw_size(), max_image_data));
+        }
+        (Some(header), Some(first)) => check_image_data(header, first.offset, &idat, &mut report),
+        (None, Some(_)) => {}
+    }
+    Checked { violations: out, unverified }
+}
+
+fn check_header(chunk: &Chunk, report: &mut impl FnMut(Option<usize>, String)) -> Option<Header> {
+    let at = Some(chunk.offset);
+    let Some(header) = Header::parse(chunk.data) else {
+        report(at, format!("IHDR length {} is not 13", chunk.data.len()));
+        return None;
+    };
+    if header.width == 0 || header.height == 0 || header.width > i32::MAX as u32 || header.height > i32::MAX as u32 {
+        report(at, format!("invalid dimensions {}x{}", header.width, header.height));
+    }
+    let depth_ok = match header.color_type {
+        0 => matches!(header.bit_depth, 1 | 2 | 4 | 8 | 16),
+        3 => matches!(header.bit_depth, 1 | 2 | 4 | 8),
+        2 | 4 | 6 => matches!(header.bit_depth, 8 | 16),
+        _ => {
+            report(at, format!("invalid color type {}", header.color_type));
+            return None;
+        }
+    };
+    if !depth_ok {
+        report(at, format!("bit depth {} not allowed for color type {}", header.bit_depth, header.color_type));
+        return None;
+    }
+    if chunk.data[10] != 0 || chunk.data[11] != 0 {
+        report(at, "unknown compression or filter method".to_string());
+    }
+    if chunk.data[12] > 1 {
+        report(at, format!("unknown interlace method {}", chunk.data[12]));
+    }
+    Some(header)
+}
+
+fn check_trns(chunk: &Chunk, header: Option<Header>, palette_entries: Option<usize>, report: &mut impl FnMut(Option<usize>, String)) {
+    let at = Some(chunk.offset);
+    let Some(header) = header else {
+        return;
+    };
+    let len = chunk.data.len();
+    match header.color_type {
+        0 if len != 2 => report(at, format!("tRNS length {} for grayscale, expected 2", len)),
+        2 if len != 6 => report(at, format!("tRNS length {} for RGB, expected 6", len)),
+        3 => match palette_entries {
+            None => report(at, "tRNS before PLTE".to_string()),
+            Some(entries) if len > entries => report(at, format!("tRNS has {} entries but PLTE only {}", len, entries)),
+            _ => {}
+        },
+        4 | 6 => report(at, "tRNS in an image with an alpha channel".to_string()),
+        _ => {}
+    }
+}
+
+/// Inflates the image data a buffer at a time, looking only at the filter type byte of each row.
+fn check_image_data(header: Header, offset: usize, idat: &[Chunk], report: &mut impl FnMut(Option<usize>, String)) {
+    let at = Some(offset);
+    let expected = header.raw_size();
+    let mut decoder = ZlibDecoder::new(IdatReader { chunks: idat, pos: 0 }).take(expected + 1);
+    let mut rows = header.rows().into_iter().flat_map(|(row_bytes, rows)| std::iter::repeat_n(row_bytes, rows as usize));
+    let mut buf = vec![0; 64 << 10];
+    // Stream offsets of the bytes read so far and of the next filter type byte.
+    let (mut total, mut filter_at) = (0u64, 0u64);
+    let mut bad_filters = 0;
+    loop {
+        let n = match decoder.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                report(at, format!("zlib stream: {}", e));
+                return;
+            }
+        };
+        while filter_at < total + n as u64 {
+            bad_filters += (buf[(filter_at - total) as usize] > 4) as usize;
+            filter_at = rows.next().map_or(u64::MAX, |row_bytes| filter_at + 1 + row_bytes);
+        }
+        total += n as u64;
+    }
+    if total != expected {
+        let what = if total < expected { "short" } else { "long" };
+        report(at, format!("image data too {}: expected {} bytes", what, expected));
+        return;
+    }
+    if bad_filters > 0 {
+        report(at, format!("{} scanlines with an invalid filter type", bad_filters));
+    }
+}
+
+/// Reads the concatenated payload of consecutive IDAT chunks.
+struct IdatReader<'a> {
+    chunks: &'a [Chunk<'a>],
+    pos: usize,
+}
+
+impl Read for IdatReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while let Some(chunk) = self.chunks.first() {
+            if self.pos < chunk.data.len() {
+                let n = buf.len().min(chunk.data.len() - self.pos);
+                buf[..n].copy_from_slice(&chunk.data[self.pos..][..n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            self.chunks = &self.chunks[1..];
+            self.pos = 0;
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(width: u32, height: u32) -> Vec<u8> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, width, height);
+        encoder.set_color(png::ColorType::Grayscale);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&vec![0; (width * height) as usize]).unwrap();
+        writer.finish().unwrap();
+        png
+    }
+
+    #[test]
+    fn leaves_image_data_over_the_limit_unverified() {
+        let png = gray(64, 64);
+        assert_eq!(check(&png), []);
+        assert_eq!(check_with(&png, 64 * 65), Checked { violations: Vec::new(), unverified: None });
+        let checked = check_with(&png, 64 * 65 - 1);
+        assert_eq!(checked.violations, []);
+        assert_eq!(checked.unverified.as_deref(), Some("image data of 4160 bytes is over the limit of 4159"));
+    }
+
+    #[test]
+    fn finds_bad_filter_types_across_buffers() {
+        let (width, height) = (300u32, 300u32);
+        let mut raw = vec![0; (height * (width + 1)) as usize];
+        raw[(height - 1) as usize * (width + 1) as usize] = 5;
+        let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+        std::io::Write::write_all(&mut zlib, &raw).unwrap();
+        let mut png = chunks::SIGNATURE.to_vec();
+        let ihdr = [&width.to_be_bytes()[..], &height.to_be_bytes(), &[8, 0, 0, 0, 0]].concat();
+        chunks::write_chunk(&mut png, b"IHDR", &ihdr).unwrap();
+        chunks::write_chunk(&mut png, b"IDAT", &zlib.finish().unwrap()).unwrap();
+        chunks::write_chunk(&mut png, b"IEND", &[]).unwrap();
+        let violations = check(&png);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].message, "1 scanlines with an invalid filter type");
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    fmt,
+    io::{self, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+/// A chunk as stored in the file, borrowed from the source buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct Chunk<'a> {
+    /// Byte offset of the length field.
+    pub offset: usize,
+    pub kind: [u8; 4],
+    pub data: &'a [u8],
+    pub crc: u32,
+}
+
+impl Chunk<'_> {
+    pub fn name(&self) -> String {
+        String::from_utf8_lossy(&self.kind).into_owned()
+    }
+
+    pub fn computed_crc(&self) -> u32 {
+        crc(&self.kind, self.data)
+    }
+
+    pub fn crc_ok(&self) -> bool {
+        self.crc == self.computed_crc()
+    }
+
+    /// Critical chunks have an uppercase first letter; decoders must not skip unknown ones.
+    pub fn is_critical(&self) -> bool {
+        self.kind[0].is_ascii_uppercase()
+    }
+
+    /// Total size in the file including length, type and CRC.
+    pub fn len(&self) -> usize {
+        self.data.len() + 12
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Keyword and text of a `tEXt` chunk.
+    pub fn text(&self) -> Option<(&[u8], &[u8])> {
+        if &self.kind != b"tEXt" {
+            return None;
+        }
+        let nul = self.data.iter().position(|&b| b == 0)?;
+        Some((&self.data[..nul], &self.data[nul + 1..]))
+    }
+
+    /// Keyword and text of a `tEXt`, `zTXt` or `iTXt` chunk, decompressed.
+    pub fn decoded_text(&self) -> Option<(String, String)> {
+        let nul = self.data.iter().position(|&b| b == 0)?;
+        let (keyword, rest) = (latin1(&self.data[..nul]), &self.data[nul + 1..]);
+        let inflate = |data: &[u8]| miniz_oxide::inflate::decompress_to_vec_zlib(data).ok();
+        match &self.kind {
+            b"tEXt" => Some((keyword, latin1(rest))),
+            b"zTXt" if rest.first() == Some(&0) => Some((keyword, latin1(&inflate(&rest[1..])?))),
+            b"iTXt" if rest.len() >= 2 => {
+                let (compressed, rest) = (rest[0] == 1, &rest[2..]);
+                // Skip the language tag and translated keyword.
+                let lang = rest.iter().position(|&b| b == 0)?;
+                let rest = &rest[lang + 1..];
+                let translated = rest.iter().position(|&b| b == 0)?;
+                let text = &rest[translated + 1..];
+                let text = if compressed { inflate(text)? } else { text.to_vec() };
+                Some((keyword, String::from_utf8(text).ok()?))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Type and data of the smallest chunk holding `text`: `tEXt`, or `zTXt` when compression pays
+/// off, and `iTXt` (compressed when that is smaller) for text outside Latin-1. Keywords are
+/// Latin-1; other characters become `?`.
+pub fn text_chunk(keyword: &str, text: &str) -> ([u8; 4], Vec<u8>) {
+    let mut data = keyword.chars().map(|c| u8::try_from(c).unwrap_or(b'?')).collect::<Vec<_>>();
+    data.push(0);
+    let deflate = |bytes: &[u8]| miniz_oxide::deflate::compress_to_vec_zlib(bytes, 9);
+    if let Some(plain) = text.chars().map(|c| u8::try_from(c).ok()).collect::<Option<Vec<_>>>() {
+        let compressed = deflate(&plain);
+        if compressed.len() + 1 < plain.len() {
+            data.push(0);
+            data.extend(compressed);
+            return (*b"zTXt", data);
+        }
+        data.extend(plain);
+        return (*b"tEXt", data);
+    }
+    let compressed = deflate(text.as_bytes());
+    let compress = compressed.len() < text.len();
+    // Compression flag and method, then empty language tag and translated keyword.
+    data.extend([compress as u8, 0, 0, 0]);
+    data.extend(if compress { compressed } else { text.as_bytes().to_vec() });
+    (*b"iTXt", data)
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+/// Result of splitting a file into chunks without interpreting them.
+#[derive(Debug)]
+pub struct Chunks<'a> {
+    pub signature_ok: bool,
+    pub chunks: Vec<Chunk<'a>>,
+    /// Offset where parsing stopped because the remaining bytes do not form a complete chunk.
+    pub truncated_at: Option<usize>,
+    /// Bytes following `IEND`.
+    pub trailing: &'a [u8],
+}
+
+pub fn parse(src: &[u8]) -> Chunks<'_> {
+    let signature_ok = src.starts_with(&SIGNATURE);
+    let mut pos = SIGNATURE.len().min(src.len());
+    let mut chunks = Vec::new();
+    let mut truncated_at = None;
+    while pos < src.len() {
+        let rest = &src[pos..];
+        if rest.len() < 12 {
+            truncated_at = Some(pos);
+            break;
+        }
+        let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        if rest.len() - 12 < len {
+            truncated_at = Some(pos);
+            break;
+        }
+        let chunk = Chunk {
+            offset: pos,
+            kind: rest[4..8].try_into().unwrap(),
+            data: &rest[8..8 + len],
+            crc: u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap()),
+        };
+        chunks.push(chunk);
+        pos += chunk.len();
+        if &chunk.kind == b"IEND" {
+            break;
+        }
+    }
+    Chunks {
+        signature_ok,
+        chunks,
+        truncated_at,
+        trailing: if truncated_at.is_none() { &src[pos..] } else { &[] },
+    }
+}
+
+/// Chunks of one type in two versions of a file: how many there are and their total size in
+/// bytes, including length, type and CRC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkChange {
+    pub kind: [u8; 4],
+    pub before: (usize, usize),
+    pub after: (usize, usize),
+}
+
+impl ChunkChange {
+    /// `kept`, `stripped` or `added`.
+    pub fn status(&self) -> &'static str {
+        match (self.before.0, self.after.0) {
+            (0, _) => "added",
+            (_, 0) => "stripped",
+            _ => "kept",
+        }
+    }
+
+    pub fn delta(&self) -> isize {
+        self.after.1 as isize - self.before.1 as isize
+    }
+}
+
+impl fmt::Display for ChunkChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let side = |(count, bytes): (usize, usize)| if count > 1 { format!("{}x{}", count, bytes) } else { bytes.to_string() };
+        write!(f, "{:<8} {} ", self.status(), String::from_utf8_lossy(&self.kind))?;
+        match self.status() {
+            "added" => write!(f, "{}", side(self.after)),
+            "stripped" => write!(f, "{}", side(self.before)),
+            _ => write!(f, "{} -> {} ({:+})", side(self.before), side(self.after), self.delta()),
+        }
+    }
+}
+
+/// Chunk types of `before` and `after` in the order they first appear, those of `before` first.
+pub fn diff(before: &[u8], after: &[u8]) -> Vec<ChunkChange> {
+    let mut changes: Vec<ChunkChange> = Vec::new();
+    for (i, src) in [before, after].into_iter().enumerate() {
+        for chunk in parse(src).chunks {
+            let at = match changes.iter().position(|c| c.kind == chunk.kind) {
+                Some(at) => at,
+                None => {
+                    changes.push(ChunkChange { kind: chunk.kind, before: (0, 0), after: (0, 0) });
+                    changes.len() - 1
+                }
+            };
+            let side = if i == 0 { &mut changes[at].before } else { &mut changes[at].after };
+            *side = (side.0 + 1, side.1 + chunk.len());
+        }
+    }
+    changes
+}
+
+/// CRC-32 of a chunk.
+pub fn crc(kind: &[u8; 4], data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(kind);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Writes one chunk with a freshly computed CRC.
+pub fn write_chunk<W: Write>(mut w: W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    w.write_all(&crc(kind, data).to_be_bytes())
+}
+
+/// Data of a `tIME` chunk for `t`: UTC year (big-endian), month, day, hour, minute and second.
+pub fn time(t: SystemTime) -> [u8; 7] {
+    let secs = t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Days to civil date, after Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = (yoe + era * 400 + (month <= 2) as u64) as u16;
+    let [y0, y1] = year.to_be_bytes();
+    [y0, y1, month as u8, day as u8, (rem / 3600) as u8, (rem / 60 % 60) as u8, (rem % 60) as u8]
+}
+
+/// The UTC time `seconds` into a civil date from 1970 on, the inverse of [`time`].
+pub fn from_civil(year: u64, month: u64, day: u64, seconds: u64) -> SystemTime {
+    // Civil date to days, after Howard Hinnant's `days_from_civil`.
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let (era, yoe) = (y / 400, y % 400);
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + (153 * m + 2) / 5 + day - 1;
+    let days = era * 146_097 + doe - 719_468;
+    UNIX_EPOCH + Duration::from_secs(days * 86400 + seconds)
+}
@@ -0,0 +1,138 @@
+use std::{ffi::OsString, fs::{self, File}, io::{self, BufWriter, Write}, path::{Path, PathBuf}, time::Duration};
+
+use compress_png::{analysis, apng, check, mng, raw::{self, RawFormat}, OptimizeOptions};
+
+use crate::{json, paths, walk::Walker};
+
+pub fn check(src: &[OsString], recursive: bool, max_image_data: u64) -> io::Result<()> {
+    let mut walker = Walker::new(recursive, false);
+    for src in src {
+        walker.add_root(Path::new(src))?;
+    }
+    let mut invalid = 0;
+    for input in &walker.inputs {
+        let data = fs::read(paths::long(&input.path))?;
+        let checked = check::check_with(&data, max_image_data);
+        if let Some(reason) = &checked.unverified {
+            println!("{}: not verified: {}", paths::display(&input.path), reason);
+        }
+        if checked.violations.is_empty() {
+            println!("{}: ok", paths::display(&input.path));
+            continue;
+        }
+        invalid += 1;
+        for v in checked.violations {
+            println!("{}: {}", paths::display(&input.path), v);
+        }
+    }
+    if invalid > 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} of {} files have violations", invalid, walker.inputs.len())));
+    }
+    Ok(())
+}
+
+/// Prints the analysis of every input, looking at one row in `sample`.
+pub fn analyze(src: &[OsString], recursive: bool, sample: u32) -> io::Result<()> {
+    let mut walker = Walker::new(recursive, false);
+    for src in src {
+        walker.add_root(Path::new(src))?;
+    }
+    for input in &walker.inputs {
+        let image = compress_png::decode(&fs::read(paths::long(&input.path))?)?;
+        println!("{}: {}x{} {:?} {}", paths::display(&input.path), image.width, image.height, image.color_type, analysis::analyze_sampled(&image, sample));
+    }
+    Ok(())
+}
+
+/// Writes every frame of an APNG as `<stem>.frame-NNN.png` plus timing in `<stem>.frames.json`,
+/// or every standalone image embedded in an MNG as `<stem>.image-NNN.png`.
+pub fn extract(src: &Path, out_dir: &Path) -> io::Result<()> {
+    let data = fs::read(paths::long(src))?;
+    match mng::container(&data) {
+        Some(mng::Container::Mng) => return extract_mng(src, &data, out_dir),
+        Some(container) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported container: {}", container))),
+        None => {}
+    }
+    let animation = apng::decode_animation(&data)?;
+    let out_dir = paths::long(out_dir);
+    fs::create_dir_all(&out_dir)?;
+    let stem = src.file_stem().unwrap_or(src.as_os_str());
+    let file_name = |suffix: &str| {
+        let mut name = stem.to_os_string();
+        name.push(suffix);
+        paths::safe_file_name(&name)
+    };
+    let opts = OptimizeOptions::default();
+    let mut frames = Vec::new();
+    for (i, frame) in animation.frames.iter().enumerate() {
+        let name = file_name(&format!(".frame-{:03}.png", i));
+        let data = frame.image.optimize(&opts)?;
+        fs::write(out_dir.join(&name), &data)?;
+        println!("{} delay={}ms size={}", paths::display(Path::new(&name)), frame.delay_ms(), data.len());
+        frames.push(format!(
+            "{{\"file\": {}, \"delay_ms\": {}, \"delay_num\": {}, \"delay_den\": {}}}",
+            json::string(&name.to_string_lossy()),
+            frame.delay_ms(),
+            frame.delay_num,
+            frame.delay_den,
+        ));
+    }
+    let sidecar = format!(
+        "{{\n  \"width\": {},\n  \"height\": {},\n  \"plays\": {},\n  \"frames\": [\n    {}\n  ]\n}}\n",
+        animation.width,
+        animation.height,
+        animation.plays,
+        frames.join(",\n    "),
+    );
+    fs::write(out_dir.join(file_name(".frames.json")), sidecar)
+}
+
+fn extract_mng(src: &Path, data: &[u8], out_dir: &Path) -> io::Result<()> {
+    let images = mng::embedded_pngs(data)?;
+    if images.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: no standalone PNG images in this MNG", paths::display(src))));
+    }
+    let out_dir = paths::long(out_dir);
+    fs::create_dir_all(&out_dir)?;
+    let stem = src.file_stem().unwrap_or(src.as_os_str());
+    let opts = OptimizeOptions::default();
+    for (i, png) in images.iter().enumerate() {
+        let mut name = stem.to_os_string();
+        name.push(format!(".image-{:03}.png", i));
+        let name = paths::safe_file_name(&name);
+        let (optimized, _) = compress_png::optimize_png(png, &opts)?;
+        fs::write(out_dir.join(&name), &optimized)?;
+        println!("{} size={}", paths::display(Path::new(&name)), optimized.len());
+    }
+    Ok(())
+}
+
+/// Builds an APNG from same-sized frames shown `delay` each.
+pub fn animate(frames: &[PathBuf], delay: Duration, plays: u32, output: &Path) -> io::Result<()> {
+    let delay_num = delay.as_millis().min(u16::MAX as u128) as u16;
+    let frames = frames
+        .iter()
+        .map(|path| {
+            let image = compress_png::decode(&fs::read(paths::long(path))?)?.to_rgba8();
+            Ok(apng::Frame { image, delay_num, delay_den: 1000 })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let first = frames.first().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no frames"))?;
+    let animation = apng::Animation { width: first.image.width, height: first.image.height, plays, frames };
+    let mut out = BufWriter::new(File::create(paths::long(output))?);
+    apng::encode_animation(&mut out, &animation, &OptimizeOptions::default())?;
+    out.flush()?;
+    println!("frames={} size={}", animation.frames.len(), fs::metadata(paths::long(output))?.len());
+    Ok(())
+}
+
+/// Writes the decoded samples of `src` in a raw format.
+pub fn decode(src: &Path, format: RawFormat, output: Option<&Path>) -> io::Result<()> {
+    let image = compress_png::decode(&fs::read(paths::long(src))?)?;
+    let output = output.map_or_else(|| src.with_extension(format.extension(image.color_type)), Path::to_path_buf);
+    let mut out = BufWriter::new(File::create(paths::long(&output))?);
+    raw::write_raw(&mut out, &image, format)?;
+    out.flush()?;
+    println!("{} width={} height={} color={:?} bit_depth={:?}", paths::display(&output), image.width, image.height, image.color_type, image.bit_depth);
+    Ok(())
+}
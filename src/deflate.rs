@@ -0,0 +1,137 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::{DefaultHasher, Hasher},
+    sync::{LazyLock, Mutex},
+};
+
+use miniz_oxide::deflate::core::{compress_to_output, create_comp_flags_from_zip_params, CompressionStrategy, CompressorOxide, TDEFLFlush, TDEFLStatus};
+
+use crate::Strategy;
+
+const LEVEL: i32 = 9;
+/// Negative for a raw deflate stream; the zlib framing is added here.
+const WINDOW_BITS: i32 = -15;
+/// Deflate with a 32 KiB window, and the flags miniz writes for maximum compression.
+const ZLIB_HEADER: [u8; 2] = [0x78, 0xDA];
+/// Compressed bytes [`CACHE`] holds before dropping its oldest entries.
+const CACHE_BYTES: usize = 64 << 20;
+
+/// Zlib streams compressed so far in this process, so that identical filtered data, such as
+/// repeated APNG frames or duplicate images in a batch, is compressed only once per strategy.
+static CACHE: LazyLock<Mutex<Cache>> = LazyLock::new(Mutex::default);
+
+/// Identifies a filtered scanline stream by two independent hashes and its length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct StreamKey {
+    hash: u64,
+    crc: u32,
+    len: usize,
+    strategy: Strategy,
+}
+
+impl StreamKey {
+    pub fn new(stream: &[u8], strategy: Strategy) -> Self {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(stream);
+        StreamKey { hash: hasher.finish(), crc: crc32fast::hash(stream), len: stream.len(), strategy }
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<StreamKey, Vec<u8>>,
+    order: VecDeque<StreamKey>,
+    bytes: usize,
+}
+
+/// Copies the cached zlib stream for `key` into `out`, if there is one.
+pub(crate) fn lookup(key: &StreamKey, out: &mut Vec<u8>) -> bool {
+    let cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(data) = cache.entries.get(key) else {
+        return false;
+    };
+    out.clear();
+    out.extend_from_slice(data);
+    true
+}
+
+pub(crate) fn store(key: StreamKey, data: &[u8]) {
+    if data.len() > CACHE_BYTES / 4 {
+        return;
+    }
+    let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    if cache.entries.contains_key(&key) {
+        return;
+    }
+    while cache.bytes + data.len() > CACHE_BYTES {
+        let Some(oldest) = cache.order.pop_front() else {
+            break;
+        };
+        cache.bytes -= cache.entries.remove(&oldest).map_or(0, |d| d.len());
+    }
+    cache.bytes += data.len();
+    cache.order.push_back(key);
+    cache.entries.insert(key, data.to_vec());
+}
+
+/// One compressor per strategy, reset rather than reallocated between streams: each holds
+/// several hundred kilobytes of tables.
+#[derive(Default)]
+pub(crate) struct Compressors([Option<Box<CompressorOxide>>; Strategy::ALL.len()]);
+
+/// Streaming zlib compressor with a selectable deflate strategy, which flate2 does not expose.
+/// The Adler-32 checksum is computed with SIMD rather than by miniz's scalar implementation.
+pub(crate) struct ZlibWriter<'a> {
+    compressor: &'a mut CompressorOxide,
+    adler: simd_adler32::Adler32,
+    out: &'a mut Vec<u8>,
+}
+
+impl<'a> ZlibWriter<'a> {
+    /// Compresses into `out`, replacing what it held, with a compressor from `compressors`.
+    pub fn new(compressors: &'a mut Compressors, strategy: Strategy, out: &'a mut Vec<u8>) -> Self {
+        let slot = &mut compressors.0[Strategy::ALL.iter().position(|&s| s == strategy).unwrap()];
+        let compressor = match slot {
+            Some(compressor) => {
+                compressor.reset();
+                compressor
+            }
+            None => {
+                let strategy = match strategy {
+                    Strategy::Default => CompressionStrategy::Default,
+                    Strategy::Filtered => CompressionStrategy::Filtered,
+                    Strategy::Huffman => CompressionStrategy::HuffmanOnly,
+                    Strategy::Rle => CompressionStrategy::RLE,
+                    Strategy::Fixed => CompressionStrategy::Fixed,
+                };
+                let flags = create_comp_flags_from_zip_params(LEVEL, WINDOW_BITS, strategy as i32);
+                slot.insert(Box::new(CompressorOxide::new(flags)))
+            }
+        };
+        out.clear();
+        out.extend_from_slice(&ZLIB_HEADER);
+        ZlibWriter { compressor, adler: simd_adler32::Adler32::new(), out }
+    }
+
+    pub fn write(&mut self, mut data: &[u8]) {
+        self.adler.write(data);
+        while !data.is_empty() {
+            let (status, consumed) = compress_to_output(self.compressor, data, TDEFLFlush::None, |buf| {
+                self.out.extend_from_slice(buf);
+                true
+            });
+            assert_eq!(status, TDEFLStatus::Okay);
+            data = &data[consumed..];
+        }
+    }
+
+    pub fn finish(self) -> &'a [u8] {
+        let (status, _) = compress_to_output(self.compressor, &[], TDEFLFlush::Finish, |buf| {
+            self.out.extend_from_slice(buf);
+            true
+        });
+        assert_eq!(status, TDEFLStatus::Done);
+        self.out.extend_from_slice(&self.adler.finish().to_be_bytes());
+        self.out
+    }
+}
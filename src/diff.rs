@@ -0,0 +1,134 @@
+//! Visual and numeric comparison of two versions of an image, for auditing what a lossy mode
+//! changed.
+
+use std::{borrow::Cow, fmt};
+
+use png::{BitDepth, ColorType};
+
+use crate::{pixel::Pixels, Error, Image, Result};
+
+/// How much a channel difference is multiplied by, so that the off-by-one changes of color
+/// merging are visible at all.
+pub const AMPLIFY: u32 = 16;
+
+/// The result of [`difference`].
+#[derive(Debug, Clone)]
+pub struct Difference {
+    /// 8-bit RGB: unchanged pixels are the luma of the original at a quarter of its brightness,
+    /// changed ones red, brighter the larger the change.
+    pub image: Image<'static>,
+    /// Number of pixels that differ.
+    pub changed: usize,
+    /// Largest difference of any 8-bit RGBA channel.
+    pub max_delta: u8,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pixels = self.image.width as usize * self.image.height as usize;
+        write!(f, "{} of {} pixels changed ({:.2}%), max delta={}", self.changed, pixels, 100.0 * self.changed as f64 / pixels.max(1) as f64, self.max_delta)
+    }
+}
+
+/// Compares `before` and `after` as 8-bit RGBA. Pixels that are fully transparent in both
+/// count as unchanged whatever their color, since nobody can see it.
+pub fn difference(before: &Image, after: &Image) -> Result<Difference> {
+    if (before.width, before.height) != (after.width, after.height) {
+        return Err(Error::Format(format!("cannot compare {}x{} with {}x{}", before.width, before.height, after.width, after.height)));
+    }
+    let (before, after) = (Pixels::from_image(before)?, Pixels::from_image(after)?);
+    let (mut changed, mut max_delta) = (0, 0);
+    let data = before
+        .rgba()
+        .zip(after.rgba())
+        .flat_map(|(a, b)| {
+            let delta = if a[3] == 0 && b[3] == 0 { 0 } else { (0..4).map(|i| a[i].abs_diff(b[i])).max().unwrap() };
+            let luma = ((a[0] as u32 * 299 + a[1] as u32 * 587 + a[2] as u32 * 114) / 1000 * a[3] as u32 / 255 / 4) as u8;
+            if delta == 0 {
+                return [luma; 3];
+            }
+            changed += 1;
+            max_delta = max_delta.max(delta);
+            [(64 + delta as u32 * AMPLIFY).min(255) as u8, luma, luma]
+        })
+        .collect::<Vec<_>>();
+    let image = Image {
+        width: before.width(),
+        height: before.height(),
+        color_type: ColorType::Rgb,
+        bit_depth: BitDepth::Eight,
+        palette: None,
+        trns: None,
+        data: Cow::Owned(data),
+        text: Vec::new(),
+        chunks: Vec::new(),
+        premultiplied: false,
+    };
+    Ok(Difference { image, changed, max_delta })
+}
+
+/// Objective quality of a lossy result against its source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quality {
+    /// Peak signal-to-noise ratio over the 8-bit RGBA channels in dB; infinite when nothing
+    /// changed.
+    pub psnr: f64,
+    /// Mean structural similarity of the luma of the pixels over black, in 8x8 windows every
+    /// 4 pixels; 1 when nothing changed.
+    pub ssim: f64,
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "psnr={:.2}dB ssim={:.4}", self.psnr, self.ssim)
+    }
+}
+
+/// PSNR and SSIM of `after` against `before`.
+pub fn quality(before: &Image, after: &Image) -> Result<Quality> {
+    if (before.width, before.height) != (after.width, after.height) {
+        return Err(Error::Format(format!("cannot compare {}x{} with {}x{}", before.width, before.height, after.width, after.height)));
+    }
+    let width = before.width as usize;
+    let (before, after) = (Pixels::from_image(before)?.rgba().collect::<Vec<_>>(), Pixels::from_image(after)?.rgba().collect::<Vec<_>>());
+    let squared = before.iter().zip(&after).flat_map(|(a, b)| (0..4).map(|i| (a[i] as f64 - b[i] as f64).powi(2))).sum::<f64>();
+    let mse = squared / (4 * before.len()).max(1) as f64;
+    let psnr = if mse == 0.0 { f64::INFINITY } else { 10.0 * (255.0 * 255.0 / mse).log10() };
+    let luma = |px: &[u8; 4]| (px[0] as f64 * 0.299 + px[1] as f64 * 0.587 + px[2] as f64 * 0.114) * px[3] as f64 / 255.0;
+    let (before, after) = (before.iter().map(luma).collect::<Vec<_>>(), after.iter().map(luma).collect::<Vec<_>>());
+    let ssim = ssim(&before, &after, width);
+    Ok(Quality { psnr, ssim })
+}
+
+/// Mean SSIM of two luma planes of `width` columns, with the usual constants for 8-bit samples.
+/// Images smaller than a window are compared as a single window.
+fn ssim(a: &[f64], b: &[f64], width: usize) -> f64 {
+    const WINDOW: usize = 8;
+    const STEP: usize = 4;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let height = a.len() / width.max(1);
+    if a.is_empty() {
+        return 1.0;
+    }
+    let (window_w, window_h) = (WINDOW.min(width), WINDOW.min(height));
+    let starts = |len: usize, window: usize| (0..=len - window).step_by(STEP);
+    let (mut sum, mut windows) = (0.0, 0);
+    for y in starts(height, window_h) {
+        for x in starts(width, window_w) {
+            let at = |plane: &[f64], i: usize| plane[(y + i / window_w) * width + x + i % window_w];
+            let n = (window_w * window_h) as f64;
+            let (mean_a, mean_b) = ((0..window_w * window_h).map(|i| at(a, i)).sum::<f64>() / n, (0..window_w * window_h).map(|i| at(b, i)).sum::<f64>() / n);
+            let (mut var_a, mut var_b, mut cov) = (0.0, 0.0, 0.0);
+            for i in 0..window_w * window_h {
+                let (da, db) = (at(a, i) - mean_a, at(b, i) - mean_b);
+                var_a += da * da / n;
+                var_b += db * db / n;
+                cov += da * db / n;
+            }
+            sum += (2.0 * mean_a * mean_b + C1) * (2.0 * cov + C2) / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+    sum / windows as f64
+}
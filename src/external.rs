@@ -0,0 +1,47 @@
+use std::{
+    env,
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+};
+
+/// An external PNG optimizer and how to make it write `out` from `src`.
+pub struct Tool {
+    pub name: &'static str,
+    args: fn(&Path, &Path) -> Vec<OsString>,
+}
+
+const TOOLS: [Tool; 3] = [
+    Tool { name: "oxipng", args: |src, out| vec!["-q".into(), "-o".into(), "max".into(), "--out".into(), out.into(), src.into()] },
+    Tool { name: "optipng", args: |src, out| vec!["-quiet".into(), "-o7".into(), "-out".into(), out.into(), src.into()] },
+    Tool { name: "pngcrush", args: |src, out| vec!["-q".into(), "-brute".into(), src.into(), out.into()] },
+];
+
+/// The tools found on `PATH`, with their executable.
+pub fn detect() -> Vec<(&'static Tool, PathBuf)> {
+    let dirs = env::var_os("PATH").map(|path| env::split_paths(&path).collect::<Vec<_>>()).unwrap_or_default();
+    TOOLS
+        .iter()
+        .filter_map(|tool| {
+            let exe = format!("{}{}", tool.name, env::consts::EXE_SUFFIX);
+            dirs.iter().map(|dir| dir.join(&exe)).find(|path| path.is_file()).map(|path| (tool, path))
+        })
+        .collect()
+}
+
+/// Runs `tool` on a copy of `src` in `temp_dir` and returns the size it produced.
+pub fn run(tool: &Tool, exe: &Path, src: &[u8], temp_dir: &Path) -> io::Result<u64> {
+    let temp = |suffix: &str| temp_dir.join(format!("compress-png-{}-{}{}", process::id(), tool.name, suffix));
+    let (input, output) = (temp(".in.png"), temp(".out.png"));
+    fs::write(&input, src)?;
+    let _ = fs::remove_file(&output);
+    let status = Command::new(exe).args((tool.args)(&input, &output)).stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).status();
+    let size = fs::metadata(&output).map(|m| m.len());
+    let _ = fs::remove_file(&input);
+    let _ = fs::remove_file(&output);
+    match status? {
+        status if status.success() => size,
+        status => Err(io::Error::other(format!("{} exited with {}", tool.name, status))),
+    }
+}
@@ -0,0 +1,70 @@
+//! `http://` and `https://` inputs, downloaded with the system `curl` before the run.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs, io,
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+};
+
+use crate::{paths, walk::Input};
+
+/// Whether a command line input is a URL rather than a path.
+pub fn is_url(src: &OsStr) -> bool {
+    src.to_str().is_some_and(|s| ["http://", "https://"].iter().any(|scheme| s.get(..scheme.len()).is_some_and(|p| p.eq_ignore_ascii_case(scheme))))
+}
+
+/// Downloaded inputs, removed again when dropped.
+pub struct Downloads {
+    dir: PathBuf,
+    files: Vec<(OsString, Input)>,
+}
+
+impl Downloads {
+    /// Downloads every URL among `srcs` into `temp_dir`.
+    pub fn fetch(srcs: &[OsString], temp_dir: &Path) -> io::Result<Downloads> {
+        let mut downloads = Downloads { dir: temp_dir.join(format!("compress-png-{}", process::id())), files: Vec::new() };
+        for (i, url) in srcs.iter().filter(|src| is_url(src)).enumerate() {
+            let url_str = url.to_string_lossy();
+            let rel = PathBuf::from(file_name(&url_str));
+            // One directory per URL keeps equal file names apart.
+            let path = downloads.dir.join(i.to_string()).join(&rel);
+            fs::create_dir_all(path.parent().unwrap())?;
+            println!("{} -> {}", url_str, paths::display(&path));
+            let status = Command::new("curl")
+                .args(["--fail", "--silent", "--show-error", "--location", "--proto", "=http,https", "--proto-redir", "=http,https", "--output"])
+                .arg(&path)
+                .arg("--url")
+                .arg(url)
+                .stdin(Stdio::null())
+                .status()
+                .map_err(|e| match e.kind() {
+                    io::ErrorKind::NotFound => io::Error::new(e.kind(), format!("cannot fetch {}: curl is not installed or not on PATH", url_str)),
+                    _ => io::Error::new(e.kind(), format!("cannot run curl to fetch {}: {}", url_str, e)),
+                })?;
+            if !status.success() {
+                return Err(io::Error::other(format!("{}: curl exited with {}", url_str, status)));
+            }
+            downloads.files.push((url.clone(), Input { path, rel, same_as: None }));
+        }
+        Ok(downloads)
+    }
+
+    /// The downloaded input for `src`, if it is a URL.
+    pub fn input(&self, src: &OsStr) -> Option<Input> {
+        self.files.iter().find(|(url, _)| url == src).map(|(_, input)| input.clone())
+    }
+}
+
+impl Drop for Downloads {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// The last path segment of `url`, made safe as a file name.
+fn file_name(url: &str) -> OsString {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let name = path.split_once("://").map_or(path, |(_, rest)| rest.split_once('/').map_or("", |(_, path)| path)).rsplit('/').next().unwrap_or_default();
+    paths::safe_file_name(OsStr::new(if name.is_empty() { "download.png" } else { name }))
+}
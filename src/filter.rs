@@ -0,0 +1,61 @@
+use png::FilterType;
+
+use crate::FILTERS;
+
+/// Applies `filter` to one scanline; `prev` is all zeros for the first row.
+pub(crate) fn filter_row(filter: FilterType, bpp: usize, prev: &[u8], cur: &[u8], out: &mut [u8]) {
+    match filter {
+        FilterType::NoFilter => out.copy_from_slice(cur),
+        FilterType::Sub => {
+            for i in 0..cur.len() {
+                let left = if i >= bpp { cur[i - bpp] } else { 0 };
+                out[i] = cur[i].wrapping_sub(left);
+            }
+        }
+        FilterType::Up => {
+            for i in 0..cur.len() {
+                out[i] = cur[i].wrapping_sub(prev[i]);
+            }
+        }
+        FilterType::Avg => {
+            for i in 0..cur.len() {
+                let left = if i >= bpp { cur[i - bpp] } else { 0 };
+                out[i] = cur[i].wrapping_sub(((left as u16 + prev[i] as u16) / 2) as u8);
+            }
+        }
+        FilterType::Paeth => {
+            for i in 0..cur.len() {
+                let (left, up_left) = if i >= bpp { (cur[i - bpp], prev[i - bpp]) } else { (0, 0) };
+                out[i] = cur[i].wrapping_sub(paeth(left, prev[i], up_left));
+            }
+        }
+    }
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Picks, per row, the filter whose output has the smallest sum of absolute (signed) byte values.
+pub(crate) fn filter_row_adaptive(bpp: usize, prev: &[u8], cur: &[u8], out: &mut [u8], scratch: &mut [u8]) -> FilterType {
+    let mut best = (u64::MAX, FilterType::NoFilter);
+    for filter in FILTERS {
+        filter_row(filter, bpp, prev, cur, scratch);
+        let cost = scratch.iter().map(|&b| (b as i8).unsigned_abs() as u64).sum::<u64>();
+        if cost < best.0 {
+            best = (cost, filter);
+            out.copy_from_slice(scratch);
+        }
+    }
+    best.1
+}
@@ -0,0 +1,63 @@
+use std::{
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Staged (added, copied or modified) PNG files, as paths under the repository root. Files that
+/// also have unstaged changes are returned separately: optimizing and re-staging them would
+/// stage those changes too.
+pub fn staged_pngs() -> io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let root = git(&["rev-parse", "--show-toplevel"])?;
+    let root = PathBuf::from(os_string(root.strip_suffix(b"\n").unwrap_or(&root)));
+    let unstaged = lines(&git(&["diff", "--name-only", "-z"])?).collect::<Vec<_>>();
+    let (mut clean, mut partial) = (Vec::new(), Vec::new());
+    for name in lines(&git(&["diff", "--cached", "--name-only", "--diff-filter=ACM", "-z"])?) {
+        if !Path::new(&name).extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png")) {
+            continue;
+        }
+        if unstaged.contains(&name) {
+            partial.push(root.join(name));
+        } else {
+            clean.push(root.join(name));
+        }
+    }
+    Ok((clean, partial))
+}
+
+pub fn add(paths: &[PathBuf]) -> io::Result<()> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    let status = Command::new("git").arg("add").arg("--").args(paths).status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("git add exited with {}", status)));
+    }
+    Ok(())
+}
+
+fn git(args: &[&str]) -> io::Result<Vec<u8>> {
+    let output = Command::new("git").args(args).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("git {}: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    Ok(output.stdout)
+}
+
+/// Splits the NUL separated output of `-z`.
+fn lines(out: &[u8]) -> impl Iterator<Item = OsString> + '_ {
+    out.split(|&b| b == 0).filter(|s| !s.is_empty()).map(os_string)
+}
+
+#[cfg(unix)]
+fn os_string(bytes: &[u8]) -> OsString {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::OsStr::from_bytes(bytes).to_os_string()
+}
+
+/// Git writes UTF-8 paths on other platforms.
+#[cfg(not(unix))]
+fn os_string(bytes: &[u8]) -> OsString {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
@@ -0,0 +1,12 @@
+/// A GitHub Actions workflow command (`::warning file=...::message`) annotating `file`.
+pub fn warning(file: &str, title: &str, message: &str) -> String {
+    format!("::warning file={},title={}::{}", property(file), property(title), data(message))
+}
+
+fn data(s: &str) -> String {
+    s.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+fn property(s: &str) -> String {
+    data(s).replace(':', "%3A").replace(',', "%2C")
+}
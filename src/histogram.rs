@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+
+use png::ColorType;
+
+use crate::pixel::IterPixel;
+
+/// Exact count of every RGBA color in an image.
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    counts: HashMap<[u8; 4], u64>,
+}
+
+impl Histogram {
+    /// Number of distinct colors.
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Number of pixels.
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    pub fn count(&self, rgba: [u8; 4]) -> u64 {
+        self.counts.get(&rgba).copied().unwrap_or(0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=([u8; 4], u64)> + '_ {
+        self.counts.iter().map(|(&rgba, &n)| (rgba, n))
+    }
+
+    /// The `n` most frequent colors, most frequent first; ties are ordered by color value.
+    pub fn dominant(&self, n: usize) -> Vec<([u8; 4], u64)> {
+        let mut colors = self.iter().collect::<Vec<_>>();
+        colors.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        colors.truncate(n);
+        colors
+    }
+
+    /// Adds the counts of `other`, as for the colors of several images together.
+    pub fn merge(&mut self, other: &Histogram) {
+        for (rgba, n) in other.iter() {
+            *self.counts.entry(rgba).or_insert(0) += n;
+        }
+    }
+
+    /// The most frequent color, if any.
+    pub fn most_common(&self) -> Option<([u8; 4], u64)> {
+        self.dominant(1).pop()
+    }
+}
+
+/// Counts colors of 8-bit samples; colors without alpha are counted as opaque.
+///
+/// # Panics
+///
+/// Panics on [`ColorType::Indexed`], whose samples are not colors.
+pub fn color_histogram(data: &[u8], color: ColorType) -> Histogram {
+    let mut counts = HashMap::new();
+    let mut visit = |rgba: [u8; 4]| *counts.entry(rgba).or_insert(0) += 1;
+    match color {
+        ColorType::Grayscale => data.iter().for_each(|&g| visit([g, g, g, 0xFF])),
+        ColorType::Indexed => panic!("indexed data has no colors without its palette"),
+        ColorType::GrayscaleAlpha => data.iter_ga().for_each(|(g, a)| visit([g, g, g, a])),
+        ColorType::Rgb => data.iter_rgb().for_each(|(r, g, b)| visit([r, g, b, 0xFF])),
+        ColorType::Rgba => data.iter_rgba().for_each(|(r, g, b, a)| visit([r, g, b, a])),
+    }
+    Histogram { counts }
+}
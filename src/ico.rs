@@ -0,0 +1,164 @@
+use std::{borrow::Cow, io::{self, Write}};
+
+use png::{BitDepth, ColorType};
+
+use crate::{chunks::SIGNATURE, decode, Error, Image, Result};
+
+/// One image of an icon; `data` is a complete PNG file or a BMP without its file header.
+#[derive(Debug, Clone)]
+pub struct IcoEntry {
+    pub width: u32,
+    pub height: u32,
+    /// Bits per pixel as stored in the directory.
+    pub bit_count: u16,
+    pub data: Vec<u8>,
+}
+
+impl IcoEntry {
+    pub fn is_png(&self) -> bool {
+        self.data.starts_with(&SIGNATURE)
+    }
+
+    /// Decodes the image to 8-bit samples; BMP images come out as RGBA.
+    pub fn decode(&self) -> Result<Image<'static>> {
+        if self.is_png() {
+            decode(&self.data)
+        } else {
+            decode_bmp(&self.data)
+        }
+    }
+}
+
+pub fn is_ico(src: &[u8]) -> bool {
+    src.len() >= 6 && src[..4] == [0, 0, 1, 0]
+}
+
+pub fn parse_ico(src: &[u8]) -> Result<Vec<IcoEntry>> {
+    if !is_ico(src) {
+        return Err(Error::Format("not an icon file".to_string()));
+    }
+    let count = u16_at(src, 4) as usize;
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let dir = src.get(6 + 16 * i..22 + 16 * i).ok_or_else(|| Error::Format("icon directory is truncated".to_string()))?;
+        let dimension = |v: u8| if v == 0 { 256 } else { v as u32 };
+        let len = u32_at(dir, 8) as usize;
+        let offset = u32_at(dir, 12) as usize;
+        let data = offset.checked_add(len).and_then(|end| src.get(offset..end)).ok_or_else(|| Error::Format(format!("icon image {} is truncated", i)))?;
+        entries.push(IcoEntry {
+            width: dimension(dir[0]),
+            height: dimension(dir[1]),
+            bit_count: u16_at(dir, 6),
+            data: data.to_vec(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Writes a Windows icon holding `entries`, each at most 256 pixels wide and high.
+pub fn write_ico<W: Write>(mut w: W, entries: &[IcoEntry]) -> io::Result<()> {
+    let count = u16::try_from(entries.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many icon images"))?;
+    w.write_all(&[0, 0, 1, 0])?;
+    w.write_all(&count.to_le_bytes())?;
+    let mut offset = 6 + 16 * entries.len();
+    for entry in entries {
+        let dimension = |v: u32| match v {
+            1..=255 => Ok(v as u8),
+            256 => Ok(0),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("icon images must be 1 to 256 pixels, not {}", v))),
+        };
+        w.write_all(&[dimension(entry.width)?, dimension(entry.height)?, 0, 0])?;
+        w.write_all(&1u16.to_le_bytes())?;
+        w.write_all(&entry.bit_count.to_le_bytes())?;
+        w.write_all(&(entry.data.len() as u32).to_le_bytes())?;
+        w.write_all(&(offset as u32).to_le_bytes())?;
+        offset += entry.data.len();
+    }
+    for entry in entries {
+        w.write_all(&entry.data)?;
+    }
+    Ok(())
+}
+
+/// Decodes an icon BMP: a BITMAPINFOHEADER, an optional palette, the bottom-up color rows
+/// and a 1-bit transparency mask, the two bitmaps together being twice the icon height.
+fn decode_bmp(src: &[u8]) -> Result<Image<'static>> {
+    let format = |msg: &str| Error::Format(format!("icon bitmap: {}", msg));
+    if src.len() < 40 || u32_at(src, 0) < 40 {
+        return Err(format("header is truncated"));
+    }
+    let header_len = u32_at(src, 0) as usize;
+    let width = u32_at(src, 4) as i32;
+    let height = u32_at(src, 8) as i32 / 2;
+    let bit_count = u16_at(src, 14) as usize;
+    if u32_at(src, 16) != 0 {
+        return Err(format("compressed bitmaps are not supported"));
+    }
+    if width <= 0 || height <= 0 {
+        return Err(format("bad dimensions"));
+    }
+    let (width, height) = (width as usize, height as usize);
+    let palette_len = match bit_count {
+        1 | 4 | 8 => match u32_at(src, 32) as usize {
+            0 => 1 << bit_count,
+            n => n,
+        },
+        24 | 32 => 0,
+        _ => return Err(format(&format!("{} bits per pixel is not supported", bit_count))),
+    };
+    let palette = src.get(header_len..header_len + 4 * palette_len).ok_or_else(|| format("palette is truncated"))?;
+    let stride = (width * bit_count).div_ceil(32) * 4;
+    let mask_stride = width.div_ceil(32) * 4;
+    let pixels_at = header_len + palette.len();
+    let mask_at = pixels_at + stride * height;
+    let pixels = src.get(pixels_at..mask_at).ok_or_else(|| format("pixels are truncated"))?;
+    // Some writers omit the mask of 32-bit images.
+    let mask = src.get(mask_at..mask_at + mask_stride * height);
+
+    let mut data = Vec::with_capacity(width * height * 4);
+    for y in (0..height).rev() {
+        let row = &pixels[y * stride..][..stride];
+        for x in 0..width {
+            let bgra = match bit_count {
+                32 => [row[4 * x], row[4 * x + 1], row[4 * x + 2], row[4 * x + 3]],
+                24 => [row[3 * x], row[3 * x + 1], row[3 * x + 2], 0xFF],
+                _ => {
+                    let bit = x * bit_count;
+                    let index = (row[bit / 8] >> (8 - bit_count - bit % 8)) as usize & ((1 << bit_count) - 1);
+                    let entry = palette.get(4 * index..4 * index + 3).ok_or_else(|| format("palette index out of range"))?;
+                    [entry[0], entry[1], entry[2], 0xFF]
+                }
+            };
+            let masked = mask.is_some_and(|mask| mask[y * mask_stride + x / 8] >> (7 - x % 8) & 1 == 1);
+            data.extend([bgra[2], bgra[1], bgra[0], if masked && bit_count != 32 { 0 } else { bgra[3] }]);
+        }
+    }
+    // 32-bit images without any alpha rely on the mask alone.
+    if bit_count == 32 && data.iter().skip(3).step_by(4).all(|&a| a == 0) {
+        for (i, px) in data.chunks_exact_mut(4).enumerate() {
+            let (x, y) = (i % width, height - 1 - i / width);
+            let masked = mask.is_some_and(|mask| mask[y * mask_stride + x / 8] >> (7 - x % 8) & 1 == 1);
+            px[3] = if masked { 0 } else { 0xFF };
+        }
+    }
+    Ok(Image {
+        width: width as u32,
+        height: height as u32,
+        color_type: ColorType::Rgba,
+        bit_depth: BitDepth::Eight,
+        palette: None,
+        trns: None,
+        data: Cow::Owned(data),
+        text: Vec::new(),
+        chunks: Vec::new(),
+        premultiplied: false,
+    })
+}
+
+fn u16_at(src: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([src[at], src[at + 1]])
+}
+
+fn u32_at(src: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(src[at..at + 4].try_into().unwrap())
+}
@@ -0,0 +1,64 @@
+//! Ctrl-C and SIGTERM stop a run between files instead of killing it mid-write.
+//!
+//! The first signal only sets the flag handed to the library as its cancel flag: trials stop,
+//! an output being written is finished, and the batch ends with its report. A second signal
+//! kills the process as usual.
+
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc, OnceLock};
+
+static FLAG: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+/// The flag the signals set, for [`compress_png::OptimizeOptions::cancel`].
+pub fn flag() -> Arc<AtomicBool> {
+    FLAG.get_or_init(Arc::default).clone()
+}
+
+/// Whether a signal arrived.
+pub fn requested() -> bool {
+    FLAG.get().is_some_and(|flag| flag.load(Ordering::Relaxed))
+}
+
+/// Installs the handlers; the flag must exist before a signal can arrive.
+#[cfg(unix)]
+pub fn install() {
+    use std::ffi::c_int;
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+    }
+    const SIGINT: c_int = 2;
+    const SIGTERM: c_int = 15;
+    const SIG_DFL: usize = 0;
+    extern "C" fn on_signal(signum: c_int) {
+        if let Some(flag) = FLAG.get() {
+            flag.store(true, Ordering::Relaxed);
+        }
+        // SAFETY: signal is async-signal-safe; the next signal gets the default action.
+        unsafe { signal(signum, SIG_DFL) };
+    }
+    flag();
+    for signum in [SIGINT, SIGTERM] {
+        // SAFETY: the handler only touches an initialized atomic and calls signal.
+        unsafe { signal(signum, on_signal as extern "C" fn(c_int) as usize) };
+    }
+}
+
+#[cfg(windows)]
+pub fn install() {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetConsoleCtrlHandler(handler: Option<unsafe extern "system" fn(u32) -> i32>, add: i32) -> i32;
+    }
+    unsafe extern "system" fn on_ctrl(_event: u32) -> i32 {
+        // A second event is not handled, which lets the default handler end the process.
+        match FLAG.get() {
+            Some(flag) if !flag.swap(true, Ordering::Relaxed) => 1,
+            _ => 0,
+        }
+    }
+    flag();
+    // SAFETY: registers a handler for the lifetime of the process.
+    unsafe { SetConsoleCtrlHandler(Some(on_ctrl), 1) };
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn install() {}
@@ -0,0 +1,217 @@
+/// Quotes `s` as a JSON string.
+pub fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A parsed JSON value; objects keep their keys in order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The member `key` of an object.
+    #[cfg(feature = "rpc")]
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    #[cfg(any(feature = "rpc", feature = "quantize"))]
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// Arrays and objects nested deeper than this are rejected rather than recursed into.
+const MAX_DEPTH: usize = 128;
+
+/// Parses a JSON document, or `None` when it is malformed or nested deeper than `MAX_DEPTH`.
+pub fn parse(s: &str) -> Option<Value> {
+    let mut parser = Parser { s: s.as_bytes(), at: 0, depth: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    (parser.at == s.len()).then_some(value)
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    at: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.s.get(self.at).is_some_and(u8::is_ascii_whitespace) {
+            self.at += 1;
+        }
+    }
+
+    /// Skips whitespace and consumes `c` if it comes next.
+    fn eat(&mut self, c: u8) -> bool {
+        self.skip_whitespace();
+        let found = self.s.get(self.at) == Some(&c);
+        self.at += found as usize;
+        found
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Option<Value> {
+        self.s[self.at..].starts_with(word.as_bytes()).then(|| {
+            self.at += word.len();
+            value
+        })
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        self.skip_whitespace();
+        let c = *self.s.get(self.at)?;
+        if c == b'[' || c == b'{' {
+            if self.depth == MAX_DEPTH {
+                return None;
+            }
+            self.depth += 1;
+            let value = self.nested(c);
+            self.depth -= 1;
+            return value;
+        }
+        match c {
+            b'n' => self.literal("null", Value::Null),
+            b't' => self.literal("true", Value::Bool(true)),
+            b'f' => self.literal("false", Value::Bool(false)),
+            b'"' => self.string().map(Value::String),
+            _ => {
+                let len = self.s[self.at..].iter().take_while(|c| c.is_ascii_digit() || b"+-.eE".contains(c)).count();
+                let number = std::str::from_utf8(&self.s[self.at..self.at + len]).ok()?.parse().ok()?;
+                self.at += len;
+                Some(Value::Number(number))
+            }
+        }
+    }
+
+    /// The array or object opened by `open`.
+    fn nested(&mut self, open: u8) -> Option<Value> {
+        match open {
+            b'[' => {
+                self.at += 1;
+                let mut items = Vec::new();
+                if self.eat(b']') {
+                    return Some(Value::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    if self.eat(b']') {
+                        return Some(Value::Array(items));
+                    }
+                    if !self.eat(b',') {
+                        return None;
+                    }
+                }
+            }
+            b'{' => {
+                self.at += 1;
+                let mut members = Vec::new();
+                if self.eat(b'}') {
+                    return Some(Value::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    if !self.eat(b':') {
+                        return None;
+                    }
+                    members.push((key, self.value()?));
+                    if self.eat(b'}') {
+                        return Some(Value::Object(members));
+                    }
+                    if !self.eat(b',') {
+                        return None;
+                    }
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if self.s.get(self.at) != Some(&b'"') {
+            return None;
+        }
+        self.at += 1;
+        let mut out = Vec::new();
+        loop {
+            let c = *self.s.get(self.at)?;
+            self.at += 1;
+            match c {
+                b'"' => return String::from_utf8(out).ok(),
+                b'\\' => {
+                    let escaped = *self.s.get(self.at)?;
+                    self.at += 1;
+                    let c = match escaped {
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => {
+                            let unit = |p: &mut Self| {
+                                let hex = std::str::from_utf8(p.s.get(p.at..p.at + 4)?).ok()?;
+                                p.at += 4;
+                                u32::from_str_radix(hex, 16).ok()
+                            };
+                            let high = unit(self)?;
+                            // A surrogate pair spells one character outside the BMP.
+                            if (0xD800..0xDC00).contains(&high) && self.s[self.at..].starts_with(b"\\u") {
+                                self.at += 2;
+                                let low = unit(self)?;
+                                char::from_u32(0x10000 + ((high - 0xD800) << 10) + low.checked_sub(0xDC00)?)?
+                            } else {
+                                char::from_u32(high)?
+                            }
+                        }
+                        c => c as char,
+                    };
+                    out.extend(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                c => out.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let deep = "[".repeat(200_000);
+        assert_eq!(parse(&deep), None);
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_some());
+        assert_eq!(parse(&nested(MAX_DEPTH + 1)), None);
+    }
+}
@@ -0,0 +1,1760 @@
+use std::{borrow::Cow, cell::RefCell, cmp::Reverse, collections::{HashMap, HashSet}, fmt, hash::{DefaultHasher, Hash, Hasher}, io::{self, Write}, sync::{atomic::{AtomicBool, Ordering}, Arc}, time::{Duration, Instant}};
+
+use itertools::Itertools;
+use png::{BitDepth, ColorType, Decoder, Encoder, FilterType, Transformations};
+
+use crate::pixel::{IterPixel, Rows};
+
+pub mod analysis;
+mod anneal;
+pub mod apng;
+pub mod channels;
+pub mod check;
+pub mod chunks;
+mod deflate;
+pub mod diff;
+mod filter;
+pub mod histogram;
+pub mod ico;
+pub mod mng;
+pub mod pixel;
+mod pipeline;
+#[cfg(feature = "quantize")]
+pub mod quantize;
+pub mod raw;
+pub mod repair;
+pub mod resize;
+pub mod srgb;
+pub mod trials;
+
+pub use histogram::{color_histogram, Histogram};
+pub use pipeline::Reduction;
+
+const ROW_BATCH: usize = 64;
+/// Images from this many pixels on map colors to palette indices through a direct table.
+const DIRECT_INDEX_PIXELS: usize = 1 << 22;
+
+pub const FILTERS: [FilterType; 5] = [FilterType::NoFilter, FilterType::Sub, FilterType::Up, FilterType::Avg, FilterType::Paeth];
+
+/// Images up to this many pixels get the exhaustive trial matrix under [`Effort::Auto`].
+const SMALL_IMAGE_PIXELS: u64 = 128 * 128;
+/// Images from this many pixels on only get the adaptive filter under [`Effort::Auto`].
+const LARGE_IMAGE_PIXELS: u64 = 4_000_000;
+/// Share of rows repeating the row above from which Up and NoFilter are tried first,
+/// and [`Effort::Normal`] tries nothing else.
+pub(crate) const REPEATED_ROWS_RATIO: f64 = 0.5;
+
+/// How scanlines are filtered before compression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterMode {
+    /// The same filter on every row.
+    Fixed(FilterType),
+    /// Per row, the filter with the smallest sum of absolute differences.
+    Adaptive,
+}
+
+impl FilterMode {
+    pub const ALL: [FilterMode; 6] = [
+        FilterMode::Fixed(FilterType::NoFilter),
+        FilterMode::Fixed(FilterType::Sub),
+        FilterMode::Fixed(FilterType::Up),
+        FilterMode::Fixed(FilterType::Avg),
+        FilterMode::Fixed(FilterType::Paeth),
+        FilterMode::Adaptive,
+    ];
+}
+
+impl fmt::Display for FilterMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FilterMode::Fixed(FilterType::NoFilter) => "none",
+            FilterMode::Fixed(FilterType::Sub) => "sub",
+            FilterMode::Fixed(FilterType::Up) => "up",
+            FilterMode::Fixed(FilterType::Avg) => "avg",
+            FilterMode::Fixed(FilterType::Paeth) => "paeth",
+            FilterMode::Adaptive => "adaptive",
+        })
+    }
+}
+
+impl From<FilterType> for FilterMode {
+    fn from(filter: FilterType) -> Self {
+        FilterMode::Fixed(filter)
+    }
+}
+
+/// Deflate strategy used for the zlib stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Strategy {
+    #[default]
+    Default,
+    /// Favors literals over short matches, for filtered data with small residuals.
+    Filtered,
+    /// Huffman coding only, no matching.
+    Huffman,
+    /// Only matches at distance one.
+    Rle,
+    /// Fixed Huffman codes, which saves the code tables on tiny images.
+    Fixed,
+}
+
+impl Strategy {
+    pub const ALL: [Strategy; 5] = [Strategy::Default, Strategy::Filtered, Strategy::Huffman, Strategy::Rle, Strategy::Fixed];
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Strategy::Default => "default",
+            Strategy::Filtered => "filtered",
+            Strategy::Huffman => "huffman",
+            Strategy::Rle => "rle",
+            Strategy::Fixed => "fixed",
+        })
+    }
+}
+
+/// One point of the trial matrix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrialConfig {
+    pub filter: FilterMode,
+    pub strategy: Strategy,
+    /// Encode at [`Image::packed_depth`], several samples to a byte, instead of 8 bits.
+    pub packed: bool,
+}
+
+impl From<FilterType> for TrialConfig {
+    fn from(filter: FilterType) -> Self {
+        TrialConfig { filter: filter.into(), strategy: Strategy::Default, packed: false }
+    }
+}
+
+impl fmt::Display for TrialConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "filter={} strategy={}", self.filter, self.strategy)?;
+        if self.packed {
+            f.write_str(" packed")?;
+        }
+        Ok(())
+    }
+}
+
+/// How much of the trial matrix to search.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Effort {
+    /// Chosen from the pixel count: small images get [`Effort::Max`], multi-megapixel ones [`Effort::Fast`].
+    #[default]
+    Auto,
+    /// Only the adaptive filter.
+    Fast,
+    /// Every filter with the default strategy.
+    Normal,
+    /// Every filter with every strategy.
+    Max,
+}
+
+impl Effort {
+    /// Replaces [`Effort::Auto`] with the effort used for an image of `pixels` pixels.
+    pub fn resolve(self, pixels: u64) -> Effort {
+        match self {
+            Effort::Auto if pixels <= SMALL_IMAGE_PIXELS => Effort::Max,
+            Effort::Auto if pixels >= LARGE_IMAGE_PIXELS => Effort::Fast,
+            Effort::Auto => Effort::Normal,
+            effort => effort,
+        }
+    }
+
+    /// Trial configurations in the order they are tried at 8 bits, before the heuristics of
+    /// [`trials`]; `Auto` must be resolved first.
+    pub fn configs(self) -> Vec<TrialConfig> {
+        trials::TrialSpec::for_effort(self).configs(false)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Decode(png::DecodingError),
+    Encode(png::EncodingError),
+    Format(String),
+    Cancelled,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Decode(e) => write!(f, "decode error: {}", e),
+            Error::Encode(e) => write!(f, "encode error: {}", e),
+            Error::Format(e) => write!(f, "format error: {}", e),
+            Error::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<png::DecodingError> for Error {
+    fn from(e: png::DecodingError) -> Self {
+        Error::Decode(e)
+    }
+}
+
+impl From<png::EncodingError> for Error {
+    fn from(e: png::EncodingError) -> Self {
+        Error::Encode(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Raw scanline data together with everything needed to encode it.
+#[derive(Debug, Clone)]
+pub struct Image<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub color_type: ColorType,
+    pub bit_depth: BitDepth,
+    pub palette: Option<Vec<u8>>,
+    /// Alpha of the first palette entries (`tRNS`); the others are opaque.
+    pub trns: Option<Vec<u8>>,
+    pub data: Cow<'a, [u8]>,
+    /// Text chunks (keyword, text) written before the image data as `tEXt`, `zTXt` or `iTXt`.
+    pub text: Vec<(String, String)>,
+    /// Other ancillary chunks (type, data) written before the image data, e.g. `tIME`.
+    pub chunks: Vec<([u8; 4], Vec<u8>)>,
+    /// Color samples are multiplied by alpha, so fully transparent pixels must keep zero color.
+    /// PNG has no chunk for this; it is only set by [`Image::premultiply`].
+    pub premultiplied: bool,
+}
+
+/// Which lossless reductions [`Image::reduce_with`] may apply; some decoders mishandle
+/// particular color types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reductions {
+    /// Color images whose pixels are all gray become grayscale.
+    pub gray: bool,
+    /// Fully opaque images lose their alpha channel.
+    pub alpha_strip: bool,
+    /// RGB images with at most 256 colors become indexed.
+    pub palette: bool,
+}
+
+impl Default for Reductions {
+    fn default() -> Self {
+        Reductions { gray: true, alpha_strip: true, palette: true }
+    }
+}
+
+impl Reductions {
+    /// Whether pixels decoded as `from` may be stored as `to`.
+    pub fn allows(&self, from: ColorType, to: ColorType) -> bool {
+        let gray = |c| matches!(c, ColorType::Grayscale | ColorType::GrayscaleAlpha);
+        let alpha = |c| matches!(c, ColorType::GrayscaleAlpha | ColorType::Rgba);
+        match to {
+            ColorType::Indexed => from == ColorType::Indexed || self.palette,
+            _ => (!gray(to) || gray(from) || self.gray) && (alpha(to) || !alpha(from) || self.alpha_strip),
+        }
+    }
+}
+
+impl Image<'_> {
+    /// Applies the lossless color type reductions and palettization.
+    pub fn reduce(&self) -> Image<'_> {
+        self.reduce_with(&Reductions::default())
+    }
+
+    /// Applies the enabled lossless reductions, keeping the representation that encodes
+    /// smallest.
+    pub fn reduce_with(&self, reductions: &Reductions) -> Image<'_> {
+        pipeline::reduce(self, reductions, &[])
+    }
+
+    /// Like [`reduce_with`](Image::reduce_with) for `opts.reductions`, also trying
+    /// `opts.custom_reductions`.
+    pub fn reduce_for(&self, opts: &OptimizeOptions) -> Image<'_> {
+        pipeline::reduce(self, &opts.reductions, &opts.custom_reductions)
+    }
+
+    /// The tRNS chunk to write: for palettes, cut to the palette length and without trailing
+    /// opaque entries, and left out when nothing remains.
+    pub(crate) fn trns_chunk(&self) -> Option<&[u8]> {
+        let trns = self.trns.as_deref()?;
+        if self.color_type != ColorType::Indexed {
+            return Some(trns);
+        }
+        let entries = self.palette.as_ref().map_or(0, |p| p.len() / 3);
+        let trns = &trns[..trns.len().min(entries)];
+        let len = trns.iter().rposition(|&a| a != 0xFF).map_or(0, |i| i + 1);
+        (len > 0).then(|| &trns[..len])
+    }
+
+    /// Drops unused and duplicate palette entries and moves translucent ones first so that tRNS
+    /// stays short, otherwise keeping the palette order of the input. A tRNS shorter than the
+    /// palette leaves the remaining entries opaque, and indices past the end of the palette are
+    /// taken as opaque black, as common decoders show them, and get an entry of their own.
+    pub(crate) fn reduce_indexed(&self) -> Image<'_> {
+        let palette = self.palette.as_deref().unwrap_or_default();
+        let trns = self.trns.as_deref().unwrap_or_default();
+        let entry = |i: usize| {
+            let [r, g, b] = palette.get(i * 3..i * 3 + 3).map_or([0; 3], |rgb| [rgb[0], rgb[1], rgb[2]]);
+            [r, g, b, trns.get(i).copied().unwrap_or(0xFF)]
+        };
+        let mut used = [false; 256];
+        for &i in self.data.iter() {
+            used[i as usize] = true;
+        }
+        let mut entries = (0..256).filter(|&i| used[i]).map(entry).unique().collect::<Vec<_>>();
+        entries.sort_by_key(|rgba| rgba[3] == 0xFF);
+        let index = entries.iter().enumerate().map(|(i, &rgba)| (rgba, i as u8)).collect::<HashMap<_, _>>();
+        let to_new: [u8; 256] = std::array::from_fn(|i| if used[i] { index[&entry(i)] } else { 0 });
+        let trns = entries.iter().map(|rgba| rgba[3]).take_while(|&a| a != 0xFF).collect::<Vec<_>>();
+        Image {
+            palette: Some(entries.iter().flat_map(|rgba| &rgba[..3]).copied().collect()),
+            trns: (!trns.is_empty()).then_some(trns),
+            data: Cow::Owned(self.data.iter().map(|&i| to_new[i as usize]).collect()),
+            text: self.text.clone(),
+            chunks: self.chunks.clone(),
+            ..*self
+        }
+    }
+
+    /// Size of an adaptive filter, default strategy encode, including palette overhead.
+    pub(crate) fn quick_size(&self) -> usize {
+        let config = TrialConfig { filter: FilterMode::Adaptive, strategy: Strategy::Default, packed: false };
+        let palette = self.palette.as_ref().map_or(0, |p| p.len() + 12) + self.trns.as_ref().map_or(0, |t| t.len() + 12);
+        with_scratch(|scratch| compress_scanlines(self, config, Control::NONE, scratch).map_or(usize::MAX, <[u8]>::len)) + palette
+    }
+
+    pub fn encode_to<W: Write>(&self, w: W, config: impl Into<TrialConfig>) -> Result<()> {
+        with_scratch(|scratch| encode(w, self, config.into(), Control::NONE, scratch, &mut no_extra_chunks))
+    }
+
+    pub fn encode(&self, config: impl Into<TrialConfig>) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.encode_to(&mut buf, config)?;
+        Ok(buf)
+    }
+
+    /// Smallest bit depth below 8 that holds every sample of an 8-bit indexed or grayscale
+    /// image: palettes of up to 2, 4 or 16 entries, or gray levels that are all multiples of 255,
+    /// 85 or 17.
+    pub fn packed_depth(&self) -> Option<BitDepth> {
+        if self.bit_depth != BitDepth::Eight || self.trns.is_some() && self.color_type == ColorType::Grayscale {
+            return None;
+        }
+        let fits = |depth: BitDepth| match self.color_type {
+            ColorType::Indexed => self.palette.as_ref().is_some_and(|p| p.len() / 3 <= 1 << depth as u8),
+            ColorType::Grayscale => self.data.iter().all(|&v| v % (255 / ((1u16 << depth as u8) - 1)) as u8 == 0),
+            _ => false,
+        };
+        [BitDepth::One, BitDepth::Two, BitDepth::Four].into_iter().find(|&depth| fits(depth))
+    }
+
+    /// Bit depth of the file [`encode`](Image::encode) writes for `config`.
+    pub fn bit_depth_for(&self, config: TrialConfig) -> BitDepth {
+        config.packed.then(|| self.packed_depth()).flatten().unwrap_or(self.bit_depth)
+    }
+
+    /// The image at [`packed_depth`](Image::packed_depth), rows padded to whole bytes.
+    pub(crate) fn packed(&self) -> Option<Image<'static>> {
+        let depth = self.packed_depth()?;
+        let bits = depth as usize;
+        let scale = if self.color_type == ColorType::Grayscale { (255 / ((1 << bits) - 1)) as u8 } else { 1 };
+        let rows = Rows::from_image(self).ok()?;
+        let mut data = Vec::with_capacity(pixel::stride(self.width, bits) * rows.len());
+        for row in rows.iter() {
+            data.extend(row.chunks(8 / bits).map(|samples| samples.iter().enumerate().fold(0, |byte, (i, &v)| byte | (v / scale) << (8 - bits * (i + 1)))));
+        }
+        Some(Image {
+            bit_depth: depth,
+            palette: self.palette.clone(),
+            trns: self.trns.clone(),
+            data: Cow::Owned(data),
+            text: self.text.clone(),
+            chunks: self.chunks.clone(),
+            ..*self
+        })
+    }
+
+    /// Number of distinct colors at the image's bit depth; `None` for indexed images.
+    pub fn unique_colors(&self) -> Option<usize> {
+        match self.bit_depth {
+            BitDepth::Eight => (self.color_type != ColorType::Indexed).then(|| color_histogram(&self.data, self.color_type).len()),
+            _ => pixel::Pixels::from_image(self).ok().map(|pixels| pixels.pixels().collect::<HashSet<_>>().len()),
+        }
+    }
+
+    /// Share of sample bytes equal to the byte above, which the Up filter turns into zeros
+    /// (in the first row, bytes that are zero already). Noise added between versions of an
+    /// asset shows up as a drop here well before it is visible.
+    pub fn vertical_redundancy(&self) -> f32 {
+        let Ok(rows) = Rows::from_image(self) else {
+            return 0.0;
+        };
+        let zero = vec![0; rows.stride()];
+        let above = std::iter::once(&zero[..]).chain(rows.iter());
+        let same = rows.iter().zip(above).map(|(row, above)| row.iter().zip(above).filter(|(a, b)| a == b).count()).sum::<usize>();
+        same as f32 / self.data.len().max(1) as f32
+    }
+
+    /// Number of scanlines identical to the one above.
+    pub fn repeated_rows(&self) -> usize {
+        Rows::from_image(self).map_or(0, |rows| rows.iter().tuple_windows().filter(|(a, b)| a == b).count())
+    }
+
+    /// Makes the pixels of color `rgb` fully transparent, as legacy assets mark their background
+    /// with a magic color, and returns how many there were. True color images gain an alpha
+    /// channel, which reduction turns into a `tRNS` color key or palette entry where it can;
+    /// indexed images get the matching palette entries transparent in `tRNS`. 16-bit samples
+    /// match `rgb` scaled by 257.
+    pub fn key_out(&mut self, rgb: [u8; 3]) -> usize {
+        if self.color_type == ColorType::Indexed {
+            let Some(palette) = &self.palette else {
+                return 0;
+            };
+            let keyed = palette.chunks_exact(3).map(|entry| entry == rgb).collect::<Vec<_>>();
+            let count = self.data.iter().filter(|&&i| keyed.get(i as usize).copied().unwrap_or(false)).count();
+            if count > 0 {
+                let mut trns = self.trns.take().unwrap_or_default();
+                trns.resize(trns.len().max(keyed.iter().rposition(|&k| k).unwrap() + 1), 0xFF);
+                keyed.iter().zip(trns.iter_mut()).filter(|(&k, _)| k).for_each(|(_, a)| *a = 0);
+                self.trns = Some(trns);
+            }
+            return count;
+        }
+        let bytes = match self.bit_depth {
+            BitDepth::Eight => 1,
+            BitDepth::Sixteen => 2,
+            _ => return 0,
+        };
+        let (colors, alpha) = match self.color_type {
+            ColorType::Grayscale => (1, false),
+            ColorType::GrayscaleAlpha => (1, true),
+            ColorType::Rgb => (3, false),
+            _ => (3, true),
+        };
+        if colors == 1 && !(rgb[0] == rgb[1] && rgb[0] == rgb[2]) {
+            return 0;
+        }
+        let key = rgb[..colors].iter().flat_map(|&v| [v; 2].into_iter().take(bytes)).collect::<Vec<_>>();
+        // A color key already in tRNS, 16-bit values whatever the depth, stays transparent.
+        let old_key = self.trns.as_deref().filter(|_| !alpha).map(|trns| trns.chunks_exact(2).flat_map(|v| v[2 - bytes..].to_vec()).collect::<Vec<_>>());
+        let pixel_len = (colors + alpha as usize) * bytes;
+        let count = self.data.chunks_exact(pixel_len).filter(|px| px[..colors * bytes] == key[..]).count();
+        if count == 0 {
+            return 0;
+        }
+        let mut data = Vec::with_capacity(self.data.len() / pixel_len * (colors + 1) * bytes);
+        for px in self.data.chunks_exact(pixel_len) {
+            data.extend_from_slice(&px[..colors * bytes]);
+            if px[..colors * bytes] == key[..] || old_key.as_deref() == Some(&px[..colors * bytes]) {
+                data.extend_from_slice(&[0; 2][..bytes]);
+            } else if alpha {
+                data.extend_from_slice(&px[colors * bytes..]);
+            } else {
+                data.extend_from_slice(&[0xFF; 2][..bytes]);
+            }
+        }
+        self.color_type = if colors == 1 { ColorType::GrayscaleAlpha } else { ColorType::Rgba };
+        self.trns = None;
+        self.data = Cow::Owned(data);
+        count
+    }
+
+    /// Multiplies color by alpha, rounding to nearest; fully transparent pixels become zero.
+    pub fn premultiply(&mut self) {
+        if !self.premultiplied {
+            self.map_color_alpha(|c, a, max| (c * a + max / 2) / max);
+            self.premultiplied = true;
+        }
+    }
+
+    /// Inverse of [`Image::premultiply`], rounding to nearest; colors brighter than alpha are clamped.
+    pub fn unpremultiply(&mut self) {
+        self.map_color_alpha(|c, a, max| (c * max + a / 2).checked_div(a).map_or(0, |v| v.min(max)));
+        self.premultiplied = false;
+    }
+
+    /// Palette entries that differ in the file but look the same to renderers that premultiply
+    /// alpha, such as every fully transparent entry after the first.
+    pub fn palette_collisions(&self) -> usize {
+        let Some(palette) = &self.palette else {
+            return 0;
+        };
+        let trns = self.trns.as_deref().unwrap_or_default();
+        let premultiplied = palette
+            .chunks_exact(3)
+            .enumerate()
+            .map(|(i, rgb)| {
+                let a = trns.get(i).copied().unwrap_or(0xFF) as u32;
+                [rgb[0], rgb[1], rgb[2]].map(|c| ((c as u32 * a + 127) / 255) as u8).into_iter().chain([a as u8]).collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        premultiplied.len() - premultiplied.iter().unique().count()
+    }
+
+    /// Reorders the palette of an 8-bit indexed image by simulated annealing, seeded with
+    /// `opts.seed`, searching for the order whose indices deflate smallest on a sample of rows.
+    /// Much slower than the ordering [`reduce`](Image::reduce) picks; the new order is kept only
+    /// if the best trial then comes out smaller. Returns the best trial size before and after, or
+    /// `None` when there is no palette to reorder.
+    pub fn anneal_palette(&mut self, opts: &OptimizeOptions) -> Result<Option<(usize, usize)>> {
+        let (Some(order), Some(palette)) = (anneal::palette_order(self, opts.seed), &self.palette) else {
+            return Ok(None);
+        };
+        let mut to_new = [0u8; 256];
+        for (new, &old) in order.iter().enumerate() {
+            to_new[old as usize] = new as u8;
+        }
+        let reordered = Image {
+            palette: Some(order.iter().flat_map(|&old| &palette[old as usize * 3..old as usize * 3 + 3]).copied().collect()),
+            trns: self.trns.as_ref().map(|trns| order[..trns.len().min(order.len())].iter().map(|&old| trns[old as usize]).collect()),
+            data: Cow::Owned(self.data.iter().map(|&i| to_new[i as usize]).collect()),
+            text: Vec::new(),
+            chunks: Vec::new(),
+            ..*self
+        };
+        let before = self.trials(opts)?.best()?.size;
+        let after = reordered.trials(opts)?.best()?.size;
+        if after < before {
+            self.palette = reordered.palette;
+            self.trns = reordered.trns;
+            self.data = Cow::Owned(reordered.data.into_owned());
+        }
+        Ok(Some((before, after.min(before))))
+    }
+
+    /// Gives fully transparent pixels colors diffused from their nearest visible neighbors, so
+    /// that texture filtering, which blends them in before applying alpha, shows no dark halos.
+    /// Premultiplied images are left alone, as their transparent pixels must stay black.
+    ///
+    /// An RGBA image that fits a palette keeps fitting: bled colors are snapped to the nearest
+    /// visible color when the mix would exceed 256 colors, and bleeding is given up when even
+    /// that does not fit.
+    pub fn bleed_alpha(&mut self) {
+        if self.premultiplied {
+            return;
+        }
+        let palettizable = self.color_type == ColorType::Rgba && self.bit_depth == BitDepth::Eight && self.unique_colors().is_some_and(|n| n <= 256);
+        let original = palettizable.then(|| self.data.to_vec());
+        self.diffuse_into_transparent();
+        let Some(original) = original else {
+            return;
+        };
+        if self.unique_colors().is_some_and(|n| n <= 256) {
+            return;
+        }
+        let visible = color_histogram(&original, ColorType::Rgba).iter().map(|(rgba, _)| rgba).filter(|rgba| rgba[3] != 0).collect::<Vec<_>>();
+        let mut nearest = HashMap::new();
+        for px in self.data.to_mut().chunks_exact_mut(4).filter(|px| px[3] == 0) {
+            let rgb = [px[0], px[1], px[2]];
+            let snapped = *nearest.entry(rgb).or_insert_with(|| {
+                let distance = |c: &[u8; 4]| (0..3).map(|k| (c[k] as i32 - rgb[k] as i32).pow(2)).sum::<i32>();
+                visible.iter().min_by_key(|c| distance(c)).map_or([0; 3], |c| [c[0], c[1], c[2]])
+            });
+            px[..3].copy_from_slice(&snapped);
+        }
+        if self.unique_colors().is_some_and(|n| n > 256) {
+            self.data = Cow::Owned(original);
+        }
+    }
+
+    fn diffuse_into_transparent(&mut self) {
+        let channels = match self.color_type {
+            ColorType::GrayscaleAlpha => 2,
+            ColorType::Rgba => 4,
+            _ => return,
+        };
+        let bytes = match self.bit_depth {
+            BitDepth::Eight => 1,
+            BitDepth::Sixteen => 2,
+            _ => return,
+        };
+        let (width, height) = (self.width as usize, self.height as usize);
+        let pixel_len = channels * bytes;
+        let data = self.data.to_mut();
+        let sample = |data: &[u8], i: usize, k: usize| data[i * pixel_len + k * bytes..][..bytes].iter().fold(0u32, |v, &b| v << 8 | b as u32);
+        let mut known = (0..width * height).map(|i| sample(data, i, channels - 1) != 0).collect::<Vec<_>>();
+        let neighbors = |i: usize| {
+            let (x, y) = (i % width, i / width);
+            [(x > 0).then(|| i - 1), (x + 1 < width).then(|| i + 1), (y > 0).then(|| i - width), (y + 1 < height).then(|| i + width)].into_iter().flatten()
+        };
+        // Breadth-first from the visible pixels: each layer averages the layers before it.
+        let mut layer = (0..width * height).filter(|&i| !known[i] && neighbors(i).any(|n| known[n])).collect::<Vec<_>>();
+        let mut queued = vec![false; width * height];
+        layer.iter().for_each(|&i| queued[i] = true);
+        while !layer.is_empty() {
+            let colors = layer
+                .iter()
+                .map(|&i| {
+                    let sources = neighbors(i).filter(|&n| known[n]).collect::<Vec<_>>();
+                    (0..channels - 1).map(|k| (sources.iter().map(|&n| sample(data, n, k)).sum::<u32>() + sources.len() as u32 / 2) / sources.len() as u32).collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            for (&i, color) in layer.iter().zip(colors) {
+                for (k, v) in color.into_iter().enumerate() {
+                    data[i * pixel_len + k * bytes..][..bytes].copy_from_slice(&v.to_be_bytes()[4 - bytes..]);
+                }
+                known[i] = true;
+            }
+            let mut next = Vec::new();
+            for &i in &layer {
+                for n in neighbors(i) {
+                    if !known[n] && !queued[n] {
+                        queued[n] = true;
+                        next.push(n);
+                    }
+                }
+            }
+            layer = next;
+        }
+    }
+
+    /// Rewrites every color sample as `f(color, alpha, max)`; images without alpha are left alone.
+    fn map_color_alpha(&mut self, f: impl Fn(u64, u64, u64) -> u64) {
+        let channels = match self.color_type {
+            ColorType::GrayscaleAlpha => 2,
+            ColorType::Rgba => 4,
+            _ => return,
+        };
+        let bytes = match self.bit_depth {
+            BitDepth::Eight => 1,
+            BitDepth::Sixteen => 2,
+            _ => return,
+        };
+        let max = (1 << (8 * bytes)) - 1;
+        let read = |s: &[u8]| s.iter().fold(0, |v, &b| v << 8 | b as u64);
+        for px in self.data.to_mut().chunks_exact_mut(channels * bytes) {
+            let (color, alpha) = px.split_at_mut((channels - 1) * bytes);
+            let a = read(alpha);
+            for sample in color.chunks_exact_mut(bytes) {
+                let v = f(read(sample), a, max);
+                sample.copy_from_slice(&v.to_be_bytes()[8 - bytes..]);
+            }
+        }
+    }
+
+    /// Hash of the pixel values, independent of how they happen to be stored:
+    /// the same picture saved as RGBA, RGB or grayscale hashes identically.
+    pub fn pixel_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (self.width, self.height).hash(&mut hasher);
+        if self.bit_depth != BitDepth::Eight || self.color_type == ColorType::Indexed {
+            (self.color_type as u8, self.bit_depth as u8).hash(&mut hasher);
+            hasher.write(&self.data);
+            return hasher.finish();
+        }
+        let mut rgba = Vec::with_capacity(self.width as usize * 4);
+        for row in self.data.chunks(self.width as usize * self.color_type.samples()) {
+            rgba.clear();
+            match self.color_type {
+                ColorType::Grayscale => row.iter().for_each(|&g| rgba.extend([g, g, g, 0xFF])),
+                ColorType::GrayscaleAlpha => row.iter_ga().for_each(|(g, a)| rgba.extend([g, g, g, a])),
+                ColorType::Rgb => row.iter_rgb().for_each(|(r, g, b)| rgba.extend([r, g, b, 0xFF])),
+                ColorType::Rgba => rgba.extend_from_slice(row),
+                ColorType::Indexed => unreachable!(),
+            }
+            hasher.write(&rgba);
+        }
+        hasher.finish()
+    }
+
+    /// Whether both images have the same size and pixel values. Like
+    /// [`pixel_hash`](Image::pixel_hash), this sees through 8-bit gray, RGB and RGBA storage;
+    /// other images must be stored alike, palette and tRNS included.
+    pub fn same_pixels(&self, other: &Image) -> bool {
+        if (self.width, self.height) != (other.width, other.height) {
+            return false;
+        }
+        let expandable = |image: &Image| image.bit_depth == BitDepth::Eight && image.color_type != ColorType::Indexed && image.trns.is_none();
+        if self.color_type != other.color_type && expandable(self) && expandable(other) {
+            return self.to_rgba8().data == other.to_rgba8().data;
+        }
+        (self.color_type, self.bit_depth) == (other.color_type, other.bit_depth) && self.palette == other.palette && self.trns == other.trns && self.data == other.data
+    }
+
+    /// 64-bit difference hash (dHash) of the luminance: images that look alike differ in few bits,
+    /// see [`hamming_distance`].
+    pub fn perceptual_hash(&self) -> u64 {
+        const W: usize = 9;
+        const H: usize = 8;
+        let mut sum = [[0u64; W]; H];
+        let mut count = [[0u64; W]; H];
+        if self.bit_depth == BitDepth::Eight && self.color_type != ColorType::Indexed && self.width > 0 {
+            let (width, height) = (self.width as usize, self.height as usize);
+            let row_len = width * self.color_type.samples();
+            for (y, row) in self.data.chunks(row_len).enumerate() {
+                let by = y * H / height;
+                for x in 0..width {
+                    let px = &row[x * self.color_type.samples()..][..self.color_type.samples()];
+                    let (luma, alpha) = match self.color_type {
+                        ColorType::Grayscale => (px[0] as u64 * 1000, 255),
+                        ColorType::GrayscaleAlpha => (px[0] as u64 * 1000, px[1] as u64),
+                        ColorType::Rgb => (px[0] as u64 * 299 + px[1] as u64 * 587 + px[2] as u64 * 114, 255),
+                        ColorType::Rgba => (px[0] as u64 * 299 + px[1] as u64 * 587 + px[2] as u64 * 114, px[3] as u64),
+                        ColorType::Indexed => unreachable!(),
+                    };
+                    let bx = x * W / width;
+                    sum[by][bx] += luma * alpha / 255;
+                    count[by][bx] += 1;
+                }
+            }
+        }
+        let mut hash = 0;
+        for y in 0..H {
+            for x in 0..W - 1 {
+                let left = sum[y][x] * count[y][x + 1];
+                let right = sum[y][x + 1] * count[y][x];
+                hash = hash << 1 | (left > right) as u64;
+            }
+        }
+        hash
+    }
+
+    /// Measures the encoded size for each configuration the effort calls for,
+    /// honoring cancellation, progress and the time limit.
+    pub fn trials(&self, opts: &OptimizeOptions) -> Result<Trials> {
+        self.run_trials(opts, Instant::now())
+    }
+
+    fn run_trials(&self, opts: &OptimizeOptions, started: Instant) -> Result<Trials> {
+        let ctl = Control::new(opts, Stage::Trial);
+        let (effort, configs) = trials::plan(self, opts);
+        if configs.is_empty() {
+            return Err(no_trials());
+        }
+        let packed = configs.iter().any(|c| c.packed).then(|| self.packed()).flatten();
+        let mut results = Vec::with_capacity(configs.len());
+        with_scratch(|scratch| {
+            for (i, &config) in configs.iter().enumerate() {
+                if !results.is_empty() && opts.time_limit.is_some_and(|limit| started.elapsed() >= limit) {
+                    break;
+                }
+                let mut counter = CountingWriter(0, io::sink());
+                let image = if config.packed { packed.as_ref().unwrap_or(self) } else { self };
+                encode(&mut counter, image, config, ctl.slice(i, configs.len()), scratch, &mut no_extra_chunks)?;
+                results.push(Trial { config, size: counter.0 });
+            }
+            Ok::<_, Error>(())
+        })?;
+        ctl.tick(1.0)?;
+        Ok(Trials { effort, skipped: configs.len() - results.len(), results })
+    }
+
+    /// The image as 8-bit RGBA; 16-bit samples keep their high byte.
+    ///
+    /// # Panics
+    ///
+    /// Panics on indexed or sub-byte images; [`decode`] never produces them.
+    pub fn to_rgba8(&self) -> Image<'static> {
+        let data = match self.bit_depth {
+            BitDepth::Eight => rgba_samples(&self.data, self.color_type),
+            BitDepth::Sixteen => rgba_samples(&self.data.iter().step_by(2).copied().collect::<Vec<_>>(), self.color_type),
+            _ => panic!("sub-byte samples must be expanded first"),
+        };
+        Image {
+            width: self.width,
+            height: self.height,
+            color_type: ColorType::Rgba,
+            bit_depth: BitDepth::Eight,
+            palette: None,
+            trns: None,
+            data: Cow::Owned(data),
+            text: self.text.clone(),
+            chunks: self.chunks.clone(),
+            premultiplied: self.premultiplied,
+        }
+    }
+
+    /// Reduces the image and encodes it with the best trial.
+    pub fn optimize(&self, opts: &OptimizeOptions) -> Result<Vec<u8>> {
+        let reduced = self.reduce_for(opts);
+        let best = reduced.trials(opts)?.best()?;
+        reduced.encode(best.config)
+    }
+
+    /// Size of the encoded PNG, without keeping the encoded bytes around.
+    pub fn encoded_len(&self, config: impl Into<TrialConfig>) -> Result<usize> {
+        let mut counter = CountingWriter(0, io::sink());
+        self.encode_to(&mut counter, config)?;
+        Ok(counter.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trial {
+    pub config: TrialConfig,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Trials {
+    /// The effort actually used, never [`Effort::Auto`].
+    pub effort: Effort,
+    pub results: Vec<Trial>,
+    /// Trials not run because the time limit was reached.
+    pub skipped: usize,
+}
+
+impl Trials {
+    /// The smallest result; the earliest trial wins ties. Fails only for results put together
+    /// by hand without any trial, as [`Image::trials`] runs at least one or fails itself.
+    pub fn best(&self) -> Result<Trial> {
+        self.results.iter().min_by_key(|t| t.size).copied().ok_or_else(no_trials)
+    }
+}
+
+fn no_trials() -> Error {
+    Error::Format("the trial spec selects no trials".to_string())
+}
+
+/// Counts the bytes written through it to the inner writer.
+struct CountingWriter<W>(usize, W);
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.1.write(buf)?;
+        self.0 += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.1.flush()
+    }
+}
+
+/// Converts 8-bit samples of any non-indexed color type to RGBA.
+pub(crate) fn rgba_samples(data: &[u8], color: ColorType) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity(data.len() * 4 / color.samples());
+    match color {
+        ColorType::Grayscale => data.iter().for_each(|&g| rgba.extend([g, g, g, 0xFF])),
+        ColorType::GrayscaleAlpha => data.iter_ga().for_each(|(g, a)| rgba.extend([g, g, g, a])),
+        ColorType::Rgb => data.iter_rgb().for_each(|(r, g, b)| rgba.extend([r, g, b, 0xFF])),
+        ColorType::Rgba => rgba.extend_from_slice(data),
+        ColorType::Indexed => unreachable!("palettes are expanded"),
+    }
+    rgba
+}
+
+/// Decodes the first frame of a PNG, expanding palettes and low bit depths to 8-bit samples.
+pub fn decode(src: &[u8]) -> Result<Image<'static>> {
+    decode_with(src, &DecodeOptions::default())
+}
+
+/// How [`decode_with`] reads a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeOptions {
+    /// With this off, indexed images stay indexed: PLTE and tRNS are kept and 1, 2 and 4-bit
+    /// indices are unpacked to a byte each, saving the expansion to RGBA and the palettization
+    /// back. Other images decode the same either way.
+    pub expand: bool,
+    /// Decode despite wrong chunk CRCs and zlib checksums, as many viewers do.
+    pub ignore_checksums: bool,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions { expand: true, ignore_checksums: false }
+    }
+}
+
+/// Like [`decode`], with control over transformations and error tolerance.
+pub fn decode_with(src: &[u8], opts: &DecodeOptions) -> Result<Image<'static>> {
+    if let Some(container) = mng::container(src) {
+        return Err(Error::Format(format!("unsupported container: {}", container)));
+    }
+    let decoder = || {
+        let mut decoder = Decoder::new(src);
+        decoder.ignore_checksums(opts.ignore_checksums);
+        decoder
+    };
+    if !opts.expand {
+        let mut reader = decoder().read_info()?;
+        if reader.info().color_type == ColorType::Indexed {
+            let palette = reader.info().palette.as_ref().map(|p| p.to_vec());
+            // Like libpng and the expanding decoder, ignore a tRNS longer than the palette.
+            let entries = palette.as_ref().map_or(0, |p| p.len() / 3);
+            let trns = reader.info().trns.as_ref().filter(|t| t.len() <= entries).map(|t| t.to_vec());
+            let mut buf = vec![0; reader.output_buffer_size()];
+            let info = reader.next_frame(&mut buf)?;
+            let (width, bits) = (info.width as usize, info.bit_depth as usize);
+            let data = buf
+                .chunks(info.line_size.max(1))
+                .take(info.height as usize)
+                .flat_map(|row| (0..width).map(move |x| (row[x * bits / 8] >> (8 - bits - x * bits % 8)) & ((1u16 << bits) - 1) as u8))
+                .collect::<Vec<_>>();
+            return Ok(Image {
+                width: info.width,
+                height: info.height,
+                color_type: ColorType::Indexed,
+                bit_depth: BitDepth::Eight,
+                palette,
+                trns,
+                data: Cow::Owned(data),
+                text: Vec::new(),
+                chunks: Vec::new(),
+                premultiplied: false,
+            });
+        }
+    }
+    let reader = decoder().read_info()?;
+    let info = reader.info();
+    if matches!(info.color_type, ColorType::Rgb | ColorType::Grayscale) && info.bit_depth as u8 >= 8 && info.trns.is_some() {
+        return decode_keyed(reader);
+    }
+    let mut decoder = decoder();
+    decoder.set_transformations(Transformations::EXPAND);
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    buf.truncate(info.buffer_size());
+    Ok(Image {
+        width: info.width,
+        height: info.height,
+        color_type: info.color_type,
+        bit_depth: info.bit_depth,
+        palette: None,
+        trns: None,
+        data: Cow::Owned(buf),
+        text: Vec::new(),
+        chunks: Vec::new(),
+        premultiplied: false,
+    })
+}
+
+/// Decodes the first frame as stored, without expanding anything: indexed images keep PLTE and
+/// tRNS, and samples keep their bit depth, rows packed and padded to whole bytes.
+pub fn decode_stored(src: &[u8]) -> Result<Image<'static>> {
+    let mut reader = Decoder::new(src).read_info()?;
+    let palette = reader.info().palette.as_ref().map(|p| p.to_vec());
+    let (color_type, bit_depth) = (reader.info().color_type, reader.info().bit_depth);
+    // The decoder narrows the key of non-indexed images below 16 bits to a byte per sample.
+    let trns = reader.info().trns.as_ref().map(|t| match color_type {
+        ColorType::Indexed => t.to_vec(),
+        _ if bit_depth == BitDepth::Sixteen => t.to_vec(),
+        _ => t.iter().flat_map(|&v| [0, v]).collect(),
+    });
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    buf.truncate(info.buffer_size());
+    Ok(Image {
+        width: info.width,
+        height: info.height,
+        color_type,
+        bit_depth,
+        palette,
+        trns,
+        data: Cow::Owned(buf),
+        text: Vec::new(),
+        chunks: Vec::new(),
+        premultiplied: false,
+    })
+}
+
+/// Guards against decoding making a file worse: when `src` is stored in another representation
+/// than `decoded` (a palette or low bit depth that decoding expanded), tries re-encoding it as
+/// stored and returns that image with its best trial if it beats `best`. A stored color type
+/// that `opts.reductions` rules out is not tried.
+pub fn smaller_as_stored(src: &[u8], decoded: &Image, best: Trial, opts: &OptimizeOptions) -> Result<Option<(Image<'static>, Trial)>> {
+    let stored = decode_stored(src)?;
+    if (stored.color_type, stored.bit_depth) == (decoded.color_type, decoded.bit_depth) || !opts.reductions.allows(decoded.color_type, stored.color_type) {
+        return Ok(None);
+    }
+    let trial = stored.trials(opts)?.best()?;
+    Ok((trial.size < best.size).then_some((stored, trial)))
+}
+
+/// Decodes 8 or 16-bit gray or true color with a `tRNS` color key. When no pixel has the key
+/// color, the image is opaque and keeps its color type; otherwise the key becomes an alpha
+/// channel, as [`Transformations::EXPAND`] would make it.
+fn decode_keyed<R: io::Read>(mut reader: png::Reader<R>) -> Result<Image<'static>> {
+    let key = reader.info().trns.as_deref().unwrap_or_default().to_vec();
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    buf.truncate(info.buffer_size());
+    let bytes = info.bit_depth as usize / 8;
+    let colors = info.color_type.samples() * bytes;
+    // The decoder already narrows the key of 8-bit images to a byte per sample.
+    let (color_type, data) = if key.len() == colors && buf.chunks_exact(colors).any(|px| px == key) {
+        let color_type = if info.color_type == ColorType::Rgb { ColorType::Rgba } else { ColorType::GrayscaleAlpha };
+        let alpha = |px: &[u8]| std::iter::repeat_n(if px == key { 0 } else { 0xFF }, bytes);
+        (color_type, buf.chunks_exact(colors).flat_map(|px| px.iter().copied().chain(alpha(px))).collect())
+    } else {
+        (info.color_type, buf)
+    };
+    Ok(Image {
+        width: info.width,
+        height: info.height,
+        color_type,
+        bit_depth: info.bit_depth,
+        palette: None,
+        trns: None,
+        data: Cow::Owned(data),
+        text: Vec::new(),
+        chunks: Vec::new(),
+        premultiplied: false,
+    })
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Decode,
+    Reduce,
+    Trial,
+    Write,
+}
+
+pub type ProgressFn = dyn Fn(Stage, f32) + Send + Sync;
+
+#[derive(Clone, Default)]
+pub struct OptimizeOptions {
+    /// Checked between trials and every few scanlines; once set, optimization stops with [`Error::Cancelled`].
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Called with the current stage and its completion in `0.0..=1.0`.
+    pub on_progress: Option<Arc<ProgressFn>>,
+    /// Once an image has taken this long, remaining trials are skipped and the best one so far wins.
+    pub time_limit: Option<Duration>,
+    /// Size of the trial matrix.
+    pub effort: Effort,
+    pub reductions: Reductions,
+    /// Exactly the trial matrix to search, overriding `effort`.
+    pub trials: Option<trials::TrialSpec>,
+    /// Seed of every randomized search; the same seed and input always give the same output.
+    pub seed: u64,
+    /// Representations tried besides the built-in ones by [`Image::reduce_for`].
+    pub custom_reductions: Vec<Arc<dyn Reduction>>,
+}
+
+impl fmt::Debug for OptimizeOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OptimizeOptions")
+            .field("cancel", &self.cancel)
+            .field("on_progress", &self.on_progress.as_ref().map(|_| ".."))
+            .field("time_limit", &self.time_limit)
+            .field("effort", &self.effort)
+            .field("reductions", &self.reductions)
+            .field("trials", &self.trials)
+            .field("seed", &self.seed)
+            .field("custom_reductions", &self.custom_reductions.len())
+            .finish()
+    }
+}
+
+impl OptimizeOptions {
+    /// Starts from the defaults, which match the command line without flags.
+    pub fn builder() -> OptimizeOptionsBuilder {
+        OptimizeOptionsBuilder(OptimizeOptions::default())
+    }
+}
+
+/// Builds [`OptimizeOptions`] step by step; see [`OptimizeOptions::builder`].
+#[derive(Debug, Clone, Default)]
+pub struct OptimizeOptionsBuilder(OptimizeOptions);
+
+impl OptimizeOptionsBuilder {
+    pub fn cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.0.cancel = Some(cancel);
+        self
+    }
+
+    pub fn on_progress(mut self, on_progress: impl Fn(Stage, f32) + Send + Sync + 'static) -> Self {
+        self.0.on_progress = Some(Arc::new(on_progress));
+        self
+    }
+
+    pub fn time_limit(mut self, time_limit: Option<Duration>) -> Self {
+        self.0.time_limit = time_limit;
+        self
+    }
+
+    pub fn effort(mut self, effort: Effort) -> Self {
+        self.0.effort = effort;
+        self
+    }
+
+    pub fn trials(mut self, trials: Option<trials::TrialSpec>) -> Self {
+        self.0.trials = trials;
+        self
+    }
+
+    pub fn reductions(mut self, reductions: Reductions) -> Self {
+        self.0.reductions = reductions;
+        self
+    }
+
+    /// Whether the color type may change: gray color images to grayscale, opaque images
+    /// without alpha.
+    pub fn color_reduction(mut self, enabled: bool) -> Self {
+        self.0.reductions.gray = enabled;
+        self.0.reductions.alpha_strip = enabled;
+        self
+    }
+
+    /// Adds a representation to the search; may be called repeatedly.
+    pub fn reduction(mut self, reduction: impl Reduction + 'static) -> Self {
+        self.0.custom_reductions.push(Arc::new(reduction));
+        self
+    }
+
+    pub fn gray_reduction(mut self, enabled: bool) -> Self {
+        self.0.reductions.gray = enabled;
+        self
+    }
+
+    pub fn alpha_strip(mut self, enabled: bool) -> Self {
+        self.0.reductions.alpha_strip = enabled;
+        self
+    }
+
+    pub fn palette(mut self, enabled: bool) -> Self {
+        self.0.reductions.palette = enabled;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.0.seed = seed;
+        self
+    }
+
+    pub fn build(self) -> OptimizeOptions {
+        self.0
+    }
+}
+
+/// Cancellation and progress reporting for one stage, or for one slice of it.
+#[derive(Clone, Copy)]
+struct Control<'a> {
+    opts: Option<&'a OptimizeOptions>,
+    stage: Stage,
+    start: f32,
+    span: f32,
+}
+
+impl<'a> Control<'a> {
+    const NONE: Control<'static> = Control { opts: None, stage: Stage::Decode, start: 0.0, span: 1.0 };
+
+    fn new(opts: &'a OptimizeOptions, stage: Stage) -> Self {
+        Control { opts: Some(opts), stage, start: 0.0, span: 1.0 }
+    }
+
+    fn slice(self, index: usize, count: usize) -> Self {
+        let span = self.span / count as f32;
+        Control { start: self.start + span * index as f32, span, ..self }
+    }
+
+    /// Reports `done` (relative to this slice) and fails if cancellation was requested.
+    fn tick(&self, done: f32) -> Result<()> {
+        let Some(opts) = self.opts else {
+            return Ok(());
+        };
+        if opts.cancel.as_ref().is_some_and(|c| c.load(Ordering::Relaxed)) {
+            return Err(Error::Cancelled);
+        }
+        if let Some(on_progress) = &opts.on_progress {
+            on_progress(self.stage, (self.start + self.span * done).min(1.0));
+        }
+        Ok(())
+    }
+}
+
+/// What an optimization did, for reporting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PngStats {
+    pub original_size: usize,
+    pub new_size: usize,
+    pub config: TrialConfig,
+    /// Color type and bit depth as decoded.
+    pub from: (ColorType, BitDepth),
+    /// Color type and bit depth as written.
+    pub to: (ColorType, BitDepth),
+    /// Distinct colors of the decoded image; `None` for indexed images.
+    pub unique_colors: Option<usize>,
+    /// [`Image::vertical_redundancy`] of the decoded image.
+    pub vertical_redundancy: f32,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for PngStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let saved = 100.0 * (1.0 - self.new_size as f64 / self.original_size.max(1) as f64);
+        write!(f, "size={}->{} ({:.1}% saved)", self.original_size, self.new_size, saved)?;
+        write!(f, " color={:?}/{}->{:?}/{}", self.from.0, self.from.1 as u8, self.to.0, self.to.1 as u8)?;
+        if let Some(colors) = self.unique_colors {
+            write!(f, " colors={}", colors)?;
+        }
+        write!(f, " up_zero={:.1}%", 100.0 * self.vertical_redundancy)?;
+        write!(f, " {} time={:?}", self.config, self.elapsed)
+    }
+}
+
+pub fn optimize_png(src: &[u8], opts: &OptimizeOptions) -> Result<(Vec<u8>, PngStats)> {
+    let mut buf = Vec::new();
+    let stats = optimize_png_to(src, opts, &mut buf)?;
+    Ok((buf, stats))
+}
+
+/// Optimizes `src` and streams the smallest encoding into `writer`, or `src` itself when no
+/// encoding is smaller and its color type is one `opts.reductions` allows.
+///
+/// Trials are only measured, so the sole encoded output ever produced is the one written to `writer`.
+pub fn optimize_png_to<W: Write>(src: &[u8], opts: &OptimizeOptions, writer: W) -> Result<PngStats> {
+    let started = Instant::now();
+    Control::new(opts, Stage::Decode).tick(0.0)?;
+    let image = decode(src)?;
+    Control::new(opts, Stage::Decode).tick(1.0)?;
+    Control::new(opts, Stage::Reduce).tick(0.0)?;
+    let reduced = image.reduce_for(opts);
+    Control::new(opts, Stage::Reduce).tick(1.0)?;
+    let best = reduced.run_trials(opts, started)?.best()?;
+    let (reduced, best) = match smaller_as_stored(src, &image, best, opts)? {
+        Some((stored, trial)) => (stored, trial),
+        None => (reduced, best),
+    };
+    let mut writer = CountingWriter(0, writer);
+    let stored = if best.size < src.len() {
+        None
+    } else {
+        let reader = Decoder::new(src).read_info()?;
+        Some((reader.info().color_type, reader.info().bit_depth)).filter(|&(color_type, _)| opts.reductions.allows(image.color_type, color_type))
+    };
+    let to = match stored {
+        Some(stored) => {
+            writer.write_all(src)?;
+            stored
+        }
+        None => {
+            with_scratch(|scratch| encode(&mut writer, &reduced, best.config, Control::new(opts, Stage::Write), scratch, &mut no_extra_chunks))?;
+            (reduced.color_type, reduced.bit_depth_for(best.config))
+        }
+    };
+    Control::new(opts, Stage::Write).tick(1.0)?;
+    Ok(PngStats {
+        original_size: src.len(),
+        new_size: writer.0,
+        config: best.config,
+        from: (image.color_type, image.bit_depth),
+        to,
+        unique_colors: image.unique_colors(),
+        vertical_redundancy: image.vertical_redundancy(),
+        elapsed: started.elapsed(),
+    })
+}
+
+fn trivial_compress<'a>(data: &'a [u8], color: ColorType, reductions: &Reductions) -> (Cow<'a, [u8]>, ColorType) {
+    match color {
+        ColorType::Grayscale => {
+            (Cow::Borrowed(data), ColorType::Grayscale)
+        }
+        ColorType::Rgb if !reductions.gray => (Cow::Borrowed(data), ColorType::Rgb),
+        ColorType::GrayscaleAlpha if !reductions.alpha_strip => (Cow::Borrowed(data), ColorType::GrayscaleAlpha),
+        ColorType::Rgba if !reductions.alpha_strip => (Cow::Borrowed(data), ColorType::Rgba),
+        ColorType::Rgb => {
+            let mut gray = Vec::new();
+            for (r, g, b) in data.iter_rgb() {
+                if r == g && r == b {
+                    gray.push(r);
+                } else {
+                    return (Cow::Borrowed(data), ColorType::Rgb);
+                }
+            }
+            (Cow::Owned(gray), ColorType::Grayscale)
+        }
+        ColorType::Indexed => unreachable!(),
+        ColorType::GrayscaleAlpha => {
+            let mut gray = Vec::new();
+            for (g, a) in data.iter_ga() {
+                if a == 0xFF {
+                    gray.push(g);
+                } else {
+                    return (Cow::Borrowed(data), ColorType::GrayscaleAlpha);
+                }
+            }
+            (Cow::Owned(gray), ColorType::Grayscale)
+        }
+        ColorType::Rgba => {
+            if data.iter().skip(3).step_by(4).any(|&a| a != 0xFF) {
+                return (Cow::Borrowed(data), ColorType::Rgba);
+            }
+            if reductions.gray && data.iter_rgba().all(|(r, g, b, _)| r == g && r == b) {
+                let data = data.iter().step_by(4).copied().collect::<Vec<_>>();
+                return (Cow::Owned(data), ColorType::Grayscale);
+            }
+            let mut rgb = Vec::with_capacity(data.len() * 3 / 4);
+            for (r, g, b, _) in data.iter_rgba() {
+                rgb.push(r);
+                rgb.push(g);
+                rgb.push(b);
+            }
+            (Cow::Owned(rgb), ColorType::Rgb)
+        }
+    }
+}
+
+/// [`trivial_compress`] for 16-bit samples, compared two bytes at a time.
+fn trivial_compress_sixteen<'a>(data: &'a [u8], color: ColorType, reductions: &Reductions) -> (Cow<'a, [u8]>, ColorType) {
+    let channels = color.samples();
+    let has_alpha = matches!(color, ColorType::GrayscaleAlpha | ColorType::Rgba);
+    let pixels = || data.chunks_exact(2 * channels);
+    let opaque = has_alpha && reductions.alpha_strip && pixels().all(|px| px[2 * channels - 2..] == [0xFF, 0xFF]);
+    let colored = matches!(color, ColorType::Rgb | ColorType::Rgba);
+    let gray = colored && reductions.gray && (opaque || !has_alpha) && pixels().all(|px| px[..2] == px[2..4] && px[..2] == px[4..6]);
+    let keep = match (gray, opaque) {
+        (true, _) => 2,
+        (false, true) => 2 * channels - 2,
+        (false, false) => return (Cow::Borrowed(data), color),
+    };
+    let reduced = match (color, gray) {
+        (_, true) => ColorType::Grayscale,
+        (ColorType::GrayscaleAlpha, _) => ColorType::Grayscale,
+        _ => ColorType::Rgb,
+    };
+    (Cow::Owned(pixels().flat_map(|px| &px[..keep]).copied().collect()), reduced)
+}
+
+/// Drops the alpha channel of 8 or 16-bit samples whose alpha is only ever opaque or fully
+/// transparent, and whose transparent pixels all have one color that no opaque pixel has: that
+/// color becomes the `tRNS` key. Returns the samples, their color type and the key, further
+/// reduced to gray when every color, the key included, is gray.
+fn color_key(data: &[u8], color: ColorType, bit_depth: BitDepth, reductions: &Reductions) -> Option<(Vec<u8>, ColorType, Vec<u8>)> {
+    if !matches!(color, ColorType::GrayscaleAlpha | ColorType::Rgba) || !reductions.alpha_strip {
+        return None;
+    }
+    let bytes = bit_depth as usize / 8;
+    let colors = (color.samples() - 1) * bytes;
+    let (opaque, transparent) = (vec![0xFF; bytes], vec![0; bytes]);
+    let mut key = None;
+    for px in data.chunks_exact(colors + bytes) {
+        let (rgb, alpha) = px.split_at(colors);
+        if alpha == transparent {
+            if key.get_or_insert(rgb) != &rgb {
+                return None;
+            }
+        } else if alpha != opaque {
+            return None;
+        }
+    }
+    let key = key?;
+    if data.chunks_exact(colors + bytes).any(|px| px[colors..] == opaque && &px[..colors] == key) {
+        return None;
+    }
+    let gray = color == ColorType::GrayscaleAlpha || reductions.gray && data.chunks_exact(bytes).tuples().all(|(r, g, b, _)| r == g && r == b);
+    let keep = if gray { bytes } else { colors };
+    let stripped = data.chunks_exact(colors + bytes).flat_map(|px| &px[..keep]).copied().collect();
+    // tRNS holds every key sample as 16 bits.
+    let trns = key[..keep].chunks_exact(bytes).flat_map(|s| if bytes == 2 { [s[0], s[1]] } else { [0, s[0]] }).collect();
+    Some((stripped, if gray { ColorType::Grayscale } else { ColorType::Rgb }, trns))
+}
+
+/// Indices, palette and `tRNS` of an image with at most 256 colors.
+struct Palettized {
+    data: Vec<u8>,
+    palette: Vec<u8>,
+    trns: Option<Vec<u8>>,
+}
+
+fn calc_pallet(rows: Rows, color: ColorType) -> Option<Palettized> {
+    let pixels = match color {
+        ColorType::Rgb => rows.iter().flat_map(|row| row.iter_rgb()).map(|(r, g, b)| [r, g, b, 0xFF]).collect::<Vec<_>>(),
+        ColorType::Rgba => rows.iter().flat_map(|row| row.iter_rgba()).map(|(r, g, b, a)| [r, g, b, a]).collect(),
+        ColorType::Grayscale | ColorType::GrayscaleAlpha | ColorType::Indexed => return None,
+    };
+    let mut count = HashMap::new();
+    for &rgba in &pixels {
+        *count.entry(rgba).or_insert(0u32) += 1;
+        if count.len() > 256 {
+            return None;
+        }
+    }
+    let mut count = count.into_iter().collect::<Vec<_>>();
+    count.sort_unstable_by_key(|&(rgba, n)| (Reverse(n), rgba));
+    let mut count = order_by_adjacency(&pixels, rows, count);
+    // tRNS can only leave out entries at the end, so translucent entries go first.
+    count.sort_by_key(|&(rgba, _)| rgba[3] == 0xFF);
+    let pallet_map = ColorIndex::new(count.iter().map(|&(rgba, _)| rgba), pixels.len());
+    let palette = count.iter().flat_map(|(rgba, _)| &rgba[..3]).copied().collect();
+    let trns = count.iter().map(|(rgba, _)| rgba[3]).take_while(|&a| a != 0xFF).collect::<Vec<_>>();
+    Some(Palettized {
+        data: pallet_map.map(&pixels),
+        palette,
+        trns: (!trns.is_empty()).then_some(trns),
+    })
+}
+
+/// Reorders palette entries (given most frequent first) so that colors which often touch get
+/// neighboring indices, starting from the most frequent and always appending the remaining color
+/// that borders the last one most. Small index differences are what Sub and Up filters exploit.
+fn order_by_adjacency(pixels: &[[u8; 4]], rows: Rows, colors: Vec<([u8; 4], u32)>) -> Vec<([u8; 4], u32)> {
+    let n = colors.len();
+    if n <= 2 || rows.width() == 0 {
+        return colors;
+    }
+    let indices = ColorIndex::new(colors.iter().map(|&(rgba, _)| rgba), pixels.len()).map(pixels);
+    let Ok(index_rows) = Rows::new(&indices, rows.width(), rows.len() as u32, ColorType::Indexed, BitDepth::Eight) else {
+        return colors;
+    };
+    let mut touching = vec![0u32; n * n];
+    let mut add = |a: u8, b: u8| {
+        if a != b {
+            touching[a as usize * n + b as usize] += 1;
+            touching[b as usize * n + a as usize] += 1;
+        }
+    };
+    for row in index_rows.iter() {
+        row.iter().tuple_windows().for_each(|(&a, &b)| add(a, b));
+    }
+    for (above, below) in index_rows.iter().tuple_windows() {
+        above.iter().zip(below).for_each(|(&a, &b)| add(a, b));
+    }
+    let mut placed = vec![false; n];
+    let mut order = Vec::with_capacity(n);
+    let mut last = 0;
+    placed[0] = true;
+    order.push(0);
+    while order.len() < n {
+        // Ties, including colors that never touch, keep frequency order.
+        let next = (0..n).filter(|&j| !placed[j]).max_by_key(|&j| (touching[last * n + j], Reverse(j))).unwrap();
+        placed[next] = true;
+        order.push(next);
+        last = next;
+    }
+    order.into_iter().map(|i| colors[i]).collect()
+}
+
+/// Index of each of up to 256 colors, looked up without hashing: in a 16 MiB table addressed by
+/// RGB for large opaque images, where filling it pays off, otherwise by binary search of the
+/// sorted colors.
+struct ColorIndex {
+    direct: Option<Vec<u8>>,
+    sorted: Vec<(u32, u8)>,
+}
+
+impl ColorIndex {
+    fn new(colors: impl Iterator<Item = [u8; 4]>, pixels: usize) -> Self {
+        let mut sorted = colors.enumerate().map(|(i, rgba)| (u32::from_be_bytes(rgba), i as u8)).collect::<Vec<_>>();
+        sorted.sort_unstable();
+        let direct = (pixels >= DIRECT_INDEX_PIXELS && sorted.iter().all(|&(key, _)| key & 0xFF == 0xFF)).then(|| {
+            let mut table = vec![0; 1 << 24];
+            for &(key, i) in &sorted {
+                table[(key >> 8) as usize] = i;
+            }
+            table
+        });
+        ColorIndex { direct, sorted }
+    }
+
+    fn get(&self, rgba: [u8; 4]) -> u8 {
+        let key = u32::from_be_bytes(rgba);
+        match &self.direct {
+            Some(table) => table[(key >> 8) as usize],
+            None => self.sorted[self.sorted.binary_search_by_key(&key, |&(k, _)| k).expect("color is in the palette")].1,
+        }
+    }
+
+    /// Indices of `pixels`, every color of which must be known; runs of one color are looked
+    /// up once.
+    fn map(&self, pixels: &[[u8; 4]]) -> Vec<u8> {
+        let mut last = None;
+        pixels
+            .iter()
+            .map(|&rgba| match last {
+                Some((prev, i)) if prev == rgba => i,
+                _ => {
+                    let i = self.get(rgba);
+                    last = Some((rgba, i));
+                    i
+                }
+            })
+            .collect()
+    }
+}
+
+/// Where [`encode_with`] lets the caller add chunks of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkSlot {
+    /// After PLTE and tRNS, or where they would be in an image without a palette; for chunks
+    /// such as bKGD and hIST that refer to the palette.
+    AfterPlte,
+    /// After every other ancillary chunk, right before the image data.
+    BeforeIdat,
+}
+
+/// Extra chunks (type, data) to write at a [`ChunkSlot`].
+pub type ExtraChunks = Vec<([u8; 4], Vec<u8>)>;
+
+/// Encodes `image` with `config` like [`Image::encode_to`], asking `inject` once for each
+/// [`ChunkSlot`] for chunks to add there, such as private chunks of a custom format. Injected
+/// chunk types must be four ASCII letters and not one of IHDR, PLTE, IDAT or IEND, whose
+/// placement the encoder owns.
+pub fn encode_with<W: Write>(w: W, image: &Image, config: impl Into<TrialConfig>, mut inject: impl FnMut(ChunkSlot) -> ExtraChunks) -> Result<()> {
+    with_scratch(|scratch| encode(w, image, config.into(), Control::NONE, scratch, &mut inject))
+}
+
+fn no_extra_chunks(_: ChunkSlot) -> ExtraChunks {
+    Vec::new()
+}
+
+fn encode<W: Write>(w: W, image: &Image, config: TrialConfig, ctl: Control, scratch: &mut Scratch, inject: &mut dyn FnMut(ChunkSlot) -> ExtraChunks) -> Result<()> {
+    // Trials pack once up front and hand in the packed image.
+    let packed;
+    let image = match config.packed.then(|| image.packed()).flatten() {
+        Some(p) => {
+            packed = p;
+            &packed
+        }
+        None => image,
+    };
+    let mut encoder = Encoder::new(w, image.width, image.height);
+    encoder.set_color(image.color_type);
+    encoder.set_depth(image.bit_depth);
+    let mut writer = encoder.write_header()?;
+    // PLTE is written here rather than by the encoder so that color space chunks can precede it.
+    let (before_plte, after_plte) = image.chunks.iter().partition::<Vec<_>, _>(|(kind, _)| check::BEFORE_PLTE.contains(&kind));
+    for (kind, data) in before_plte {
+        writer.write_chunk(png::chunk::ChunkType(*kind), data)?;
+    }
+    if let Some(pallet) = &image.palette {
+        writer.write_chunk(png::chunk::PLTE, pallet)?;
+    }
+    if let Some(trns) = image.trns_chunk() {
+        writer.write_chunk(png::chunk::tRNS, trns)?;
+    }
+    let mut write_extra = |writer: &mut png::Writer<W>, slot: ChunkSlot| -> Result<()> {
+        for (kind, data) in inject(slot) {
+            if !kind.iter().all(u8::is_ascii_alphabetic) || matches!(&kind, b"IHDR" | b"PLTE" | b"IDAT" | b"IEND") {
+                return Err(Error::Format(format!("cannot inject chunk type {}", String::from_utf8_lossy(&kind))));
+            }
+            writer.write_chunk(png::chunk::ChunkType(kind), &data)?;
+        }
+        Ok(())
+    };
+    write_extra(&mut writer, ChunkSlot::AfterPlte)?;
+    for (keyword, text) in &image.text {
+        let (kind, data) = chunks::text_chunk(keyword, text);
+        writer.write_chunk(png::chunk::ChunkType(kind), &data)?;
+    }
+    for (kind, data) in after_plte {
+        writer.write_chunk(png::chunk::ChunkType(*kind), data)?;
+    }
+    write_extra(&mut writer, ChunkSlot::BeforeIdat)?;
+    let idat = compress_scanlines(image, config, ctl, scratch)?;
+    writer.write_chunk(png::chunk::IDAT, idat)?;
+    Ok(writer.finish()?)
+}
+
+/// Compressed image data of the best trial, for writers that assemble the chunks themselves.
+pub(crate) fn compress_best(image: &Image, opts: &OptimizeOptions) -> Result<Vec<u8>> {
+    let effort = opts.effort.resolve(image.width as u64 * image.height as u64);
+    let configs = opts.trials.as_ref().map_or_else(|| effort.configs(), |spec| spec.configs(false));
+    let ctl = Control::new(opts, Stage::Trial);
+    with_scratch(|scratch| {
+        let mut best: Option<Vec<u8>> = None;
+        for (i, &config) in configs.iter().enumerate() {
+            let data = compress_scanlines(image, config, ctl.slice(i, configs.len()), scratch)?;
+            if best.as_ref().is_none_or(|b| data.len() < b.len()) {
+                best = Some(data.to_vec());
+            }
+        }
+        best.ok_or_else(no_trials)
+    })
+}
+
+/// Buffers reused between encodes: row buffers, the filtered stream, a deflate compressor per
+/// strategy and the compressed output. Each thread keeps one, see [`with_scratch`], so that batch runs over
+/// thousands of small images do not allocate them for every trial.
+#[derive(Default)]
+pub(crate) struct Scratch {
+    prev: Vec<u8>,
+    filtered: Vec<u8>,
+    adaptive: Vec<u8>,
+    /// Filter type bytes and filtered rows of the whole image, before compression.
+    stream: Vec<u8>,
+    compressors: deflate::Compressors,
+    idat: Vec<u8>,
+}
+
+thread_local! {
+    static SCRATCH: RefCell<Scratch> = RefCell::default();
+}
+
+/// Runs `f` with the scratch buffers of this thread, or fresh ones when they are in use further
+/// up the stack.
+pub(crate) fn with_scratch<T>(f: impl FnOnce(&mut Scratch) -> T) -> T {
+    SCRATCH.with(|scratch| match scratch.try_borrow_mut() {
+        Ok(mut scratch) => f(&mut scratch),
+        Err(_) => f(&mut Scratch::default()),
+    })
+}
+
+fn compress_scanlines<'s>(image: &Image, config: TrialConfig, ctl: Control, scratch: &'s mut Scratch) -> Result<&'s [u8]> {
+    let rows = Rows::from_image(image)?;
+    let bpp = rows.filter_distance();
+    let Scratch { prev, filtered, adaptive, stream, compressors, idat } = scratch;
+    for buf in [&mut *prev, &mut *filtered, &mut *adaptive] {
+        buf.clear();
+        buf.resize(rows.stride(), 0);
+    }
+    stream.clear();
+    for row in rows.iter() {
+        let filter_type = match config.filter {
+            FilterMode::Fixed(filter_type) => {
+                filter::filter_row(filter_type, bpp, prev, row, filtered);
+                filter_type
+            }
+            FilterMode::Adaptive => filter::filter_row_adaptive(bpp, prev, row, filtered, adaptive),
+        };
+        stream.push(filter_type as u8);
+        stream.extend_from_slice(filtered);
+        prev.copy_from_slice(row);
+    }
+    let key = deflate::StreamKey::new(stream, config.strategy);
+    if deflate::lookup(&key, idat) {
+        return Ok(idat);
+    }
+    let mut zlib = deflate::ZlibWriter::new(compressors, config.strategy, idat);
+    for (i, batch) in stream.chunks((rows.stride() + 1) * ROW_BATCH).enumerate() {
+        ctl.tick((i * ROW_BATCH) as f32 / image.height as f32)?;
+        zlib.write(batch);
+    }
+    let idat = zlib.finish();
+    deflate::store(key, idat);
+    Ok(idat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn indexed(palette: &[u8], trns: Option<&[u8]>, data: &[u8]) -> Image<'static> {
+        Image {
+            width: data.len() as u32,
+            height: 1,
+            color_type: ColorType::Indexed,
+            bit_depth: BitDepth::Eight,
+            palette: Some(palette.to_vec()),
+            trns: trns.map(<[u8]>::to_vec),
+            data: Cow::Owned(data.to_vec()),
+            text: Vec::new(),
+            chunks: Vec::new(),
+            premultiplied: false,
+        }
+    }
+
+    /// An indexed PNG with `trns` written as given, however long or short.
+    fn indexed_png(palette: &[u8], trns: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut png = Vec::new();
+        let mut encoder = Encoder::new(&mut png, data.len() as u32, 1);
+        encoder.set_color(ColorType::Indexed);
+        encoder.set_depth(BitDepth::Eight);
+        encoder.set_palette(palette);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_chunk(png::chunk::tRNS, trns).unwrap();
+        writer.write_image_data(data).unwrap();
+        writer.finish().unwrap();
+        png
+    }
+
+    const RGB: [u8; 9] = [0xFF, 0, 0, 0, 0xFF, 0, 0, 0, 0xFF];
+
+    #[test]
+    fn trns_chunk_is_cut_to_the_palette_and_its_last_translucent_entry() {
+        assert_eq!(indexed(&RGB, Some(&[0x80, 0xFF, 0xFF, 0]), &[0]).trns_chunk(), Some(&[0x80][..]));
+        assert_eq!(indexed(&RGB, Some(&[0xFF, 0x40]), &[0]).trns_chunk(), Some(&[0xFF, 0x40][..]));
+        assert_eq!(indexed(&RGB, Some(&[0xFF, 0xFF]), &[0]).trns_chunk(), None);
+        assert_eq!(indexed(&RGB, Some(&[]), &[0]).trns_chunk(), None);
+        assert_eq!(indexed(&RGB, None, &[0]).trns_chunk(), None);
+    }
+
+    #[test]
+    fn reduce_indexed_fills_short_trns_and_missing_entries() {
+        // Entry 1 is past the short tRNS and opaque; index 7 is past the palette and opaque black.
+        let image = indexed(&RGB, Some(&[0]), &[0, 1, 7, 1]);
+        let reduced = image.reduce_indexed();
+        assert_eq!(reduced.palette.as_deref(), Some(&[0xFF, 0, 0, 0, 0xFF, 0, 0, 0, 0][..]));
+        assert_eq!(reduced.trns.as_deref(), Some(&[0][..]));
+        assert_eq!(&reduced.data[..], &[0, 1, 2, 1]);
+    }
+
+    #[test]
+    fn decode_ignores_a_trns_longer_than_the_palette() {
+        let png = indexed_png(&RGB[..6], &[0, 0, 0], &[0, 1]);
+        let image = decode_with(&png, &DecodeOptions { expand: false, ignore_checksums: false }).unwrap();
+        assert_eq!(image.trns, None);
+    }
+
+    #[test]
+    fn same_pixels_compares_values_not_hashes() {
+        let rgb = |data: &[u8]| Image { width: 1, color_type: ColorType::Rgb, palette: None, ..indexed(&[], None, data) };
+        let gray = Image { color_type: ColorType::Grayscale, palette: None, ..indexed(&[], None, &[7]) };
+        assert!(rgb(&[7, 7, 7]).same_pixels(&gray));
+        assert!(!rgb(&[7, 7, 8]).same_pixels(&gray));
+        // Same indices, other colors.
+        let (red, blue) = (indexed(&RGB, None, &[0, 0]), indexed(&[0, 0, 0xFF], None, &[0, 0]));
+        assert_eq!(red.pixel_hash(), blue.pixel_hash());
+        assert!(!red.same_pixels(&blue));
+        assert!(red.same_pixels(&red.clone()));
+    }
+
+    #[test]
+    fn stats_report_the_written_size() {
+        let png = indexed_png(&RGB, &[0x80], &[0, 1, 2, 2]);
+        let (out, stats) = optimize_png(&png, &OptimizeOptions::default()).unwrap();
+        assert_eq!(stats.new_size, out.len());
+    }
+
+    #[test]
+    fn optimizing_normalizes_short_and_empty_trns() {
+        for trns in [&[0x80][..], &[]] {
+            let png = indexed_png(&RGB, trns, &[0, 1, 2, 2]);
+            let (out, _) = optimize_png(&png, &OptimizeOptions::default()).unwrap();
+            let expected = [[0xFF, 0, 0, trns.first().copied().unwrap_or(0xFF)], [0, 0xFF, 0, 0xFF], [0, 0, 0xFF, 0xFF], [0, 0, 0xFF, 0xFF]];
+            assert_eq!(decode(&out).unwrap().to_rgba8().data.chunks(4).collect::<Vec<_>>(), expected.iter().map(|p| &p[..]).collect::<Vec<_>>());
+            let stored = decode_stored(&out).unwrap();
+            assert!(stored.trns.as_ref().is_none_or(|t| !t.is_empty() && t.len() <= stored.palette.as_ref().map_or(0, |p| p.len() / 3)));
+        }
+    }
+
+    #[test]
+    fn stored_fallback_respects_disabled_reductions() {
+        let mut png = Vec::new();
+        let mut encoder = Encoder::new(&mut png, 64, 64);
+        encoder.set_color(ColorType::Indexed);
+        encoder.set_depth(BitDepth::One);
+        encoder.set_palette(&RGB[..6]);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&(0..64 * 8).map(|i| (i * 37 % 251) as u8).collect::<Vec<_>>()).unwrap();
+        writer.finish().unwrap();
+        let opts = OptimizeOptions::builder().palette(false).build();
+        let (out, stats) = optimize_png(&png, &opts).unwrap();
+        assert_ne!(decode_stored(&out).unwrap().color_type, ColorType::Indexed);
+        assert_ne!(stats.to.0, ColorType::Indexed);
+    }
+
+    #[test]
+    fn optimizing_never_grows_the_input() {
+        let png = indexed_png(&RGB, &[0x80], &[0, 1, 2, 2]);
+        let (out, _) = optimize_png(&png, &OptimizeOptions::default()).unwrap();
+        let (again, stats) = optimize_png(&out, &OptimizeOptions::default()).unwrap();
+        assert!(again.len() < out.len() || again == out);
+        assert_eq!(stats.new_size, again.len());
+    }
+}
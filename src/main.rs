@@ -1,155 +1,825 @@
-use std::{borrow::Cow, collections::HashMap, ffi::OsString, fs};
+use std::{cmp::Reverse, ffi::{OsStr, OsString}, fmt::Write as _, fs, io, path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
 
-use clap::Parser;
-use itertools::Itertools;
-use png::{BitDepth, ColorType, Compression, Decoder, Encoder, FilterType, Transformations};
+use clap::{Parser, Subcommand, ValueEnum};
+#[cfg(feature = "quantize")]
+use compress_png::quantize::Region;
+use compress_png::{channels::Swizzle, chunks, raw::RawFormat, trials::TrialSpec, Effort, TrialConfig};
+#[cfg(any(feature = "rpc", feature = "serve"))]
+use compress_png::OptimizeOptions;
+use png::{BitDepth, ColorType};
 
-trait IterPixel {
-    fn iter_ga(&self) -> impl Iterator<Item=(u8, u8)>;
+#[cfg(feature = "archive")]
+mod archive;
+mod batch;
+mod cmd;
+#[cfg(feature = "compare-external")]
+mod external;
+#[cfg(feature = "fetch")]
+mod fetch;
+mod git;
+mod github;
+mod interrupt;
+mod json;
+#[cfg(feature = "object-store")]
+mod objstore;
+mod palette;
+mod paths;
+mod resume;
+#[cfg(feature = "rpc")]
+mod rpc;
+mod selftest;
+#[cfg(feature = "serve")]
+mod serve;
+mod throttle;
+mod walk;
 
-    fn iter_rgb(&self) -> impl Iterator<Item=(u8, u8, u8)>;
+#[derive(Subcommand)]
+enum Command {
+    /// Validate CRCs, chunk order and image data without re-encoding
+    Check {
+        #[arg(required = true)]
+        src: Vec<OsString>,
+        /// Descend into directories given as inputs
+        #[arg(short, long)]
+        recursive: bool,
+        /// Leave image data that would decompress to more than this unverified instead of inflating it
+        #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "256MiB")]
+        max_image_data: u64,
+    },
+    /// Report colors, alpha usage and likely reductions without optimizing
+    Analyze {
+        #[arg(required = true)]
+        src: Vec<OsString>,
+        /// Descend into directories given as inputs
+        #[arg(short, long)]
+        recursive: bool,
+        /// Only look at one row in this many (e.g. 1/16) for a quick estimate on huge images
+        #[arg(long, value_name = "1/N", value_parser = parse_sample, default_value = "1/1")]
+        sample: u32,
+    },
+    /// Write each frame of an animated PNG as an optimized PNG, with timing in a JSON sidecar,
+    /// or each PNG image embedded in an MNG
+    Extract {
+        src: PathBuf,
+        /// Directory for the frames and <name>.frames.json
+        #[arg(short, long, default_value = ".")]
+        out_dir: PathBuf,
+    },
+    /// Build an animated PNG from same-sized frames, storing only what changes between them
+    Animate {
+        #[arg(required = true)]
+        frames: Vec<PathBuf>,
+        /// How long each frame is shown (e.g. 40ms, 1s)
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "100ms")]
+        delay: Duration,
+        /// Number of times to play, 0 for forever
+        #[arg(long, default_value_t = 0)]
+        plays: u32,
+        #[arg(short, long, default_value = "out.png")]
+        output: PathBuf,
+    },
+    /// Write the pixels the optimizer works on, after expanding palettes and low bit depths
+    Decode {
+        src: PathBuf,
+        #[arg(long, value_enum, default_value = "pam")]
+        format: RawFormatArg,
+        /// Defaults to the input name with the format's extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Answer JSON-RPC requests to optimize files, framed by Content-Length headers, on stdio
+    #[cfg(feature = "rpc")]
+    Rpc {
+        /// Listen on this unix socket instead
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+        /// Trials to run unless a request names its own effort
+        #[arg(long, value_enum, default_value = "auto")]
+        effort: EffortArg,
+        /// Skip remaining trials once an image has taken this long (e.g. 500ms, 5s, 1m)
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        max_time_per_image: Option<Duration>,
+    },
+    /// Answer POST /optimize over HTTP with the optimized PNG, statistics in X- headers
+    #[cfg(feature = "serve")]
+    Serve {
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:8080")]
+        listen: std::net::SocketAddr,
+        /// Trials to run: auto picks max for icons and fast for multi-megapixel photos
+        #[arg(long, value_enum, default_value = "auto")]
+        effort: EffortArg,
+        /// Skip remaining trials once an image has taken this long (e.g. 500ms, 5s, 1m)
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        max_time_per_image: Option<Duration>,
+        /// Refuse larger request bodies
+        #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "64MiB")]
+        max_body: u64,
+        /// Optimize at most this many requests at once; as many more wait and the rest are
+        /// answered 503 [default: the number of CPUs]
+        #[arg(long, value_name = "N")]
+        jobs: Option<std::num::NonZeroUsize>,
+    },
+}
 
-    fn iter_rgba(&self) -> impl Iterator<Item=(u8, u8, u8, u8)>;
+#[derive(Clone, Copy, ValueEnum)]
+enum RawFormatArg {
+    /// PGM or PPM; no alpha
+    Pnm,
+    Pam,
+    Farbfeld,
 }
 
-impl IterPixel for [u8] {
-    fn iter_ga(&self) -> impl Iterator<Item=(u8, u8)> {
-        self.iter().copied().tuples()
+impl From<RawFormatArg> for RawFormat {
+    fn from(format: RawFormatArg) -> Self {
+        match format {
+            RawFormatArg::Pnm => RawFormat::Pnm,
+            RawFormatArg::Pam => RawFormat::Pam,
+            RawFormatArg::Farbfeld => RawFormat::Farbfeld,
+        }
     }
+}
 
-    fn iter_rgb(&self) -> impl Iterator<Item=(u8, u8, u8)> {
-        self.iter().copied().tuples()
-    }
+#[derive(Clone, Copy, ValueEnum)]
+enum LinkKind {
+    Hard,
+    Sym,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Channel {
+    R,
+    G,
+    B,
+    A,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SetTime {
+    /// Drop the modification time
+    Strip,
+    /// Copy the input's tIME chunk
+    Keep,
+    /// Stamp the current time
+    Now,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Report {
+    Text,
+    /// Also emit GitHub Actions warning annotations for files over the size or savings thresholds
+    Github,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Order {
+    /// Largest files first, so the biggest wins show up early and no long job is left for last
+    SizeDesc,
+    SizeAsc,
+    Path,
+}
 
-    fn iter_rgba(&self) -> impl Iterator<Item=(u8, u8, u8, u8)> {
-        self.iter().copied().tuples()
+#[derive(Clone, Copy, ValueEnum)]
+enum EffortArg {
+    Auto,
+    Fast,
+    Normal,
+    Max,
+}
+
+impl From<EffortArg> for Effort {
+    fn from(effort: EffortArg) -> Self {
+        match effort {
+            EffortArg::Auto => Effort::Auto,
+            EffortArg::Fast => Effort::Fast,
+            EffortArg::Normal => Effort::Normal,
+            EffortArg::Max => Effort::Max,
+        }
     }
 }
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Opts {
-    src: OsString,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Images, directories with --recursive, zip and tar archives of them, http(s) URLs, or
+    /// s3:// and gs:// prefixes, whose shrunk objects are uploaded back without --out-dir
+    #[arg(required_unless_present_any = ["staged", "self_test"])]
+    src: Vec<OsString>,
+    /// Before anything else, optimize a few built-in images and verify the results, e.g. to
+    /// check a static build on a new machine
+    #[arg(long)]
+    self_test: bool,
+    /// Optimize the PNG files staged in git in place and stage the result, for pre-commit hooks
+    #[arg(long, conflicts_with_all = ["src", "out_dir", "output"])]
+    staged: bool,
+    /// Output file when optimizing a single image
+    #[arg(short, long, default_value = "out.png")]
+    output: PathBuf,
+    /// Mirror optimized files into this directory; required for several inputs
+    #[arg(long, value_name = "DIR")]
+    out_dir: Option<PathBuf>,
+    /// Write optimized files into this .zip, .tar or .tar.gz at their relative paths instead of a directory
+    #[cfg(feature = "archive")]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["out_dir", "output", "staged", "link_duplicates", "resume", "check", "dry_run"])]
+    out_archive: Option<PathBuf>,
+    /// Descend into directories given as inputs
+    #[arg(short, long)]
+    recursive: bool,
+    /// Follow symbolic links found while walking directories
+    #[arg(long)]
+    follow_symlinks: bool,
+    /// Fix bad CRCs, missing IEND and chunk order problems before optimizing
+    #[arg(long)]
+    repair: bool,
+    /// Accept out-of-spec files where possible: repair them and ignore bad zlib checksums
+    #[arg(long, conflicts_with = "strict")]
+    permissive: bool,
+    /// Reject files with any structural violation that the check subcommand reports
+    #[arg(long)]
+    strict: bool,
+    /// Report inputs whose pixels are identical to an earlier input; keeps the pixels of every
+    /// distinct input in memory
+    #[arg(long)]
+    find_duplicates: bool,
+    /// Instead of optimizing pixel-identical duplicates again, link them to the first output;
+    /// keeps the pixels of every distinct input in memory
+    #[arg(long, value_enum, value_name = "KIND")]
+    link_duplicates: Option<LinkKind>,
+    /// List images whose perceptual hashes differ in at most this many bits
+    #[arg(long, value_name = "BITS", num_args = 0..=1, default_missing_value = "6")]
+    find_similar: Option<u32>,
+    /// Skip remaining trials once an image has taken this long (e.g. 500ms, 5s, 1m)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    max_time_per_image: Option<Duration>,
+    /// Trials to run: auto picks max for icons and fast for multi-megapixel photos
+    #[arg(long, value_enum, default_value = "auto")]
+    effort: EffortArg,
+    /// Exactly the trials to run instead of --effort, e.g. "filters=all;strategies=filtered,default;depths=min,8"
+    #[arg(long, value_name = "SPEC", value_parser = parse_trials)]
+    trials: Option<TrialSpec>,
+    /// Keep indexed inputs indexed instead of expanding them to RGBA and palettizing them again
+    #[arg(long, conflicts_with_all = ["convert_to_srgb", "bleed_alpha", "premultiply", "unpremultiply", "sizes", "thumbnail"])]
+    no_expand: bool,
+    /// Keep color images color even when every pixel is gray
+    #[arg(long)]
+    no_gray_reduction: bool,
+    /// Keep the alpha channel even when every pixel is opaque
+    #[arg(long)]
+    no_alpha_strip: bool,
+    /// Never convert to an indexed (palette) image
+    #[arg(long)]
+    no_palette: bool,
+    /// Merge colors closer than this CIELAB distance (about 2.3 is just noticeable); lossy
+    #[cfg(feature = "quantize")]
+    #[arg(long, value_name = "DELTA_E", conflicts_with = "no_expand")]
+    merge_close_colors: Option<f32>,
+    /// Keep the pixels of this rectangle exact in lossy modes; may be repeated
+    #[cfg(feature = "quantize")]
+    #[arg(long, value_name = "X,Y,W,H", value_parser = parse_region)]
+    lossless_region: Vec<Region>,
+    /// Undo lossy steps whose result has a lower SSIM than this against the input (0 to 1)
+    #[cfg(feature = "quantize")]
+    #[arg(long, value_name = "SSIM")]
+    min_ssim: Option<f64>,
+    /// Merge close colors only when quality stays above --min-ssim (default 0.98) and the output shrinks by --auto-lossy-saving
+    #[cfg(feature = "quantize")]
+    #[arg(long, conflicts_with = "no_expand")]
+    auto_lossy: bool,
+    /// Smallest size saving, relative to the lossless output, that --auto-lossy accepts
+    #[cfg(feature = "quantize")]
+    #[arg(long, value_name = "PERCENT", default_value_t = 10.0, requires = "auto_lossy")]
+    auto_lossy_saving: f64,
+    /// Index every image against one palette of at most 256 colors computed over all inputs, for sprite sets shown together; lossy beyond 256 colors
+    #[cfg(feature = "quantize")]
+    #[arg(long, conflicts_with_all = ["no_expand", "no_palette", "merge_close_colors", "auto_lossy", "convert_to_srgb", "bleed_alpha", "premultiply", "unpremultiply", "palette_anneal", "sizes", "mark"])]
+    shared_palette: bool,
+    /// Index every image against the colors of this .act, .gpl or .json palette file, so only approved colors are used; lossy
+    #[cfg(feature = "quantize")]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["shared_palette", "no_expand", "no_palette", "merge_close_colors", "auto_lossy", "convert_to_srgb", "bleed_alpha", "premultiply", "unpremultiply", "palette_anneal", "sizes", "mark"])]
+    palette_in: Option<PathBuf>,
+    /// Write the palette of the indexed output, or the one every image was indexed against, to this .act, .gpl or .json file
+    #[arg(long, value_name = "PATH", conflicts_with = "sizes")]
+    palette_out: Option<PathBuf>,
+    /// Make pixels of this #RRGGBB color fully transparent, for legacy assets with a magic background color
+    #[arg(long, value_name = "#RRGGBB", value_parser = parse_rgb)]
+    colorkey: Option<[u8; 3]>,
+    /// Keep only this channel, as a gray image, for masks packed into the channels of a texture
+    #[arg(long, value_enum, conflicts_with_all = ["swizzle", "no_expand"])]
+    extract_channel: Option<Channel>,
+    /// Rearrange channels, written like rgba->bgra; 0 and 1 make constant channels, and fewer than four outputs drop channels
+    #[arg(long, value_name = "IN->OUT", value_parser = parse_swizzle, conflicts_with = "no_expand")]
+    swizzle: Option<Swizzle>,
+    /// Write the color as RGB and the alpha channel as a gray mask in <output>.alpha.png, each optimized on its own
+    #[arg(long, conflicts_with_all = ["extract_channel", "no_expand"])]
+    split_alpha: bool,
+    /// Convert pixels from the embedded ICC profile or gAMA to sRGB, and tag the output sRGB
+    #[arg(long, conflicts_with = "no_expand")]
+    convert_to_srgb: bool,
+    /// Fill the color of fully transparent pixels from their visible neighbors, against halos in filtered textures
+    #[arg(long, alias = "alpha-bleed", conflicts_with = "premultiply")]
+    bleed_alpha: bool,
+    /// Multiply color by alpha before optimizing
+    #[arg(long, conflicts_with = "unpremultiply")]
+    premultiply: bool,
+    /// Divide premultiplied color by alpha before optimizing
+    #[arg(long)]
+    unpremultiply: bool,
+    /// Write an amplified per-pixel difference between the input and the output, to audit what lossy modes changed
+    #[arg(long, value_name = "PATH", conflicts_with = "no_expand")]
+    diff_image: Option<PathBuf>,
+    /// Also write a copy scaled to fit this many pixels as <output>.thumb.png
+    #[arg(long, value_name = "PIXELS", value_parser = clap::value_parser!(u32).range(1..))]
+    thumbnail: Option<u32>,
+    /// Instead of the full size image, write one scaled to fit each size; an .ico output holds them all
+    #[arg(long, value_name = "PIXELS", value_delimiter = ',', value_parser = clap::value_parser!(u32).range(1..))]
+    sizes: Vec<u32>,
+    /// Carry the input's tEXt, zTXt and iTXt chunks over to the output
+    #[arg(long)]
+    keep_text: bool,
+    /// Add a text chunk, replacing any with the same keyword; may be repeated
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_text)]
+    set_text: Vec<(String, String)>,
+    /// Drop kept text chunks with this keyword; may be repeated
+    #[arg(long, value_name = "KEY", requires = "keep_text")]
+    remove_text: Vec<String>,
+    /// Physical resolution in dots per inch, written as a pHYs chunk
+    #[arg(long, value_name = "DPI", value_parser = clap::value_parser!(u32).range(1..))]
+    dpi: Option<u32>,
+    /// Position in pixels for compositing, written as an oFFs chunk
+    #[arg(long, value_name = "X,Y", value_parser = parse_offset, allow_hyphen_values = true)]
+    offset: Option<(i32, i32)>,
+    /// What to do with the tIME (last modification) chunk
+    #[arg(long, value_enum, value_name = "MODE", default_value = "strip")]
+    set_time: SetTime,
+    /// Reject images whose width or height is not a power of two, as some texture formats need
+    #[arg(long)]
+    require_pot: bool,
+    /// Reject images of any other size (e.g. 512x512)
+    #[arg(long, value_name = "WxH", value_parser = parse_dimensions)]
+    require_dimensions: Option<(u32, u32)>,
+    /// Only warn about --require-pot and --require-dimensions violations instead of failing the file
+    #[arg(long)]
+    warn_dimensions: bool,
+    /// Record version and options in a tEXt chunk, and skip inputs that already carry the same record
+    #[arg(long)]
+    mark: bool,
+    /// Also run oxipng, optipng and pngcrush when installed and report their sizes
+    #[cfg(feature = "compare-external")]
+    #[arg(long)]
+    compare_external: bool,
+    /// Fail listing every file whose optimized size is above this (e.g. 500KB, 2MiB)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
+    /// Only check --max-size without writing any output
+    #[arg(long, requires = "max_size")]
+    check: bool,
+    /// Write nothing; show which chunks each output would keep, strip and add, and the size changes
+    #[arg(long, conflicts_with = "check")]
+    dry_run: bool,
+    /// Output format of the per-file report
+    #[arg(long, value_enum, default_value = "text")]
+    report: Report,
+    /// With --report github, warn about inputs larger than this many bytes
+    #[arg(long, value_name = "BYTES")]
+    warn_size: Option<u64>,
+    /// With --report github, warn about inputs that optimizing would shrink by at least this percentage
+    #[arg(long, value_name = "PERCENT", default_value_t = 10.0)]
+    warn_savings: f64,
+    /// Leave out inputs larger than this (e.g. 50MB)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    skip_larger_than: Option<u64>,
+    /// Leave out inputs smaller than this (e.g. 1KB)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    skip_smaller_than: Option<u64>,
+    /// Only process inputs modified after this time: a reference file, seconds since the epoch,
+    /// or a UTC date such as 2024-05-01 or 2024-05-01T12:00:00
+    #[arg(long, value_name = "TIME|FILE", value_parser = parse_newer_than)]
+    newer_than: Option<SystemTime>,
+    /// Order in which to process inputs instead of the order they were given and found in
+    #[arg(long, value_enum)]
+    order: Option<Order>,
+    /// Record progress in this file and, when it exists, continue the run it describes
+    #[arg(long, value_name = "STATE")]
+    resume: Option<PathBuf>,
+    /// Search palette orders by simulated annealing for the smallest indexed files; slow
+    #[arg(long)]
+    palette_anneal: bool,
+    /// Seed for randomized searches, so repeated runs produce identical files
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Run at the lowest CPU priority so other workloads are not starved
+    #[arg(long)]
+    nice: bool,
+    /// Limit reading and writing to this many megabytes per second on average
+    #[arg(long, value_name = "MB/S", value_parser = parse_rate)]
+    io_throttle: Option<f64>,
+    /// Write every trial result and a summary into this directory
+    #[arg(long, value_name = "DIR")]
+    emit_candidates: Option<PathBuf>,
+    /// Directory for temporary files: downloads, object store copies and external optimizer
+    /// runs; defaults to the system's (TMPDIR)
+    #[arg(long, value_name = "DIR")]
+    temp_dir: Option<PathBuf>,
 }
 
+impl Opts {
+    /// Whether outputs go into `--out-archive` rather than files.
+    fn writes_archive(&self) -> bool {
+        #[cfg(feature = "archive")]
+        return self.out_archive.is_some();
+        #[cfg(not(feature = "archive"))]
+        false
+    }
 
-fn main() -> std::io::Result<()> {
-    let opts = Opts::parse();
-    let src_data = fs::read(&opts.src)?;
-
-    let mut decoder = Decoder::new(src_data.as_slice());
-    decoder.set_transformations(Transformations::EXPAND);
-    let mut reader = decoder.read_info().unwrap();
-    let mut buf = vec![0; reader.output_buffer_size()];
-    let info = reader.next_frame(&mut buf).unwrap();
-    let bytes = &buf[..info.buffer_size()];
-    println!("{:?}", info);
-
-    let (trivial_compressed, color) = trivial_compress(bytes, info.color_type);
-    let (pallet_compressed, pallet, color, bit_depth) = calc_pallet(&trivial_compressed, color);
-
-    let mut best_size = usize::MAX;
-    let mut best_out = Vec::new();
-    for f in [FilterType::NoFilter, FilterType::Sub, FilterType::Up, FilterType::Avg, FilterType::Paeth] {
-        let out = encode(&pallet_compressed, info.width, info.height, color, pallet.as_ref(), bit_depth, f);
-        println!("filter={:?} size={}", f, out.len());
-        if out.len() < best_size {
-            best_size = out.len();
-            best_out = out;
-        }
+    /// Whether outputs end up as files, unlike with `--check`, `--dry-run` or `--out-archive`.
+    fn writes_files(&self) -> bool {
+        !self.check && !self.dry_run && !self.writes_archive()
+    }
+
+    /// Whether outputs get metadata the input may lack, so that keeping it as it is would not do.
+    fn edits_metadata(&self) -> bool {
+        self.mark || !self.set_text.is_empty() || !self.remove_text.is_empty() || self.dpi.is_some() || self.offset.is_some() || self.convert_to_srgb || self.set_time == SetTime::Now
+    }
+
+    /// The first given option that changes pixels or writes companion images, which animations
+    /// cannot take.
+    fn still_only(&self) -> Option<&'static str> {
+        #[cfg(feature = "quantize")]
+        let lossy = [
+            ("--merge-close-colors", self.merge_close_colors.is_some()),
+            ("--auto-lossy", self.auto_lossy),
+            ("--shared-palette", self.shared_palette),
+            ("--palette-in", self.palette_in.is_some()),
+        ];
+        #[cfg(not(feature = "quantize"))]
+        let lossy = [];
+        [
+            ("--colorkey", self.colorkey.is_some()),
+            ("--extract-channel", self.extract_channel.is_some()),
+            ("--swizzle", self.swizzle.is_some()),
+            ("--split-alpha", self.split_alpha),
+            ("--convert-to-srgb", self.convert_to_srgb),
+            ("--bleed-alpha", self.bleed_alpha),
+            ("--premultiply", self.premultiply),
+            ("--unpremultiply", self.unpremultiply),
+            ("--diff-image", self.diff_image.is_some()),
+            ("--thumbnail", self.thumbnail.is_some()),
+            ("--sizes", !self.sizes.is_empty()),
+        ]
+        .into_iter()
+        .chain(lossy)
+        .find_map(|(option, given)| given.then_some(option))
+    }
+
+    #[cfg(any(feature = "compare-external", feature = "fetch", feature = "object-store"))]
+    fn temp_dir(&self) -> PathBuf {
+        self.temp_dir.clone().unwrap_or_else(std::env::temp_dir)
     }
-    fs::write("out.png", &best_out)?;
-    Ok(())
 }
 
-fn trivial_compress(data: &[u8], color: ColorType) -> (Cow<'_, [u8]>, ColorType) {
-    match color {
-        ColorType::Grayscale => {
-            (Cow::Borrowed(data), ColorType::Grayscale)
+fn main() -> io::Result<()> {
+    let opts = Opts::parse();
+    match &opts.command {
+        Some(Command::Check { src, recursive, max_image_data }) => return cmd::check(src, *recursive, *max_image_data),
+        Some(Command::Analyze { src, recursive, sample }) => return cmd::analyze(src, *recursive, *sample),
+        Some(Command::Extract { src, out_dir }) => return cmd::extract(src, out_dir),
+        Some(Command::Animate { frames, delay, plays, output }) => return cmd::animate(frames, *delay, *plays, output),
+        Some(Command::Decode { src, format, output }) => return cmd::decode(src, (*format).into(), output.as_deref()),
+        #[cfg(feature = "rpc")]
+        Some(Command::Rpc { socket, effort, max_time_per_image }) => {
+            let lib_opts = OptimizeOptions::builder().effort((*effort).into()).time_limit(*max_time_per_image).build();
+            return rpc::run(socket.as_deref(), lib_opts);
         }
-        ColorType::Rgb => {
-            let mut gray = Vec::new();
-            for (r, g, b) in data.iter_rgb() {
-                if r == g && r == b {
-                    gray.push(r);
-                } else {
-                    return (Cow::Borrowed(data), ColorType::Rgb);
-                }
-            }
-            (Cow::Owned(gray), ColorType::Grayscale)
+        #[cfg(feature = "serve")]
+        Some(Command::Serve { listen, effort, max_time_per_image, max_body, jobs }) => {
+            let lib_opts = OptimizeOptions::builder().effort((*effort).into()).time_limit(*max_time_per_image).build();
+            let jobs = jobs.or_else(|| std::thread::available_parallelism().ok()).unwrap_or(std::num::NonZeroUsize::MIN);
+            return serve::run(*listen, lib_opts, *max_body, jobs);
+        }
+        None => {}
+    }
+    if opts.self_test {
+        selftest::run()?;
+        if opts.src.is_empty() && !opts.staged {
+            return Ok(());
+        }
+    }
+    if opts.nice {
+        if let Err(e) = throttle::lower_priority() {
+            eprintln!("warning: cannot lower priority: {}", e);
         }
-        ColorType::Indexed => unreachable!(),
-        ColorType::GrayscaleAlpha => {
-            let mut gray = Vec::new();
-            for (g, a) in data.iter_ga() {
-                if a == 0xFF {
-                    gray.push(g);
-                } else {
-                    return (Cow::Borrowed(data), ColorType::GrayscaleAlpha);
-                }
+    }
+    // Fail before any work rather than on the first temporary file.
+    if let Some(dir) = &opts.temp_dir {
+        fs::create_dir_all(paths::long(dir))
+            .and_then(|()| check_writable(dir))
+            .map_err(|e| io::Error::new(e.kind(), format!("--temp-dir {}: {}", paths::display(dir), e)))?;
+    }
+    #[cfg(feature = "fetch")]
+    let downloads = fetch::Downloads::fetch(&opts.src, &opts.temp_dir())?;
+    // Uploading writes the downloaded copies in place, which must not touch local inputs.
+    #[cfg(feature = "object-store")]
+    let upload = opts.src.iter().any(|src| objstore::is_prefix(src)) && opts.out_dir.is_none() && !opts.writes_archive();
+    #[cfg(feature = "object-store")]
+    if upload && !opts.src.iter().all(|src| objstore::is_prefix(src)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "object store prefixes mixed with other inputs need --out-dir or --out-archive"));
+    }
+    #[cfg(feature = "object-store")]
+    let prefixes = objstore::Prefixes::download(&opts.src, &opts.temp_dir())?;
+    let scan = || -> io::Result<Vec<walk::Input>> {
+        let mut walker = walk::Walker::new(opts.recursive, opts.follow_symlinks);
+        if opts.staged {
+            let (staged, partial) = git::staged_pngs()?;
+            for path in &partial {
+                eprintln!("{}: has unstaged changes, skipped", paths::display(path));
+            }
+            for path in &staged {
+                walker.add_root(path)?;
             }
-            (Cow::Owned(gray), ColorType::Grayscale)
         }
-        ColorType::Rgba => {
-            if data.iter().skip(3).step_by(4).any(|&a| a != 0xFF) {
-                return (Cow::Borrowed(data), ColorType::Rgba);
+        for src in &opts.src {
+            #[cfg(feature = "fetch")]
+            if let Some(input) = downloads.input(src) {
+                walker.inputs.push(input);
+                continue;
+            }
+            #[cfg(feature = "object-store")]
+            if let Some(inputs) = prefixes.inputs(src) {
+                walker.inputs.extend(inputs);
+                continue;
             }
-            if data.iter_rgba().all(|(r, g, b, _)| r == g && r == b) {
-                let data = data.iter().step_by(4).copied().collect::<Vec<_>>();
-                return (Cow::Owned(data), ColorType::Grayscale);
+            if let Some(feature) = missing_feature(src) {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, format!("{}: needs a build with the {} feature", src.to_string_lossy(), feature)));
             }
-            let mut rgb = Vec::with_capacity(data.len() * 3 / 4);
-            for (r, g, b, _) in data.iter_rgba() {
-                rgb.push(r);
-                rgb.push(g);
-                rgb.push(b);
+            walker.add_root(Path::new(src))?;
+        }
+        Ok(walker.inputs)
+    };
+    let (mut state, mut inputs) = match &opts.resume {
+        Some(path) => {
+            let (state, inputs) = resume::State::open(path, scan)?;
+            if state.done() > 0 {
+                println!("resuming: {} of {} inputs already done", state.done(), inputs.len());
             }
-            (Cow::Owned(rgb), ColorType::Rgb)
+            (Some(state), inputs)
         }
+        None => (None, scan()?),
+    };
+    let size = |input: &walk::Input| fs::metadata(paths::long(&input.path)).map_or(0, |m| m.len());
+    if opts.skip_larger_than.is_some() || opts.skip_smaller_than.is_some() {
+        inputs.retain(|input| {
+            let len = size(input);
+            let keep = opts.skip_larger_than.is_none_or(|max| len <= max) && opts.skip_smaller_than.is_none_or(|min| len >= min);
+            if !keep {
+                println!("{}: {} bytes, skipped", paths::display(&input.path), len);
+            }
+            keep
+        });
+    }
+    if let Some(since) = opts.newer_than {
+        let before = inputs.len();
+        inputs.retain(|input| fs::metadata(paths::long(&input.path)).and_then(|m| m.modified()).map_or(true, |modified| modified > since));
+        if inputs.len() < before {
+            println!("{} inputs not modified since --newer-than, skipped", before - inputs.len());
+        }
+    }
+    match opts.order {
+        Some(Order::SizeDesc) => walk::sort_by_key(&mut inputs, |input| Reverse(size(input))),
+        Some(Order::SizeAsc) => walk::sort_by_key(&mut inputs, size),
+        Some(Order::Path) => walk::sort_by_key(&mut inputs, |input| input.path.clone()),
+        None => {}
+    }
+    #[cfg(not(feature = "object-store"))]
+    let upload = false;
+    if opts.out_dir.is_none() && !opts.writes_archive() && !opts.staged && !upload && !opts.check && !opts.dry_run && inputs.len() != 1 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} input files; use --out-dir or --out-archive", inputs.len())));
+    }
+    #[cfg(feature = "archive")]
+    if opts.out_dir.is_none() && !opts.writes_archive() && !opts.check && !opts.dry_run && inputs.iter().any(|input| archive::is_archive(&input.path)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "archive inputs need --out-dir or --out-archive"));
+    }
+    #[cfg(feature = "quantize")]
+    let imposed_palette = opts.shared_palette || opts.palette_in.is_some();
+    #[cfg(not(feature = "quantize"))]
+    let imposed_palette = false;
+    if let Some(path) = &opts.palette_out {
+        palette::check_extension(path)?;
+        if inputs.len() != 1 && !imposed_palette {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "--palette-out with several inputs needs --shared-palette or --palette-in"));
+        }
+    }
+    #[cfg(feature = "quantize")]
+    let palette_in = opts.palette_in.as_deref().map(palette::read).transpose()?;
+    if opts.diff_image.is_some() && inputs.len() != 1 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("--diff-image takes one input, not {}", inputs.len())));
     }
-}
 
-fn calc_pallet(data: &[u8], color: ColorType) -> (Cow<'_, [u8]>, Option<Vec<u8>>, ColorType, BitDepth) {
-    match color {
-        ColorType::Grayscale | ColorType::GrayscaleAlpha | ColorType::Rgba | ColorType::Indexed => {
-            (Cow::Borrowed(data), None, color, BitDepth::Eight)
+    interrupt::install();
+    let mut batch = batch::Batch::new(&opts)?;
+    if upload {
+        batch.write_in_place();
+    }
+    #[cfg(feature = "quantize")]
+    if let Some(palette) = palette_in {
+        batch.index_with(palette);
+    } else if opts.shared_palette {
+        batch.share_palette(&inputs)?;
+    }
+    for (i, input) in inputs.iter().enumerate() {
+        if interrupt::requested() {
+            println!("interrupted: {} of {} inputs not processed", inputs.len() - i, inputs.len());
+            break;
         }
-        ColorType::Rgb => {
-            let mut count = HashMap::new();
-            for rgb in data.iter_rgb() {
-                *count.entry(rgb).or_insert(0u32) += 1;
-            }
-            eprintln!("colors={}", count.len());
-            if count.len() > 256 {
-                return (Cow::Borrowed(data), None, color, BitDepth::Eight);
-            }
-            let mut count = count.into_iter().collect::<Vec<_>>();
-            count.sort_unstable_by(|a, b| b.1.cmp(&a.1));
-            let pallet_map = count.iter().enumerate().map(|(i, x)| (x.0, i as u8)).collect::<HashMap<_, _>>();
-            let mut pallet = Vec::with_capacity(count.len() * 3);
-            for &((r, g, b), _) in count.iter() {
-                pallet.push(r);
-                pallet.push(g);
-                pallet.push(b);
-            }
-            let buf = data.iter_rgb().map(|rgb| pallet_map[&rgb]).collect();
-            (Cow::Owned(buf), Some(pallet), ColorType::Indexed, BitDepth::Eight)
+        if state.as_ref().is_some_and(|state| state.is_done(&input.path)) {
+            continue;
         }
+        let unfinished = batch.unfinished();
+        batch.process(input)?;
+        if let Some(state) = state.as_mut().filter(|_| batch.unfinished() == unfinished) {
+            state.mark_done(&input.path)?;
+        }
+    }
+    if opts.staged && !opts.dry_run {
+        git::add(&batch.outputs())?;
+    }
+    #[cfg(feature = "object-store")]
+    if upload && !opts.check && !opts.dry_run {
+        prefixes.upload_smaller()?;
     }
+    batch.finish()?;
+    if interrupt::requested() {
+        #[cfg(feature = "fetch")]
+        drop(downloads);
+        #[cfg(feature = "object-store")]
+        drop(prefixes);
+        // The conventional status of a process ended by SIGINT.
+        std::process::exit(130);
+    }
+    Ok(())
 }
 
-fn encode(bytes: &[u8], width: u32, height: u32, color_type: ColorType, pallet: Option<&Vec<u8>>, bit_depth: BitDepth, filter_type: FilterType) -> Vec<u8> {
-    let mut buf = Vec::new();
-    {
-        let mut encoder = Encoder::new(&mut buf, width, height);
-        encoder.set_compression(Compression::Best);
-        encoder.set_color(color_type);
-        if let Some(pallet) = pallet {
-            encoder.set_palette(pallet);
-        }
-        encoder.set_depth(bit_depth);
-        encoder.set_filter(filter_type);
-        let mut writer = encoder.write_header().unwrap();
-        writer.write_image_data(bytes).unwrap();
+/// The feature this build lacks to take `src` as an input, for a clearer error than a missing
+/// or undecodable file.
+fn missing_feature(src: &OsStr) -> Option<&'static str> {
+    let src = src.to_str()?.to_ascii_lowercase();
+    let needed = match src.split_once("://") {
+        Some(("http" | "https", _)) => "fetch",
+        Some(("s3" | "gs", _)) => "object-store",
+        _ if [".zip", ".tar", ".tar.gz", ".tgz"].iter().any(|ext| src.ends_with(ext)) => "archive",
+        _ => return None,
+    };
+    let built = [("fetch", cfg!(feature = "fetch")), ("object-store", cfg!(feature = "object-store")), ("archive", cfg!(feature = "archive"))];
+    built.iter().any(|&(feature, on)| feature == needed && !on).then_some(needed)
+}
+
+/// Creates and removes a file in `dir` to check that it is writable.
+fn check_writable(dir: &Path) -> io::Result<()> {
+    let probe = dir.join(format!(".compress-png-{}.probe", std::process::id()));
+    fs::write(paths::long(&probe), b"")?;
+    fs::remove_file(paths::long(&probe))
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value = value.parse::<f64>().map_err(|e| format!("{}: {}", s, e))?;
+    let seconds = match unit {
+        "ms" => value / 1000.0,
+        "" | "s" => value,
+        "m" => value * 60.0,
+        _ => return Err(format!("unknown unit {:?}, expected ms, s or m", unit)),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| format!("{}: too long", s))
+}
+
+fn parse_rgb(s: &str) -> Result<[u8; 3], String> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    let channel = |i: usize| hex.get(i * 2..i * 2 + 2).and_then(|h| u8::from_str_radix(h, 16).ok());
+    match (hex.len(), channel(0), channel(1), channel(2)) {
+        (6, Some(r), Some(g), Some(b)) => Ok([r, g, b]),
+        _ => Err(format!("{}: expected a color as #RRGGBB", s)),
+    }
+}
+
+fn parse_swizzle(s: &str) -> Result<Swizzle, String> {
+    s.parse().map_err(|e: compress_png::Error| e.to_string())
+}
+
+fn parse_trials(s: &str) -> Result<TrialSpec, String> {
+    s.parse().map_err(|e: compress_png::Error| e.to_string())
+}
+
+fn parse_sample(s: &str) -> Result<u32, String> {
+    let every = s.strip_prefix("1/").ok_or_else(|| format!("{}: expected 1/N", s))?;
+    match every.parse::<u32>() {
+        Ok(0) => Err(format!("{}: must be at least 1/1", s)),
+        Ok(every) => Ok(every),
+        Err(e) => Err(format!("{}: {}", s, e)),
     }
-    buf
 }
+
+fn parse_newer_than(s: &str) -> Result<SystemTime, String> {
+    if let Ok(meta) = fs::metadata(paths::long(Path::new(s))) {
+        return meta.modified().map_err(|e| format!("{}: {}", s, e));
+    }
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+    let (date, time) = s.trim_end_matches('Z').split_once(['T', ' ']).unwrap_or((s, "00:00"));
+    let numbers = |v: &str, sep: char| v.split(sep).map(|n| n.parse::<u64>().ok()).collect::<Option<Vec<_>>>();
+    let (year, month, day) = match numbers(date, '-').as_deref() {
+        Some(&[year, month, day]) if year >= 1970 && (1..=12).contains(&month) && (1..=days_in_month(year, month)).contains(&day) => (year, month, day),
+        _ => return Err(format!("{}: not a file, seconds since the epoch or a date from 1970 as YYYY-MM-DD[THH:MM[:SS]]", s)),
+    };
+    let (hour, minute, second) = match numbers(time, ':').as_deref() {
+        Some(&[hour, minute]) if hour < 24 && minute < 60 => (hour, minute, 0),
+        Some(&[hour, minute, second]) if hour < 24 && minute < 60 && second <= 60 => (hour, minute, second),
+        _ => return Err(format!("{}: bad time of day, expected HH:MM[:SS]", s)),
+    };
+    Ok(chunks::from_civil(year, month, day, hour * 3600 + minute * 60 + second))
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.trim_end_matches("MB/s").parse::<f64>() {
+        // At least a byte per second, so that waits stay representable.
+        Ok(rate) if rate * 1000.0 * 1000.0 >= 1.0 => Ok(rate * 1000.0 * 1000.0),
+        Ok(_) => Err(format!("{}: must be at least 0.000001 (a byte per second)", s)),
+        Err(e) => Err(format!("{}: {}", s, e)),
+    }
+}
+
+fn parse_text(s: &str) -> Result<(String, String), String> {
+    let (keyword, text) = s.split_once('=').ok_or_else(|| format!("{}: expected KEY=VALUE", s))?;
+    if !(1..=79).contains(&keyword.len()) || !keyword.chars().all(|c| matches!(c, ' '..='~')) {
+        return Err(format!("{:?}: keywords are 1 to 79 printable ASCII characters", keyword));
+    }
+    Ok((keyword.to_string(), text.to_string()))
+}
+
+fn parse_dimensions(s: &str) -> Result<(u32, u32), String> {
+    let (width, height) = s.split_once(['x', 'X']).ok_or_else(|| format!("{}: expected WxH", s))?;
+    let parse = |v: &str| v.trim().parse::<u32>().map_err(|e| format!("{}: {}", s, e));
+    Ok((parse(width)?, parse(height)?))
+}
+
+fn parse_offset(s: &str) -> Result<(i32, i32), String> {
+    let (x, y) = s.split_once(',').ok_or_else(|| format!("{}: expected x,y", s))?;
+    let parse = |v: &str| v.trim().parse::<i32>().map_err(|e| format!("{}: {}", s, e));
+    Ok((parse(x)?, parse(y)?))
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value = value.parse::<f64>().map_err(|e| format!("{}: {}", s, e))?;
+    let scale = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "kib" => 1 << 10,
+        "m" | "mb" => 1000 * 1000,
+        "mib" => 1 << 20,
+        _ => return Err(format!("unknown unit {:?}, expected B, KB, KiB, MB or MiB", unit)),
+    };
+    Ok((value * scale as f64) as u64)
+}
+
+#[cfg(feature = "quantize")]
+fn parse_region(s: &str) -> Result<Region, String> {
+    let values = s.split(',').map(|v| v.trim().parse::<u32>().map_err(|e| format!("{}: {}", s, e))).collect::<Result<Vec<_>, _>>()?;
+    match values[..] {
+        [x, y, width, height] => Ok(Region { x, y, width, height }),
+        _ => Err(format!("{}: expected x,y,width,height", s)),
+    }
+}
+
+fn emit_candidates(dir: &Path, src: &Path, color: ColorType, bit_depth: BitDepth, candidates: &[(TrialConfig, BitDepth, Vec<u8>)]) -> std::io::Result<()> {
+    let dir = paths::long(dir);
+    fs::create_dir_all(&dir)?;
+    let stem = src.file_stem().unwrap_or(src.as_os_str());
+    let file_name = |suffix: &str| {
+        let mut name = stem.to_os_string();
+        name.push(suffix);
+        paths::safe_file_name(&name)
+    };
+    let mut ranked = candidates.iter().collect::<Vec<_>>();
+    ranked.sort_by_key(|(_, _, out)| out.len());
+    let mut summary = String::new();
+    writeln!(summary, "source={} color={:?} bit_depth={:?}", paths::display(src), color, bit_depth).unwrap();
+    for (rank, (config, depth, out)) in ranked.into_iter().enumerate() {
+        let name = file_name(&format!(".{}-{}.filter-{}.{}.png", format!("{:?}", color).to_lowercase(), *depth as u8, config.filter, config.strategy));
+        fs::write(dir.join(&name), out)?;
+        writeln!(summary, "{}\t{}\t{}", rank + 1, out.len(), paths::display(Path::new(&name))).unwrap();
+    }
+    fs::write(dir.join(file_name(".summary.txt")), summary)
+}
+
@@ -0,0 +1,70 @@
+//! MNG and JNG, the multi-image and JPEG siblings of PNG: recognized so that they fail with a
+//! clear error, and MNG files give up the PNG images they embed.
+
+use std::fmt;
+
+use crate::{chunks, Result};
+
+pub const MNG_SIGNATURE: [u8; 8] = [0x8A, b'M', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+pub const JNG_SIGNATURE: [u8; 8] = [0x8B, b'J', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Mng,
+    Jng,
+}
+
+impl fmt::Display for Container {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Container::Mng => "MNG",
+            Container::Jng => "JNG",
+        })
+    }
+}
+
+/// The container `src` starts with, if it is MNG or JNG.
+pub fn container(src: &[u8]) -> Option<Container> {
+    if src.starts_with(&MNG_SIGNATURE) {
+        Some(Container::Mng)
+    } else if src.starts_with(&JNG_SIGNATURE) {
+        Some(Container::Jng)
+    } else {
+        None
+    }
+}
+
+/// The PNG images embedded in an MNG file, each run of chunks from `IHDR` to `IEND` written out
+/// as a standalone PNG file. Images that depend on MNG state, such as an empty `PLTE` standing
+/// for the global palette, do not stand alone and are skipped; delta images and JNG images
+/// never start with `IHDR`.
+pub fn embedded_pngs(src: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut images = Vec::new();
+    let mut current: Option<Vec<u8>> = None;
+    let mut standalone = true;
+    let mut pos = MNG_SIGNATURE.len();
+    while let Some(header) = src.get(pos..pos + 8) {
+        let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let kind: [u8; 4] = header[4..].try_into().unwrap();
+        let Some(data) = src.get(pos + 8..pos + 8 + len) else {
+            break;
+        };
+        pos += 12 + len;
+        if &kind == b"IHDR" {
+            current = Some(chunks::SIGNATURE.to_vec());
+            standalone = true;
+        }
+        let Some(png) = &mut current else {
+            continue;
+        };
+        standalone &= !(&kind == b"PLTE" && data.is_empty());
+        chunks::write_chunk(&mut *png, &kind, data)?;
+        if &kind == b"IEND" {
+            let png = current.take().unwrap();
+            if standalone {
+                images.push(png);
+            }
+        }
+    }
+    Ok(images)
+}
@@ -0,0 +1,158 @@
+//! `s3://` and `gs://` prefixes as inputs, through the `aws` and `gcloud` command line tools.
+//!
+//! A prefix is synced into a temporary directory and optimized there. Without `--out-dir` or
+//! `--out-archive` the objects that shrank are uploaded back with their content type, which
+//! replaces them along with any other metadata; objects that did not shrink are left alone.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs, io,
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+};
+
+use crate::{paths, walk};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tool {
+    Aws,
+    Gcloud,
+}
+
+impl Tool {
+    fn of(src: &OsStr) -> Option<Tool> {
+        let src = src.to_str()?;
+        if src.starts_with("s3://") {
+            Some(Tool::Aws)
+        } else if src.starts_with("gs://") {
+            Some(Tool::Gcloud)
+        } else {
+            None
+        }
+    }
+
+    fn command(self) -> Command {
+        let mut command = match self {
+            Tool::Aws => Command::new("aws"),
+            Tool::Gcloud => Command::new("gcloud"),
+        };
+        command.stdin(Stdio::null());
+        command
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Tool::Aws => "aws",
+            Tool::Gcloud => "gcloud",
+        }
+    }
+
+    fn scheme(self) -> &'static str {
+        match self {
+            Tool::Aws => "s3",
+            Tool::Gcloud => "gs",
+        }
+    }
+
+    /// Copies every object under `url` into `dir`; aws copies only images.
+    fn download(self, url: &str, dir: &Path) -> io::Result<()> {
+        let mut command = self.command();
+        match self {
+            Tool::Aws => command.args(["s3", "sync", url]).arg(dir).args(["--only-show-errors", "--exclude", "*", "--include", "*.png", "--include", "*.PNG", "--include", "*.ico", "--include", "*.ICO"]),
+            Tool::Gcloud => command.args(["storage", "rsync", url]).arg(dir).args(["--recursive", "--quiet"]),
+        };
+        run(self, command)
+    }
+
+    fn upload(self, file: &Path, url: &str, content_type: &str) -> io::Result<()> {
+        let mut command = self.command();
+        match self {
+            Tool::Aws => command.args(["s3", "cp", "--only-show-errors", "--content-type", content_type]).arg(file).arg(url),
+            Tool::Gcloud => command.args(["storage", "cp", "--quiet"]).arg(format!("--content-type={}", content_type)).arg(file).arg(url),
+        };
+        run(self, command)
+    }
+}
+
+fn run(tool: Tool, mut command: Command) -> io::Result<()> {
+    let status = command.status().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => io::Error::new(e.kind(), format!("{} is not installed or not on PATH; it is needed for {}:// prefixes", tool.name(), tool.scheme())),
+        _ => io::Error::new(e.kind(), format!("cannot run {}: {}", tool.name(), e)),
+    })?;
+    if !status.success() {
+        return Err(io::Error::other(format!("{} exited with {}", tool.name(), status)));
+    }
+    Ok(())
+}
+
+/// Whether a command line input is an object store prefix rather than a path.
+pub fn is_prefix(src: &OsStr) -> bool {
+    Tool::of(src).is_some()
+}
+
+struct Prefix {
+    src: OsString,
+    tool: Tool,
+    /// Images found after the download with their size then.
+    images: Vec<(walk::Input, u64)>,
+}
+
+/// Downloaded prefixes, removed again when dropped.
+pub struct Prefixes {
+    dir: PathBuf,
+    prefixes: Vec<Prefix>,
+}
+
+impl Prefixes {
+    /// Downloads every prefix among `srcs` into `temp_dir`.
+    pub fn download(srcs: &[OsString], temp_dir: &Path) -> io::Result<Prefixes> {
+        let mut downloads = Prefixes { dir: temp_dir.join(format!("compress-png-{}-objects", process::id())), prefixes: Vec::new() };
+        for (i, src) in srcs.iter().enumerate() {
+            let Some(tool) = Tool::of(src) else { continue };
+            let root = downloads.dir.join(i.to_string());
+            fs::create_dir_all(&root)?;
+            println!("{} -> {}", src.to_string_lossy(), paths::display(&root));
+            tool.download(&src.to_string_lossy(), &root)?;
+            let mut walker = walk::Walker::new(true, false);
+            walker.add_root(&root)?;
+            let images = walker.inputs.into_iter().map(|input| fs::metadata(&input.path).map(|m| (input, m.len()))).collect::<io::Result<_>>()?;
+            downloads.prefixes.push(Prefix { src: src.clone(), tool, images });
+        }
+        Ok(downloads)
+    }
+
+    /// The images downloaded for `src`, if it is a prefix.
+    pub fn inputs(&self, src: &OsStr) -> Option<Vec<walk::Input>> {
+        self.prefixes.iter().find(|prefix| prefix.src == src).map(|prefix| prefix.images.iter().map(|(input, _)| input.clone()).collect())
+    }
+
+    /// Uploads the images that are now smaller than when they were downloaded.
+    pub fn upload_smaller(&self) -> io::Result<()> {
+        let (mut uploaded, mut saved) = (0, 0);
+        for prefix in &self.prefixes {
+            let base = prefix.src.to_string_lossy();
+            let base = base.trim_end_matches('/');
+            for (input, size) in &prefix.images {
+                let new_size = fs::metadata(&input.path)?.len();
+                if new_size >= *size {
+                    continue;
+                }
+                let key = input.rel.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+                let url = format!("{}/{}", base, key);
+                let is_ico = input.rel.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("ico"));
+                prefix.tool.upload(&input.path, &url, if is_ico { "image/vnd.microsoft.icon" } else { "image/png" })?;
+                println!("uploaded {} {}->{}", url, size, new_size);
+                uploaded += 1;
+                saved += size - new_size;
+            }
+        }
+        println!("uploaded {} smaller objects, {} bytes saved", uploaded, saved);
+        Ok(())
+    }
+}
+
+impl Drop for Prefixes {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
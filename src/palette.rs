@@ -0,0 +1,158 @@
+//! Palette files exchanged with art tools, chosen by extension:
+//!
+//! - `.act`, Adobe Color Table: 256 RGB triples, optionally followed by the number of colors and
+//!   the index of the one transparent color, both 16-bit big-endian.
+//! - `.gpl`, GIMP palette: a `GIMP Palette` line, optional `Name:` and `Columns:` lines and `#`
+//!   comments, then one `R G B [name]` line per color.
+//! - `.json`: an array of `"#RRGGBB"` or `"#RRGGBBAA"` strings.
+//!
+//! Only JSON keeps translucent colors; ACT keeps one fully transparent color and GPL none.
+
+use std::{fmt::Write as _, fs, io, path::Path};
+
+#[cfg(feature = "quantize")]
+use crate::json;
+use crate::paths;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Act,
+    Gpl,
+    Json,
+}
+
+impl Format {
+    fn of(path: &Path) -> io::Result<Format> {
+        match path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("act") => Ok(Format::Act),
+            Some("gpl") => Ok(Format::Gpl),
+            Some("json") => Ok(Format::Json),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{}: palette files end in .act, .gpl or .json", paths::display(path)))),
+        }
+    }
+}
+
+/// Fails unless `path` names a palette format, to find mistakes before a long run.
+pub fn check_extension(path: &Path) -> io::Result<()> {
+    Format::of(path).map(|_| ())
+}
+
+/// Reads the colors of a palette file, at most 256.
+#[cfg(feature = "quantize")]
+pub fn read(path: &Path) -> io::Result<Vec<[u8; 4]>> {
+    let format = Format::of(path)?;
+    let data = fs::read(paths::long(path))?;
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", paths::display(path), message));
+    let colors = match format {
+        Format::Act => read_act(&data).map_err(invalid)?,
+        Format::Gpl => read_gpl(&String::from_utf8_lossy(&data)).map_err(invalid)?,
+        Format::Json => read_json(&String::from_utf8_lossy(&data)).map_err(invalid)?,
+    };
+    if colors.is_empty() || colors.len() > 256 {
+        return Err(invalid(format!("{} colors; a palette has 1 to 256", colors.len())));
+    }
+    Ok(colors)
+}
+
+/// Writes `colors` as a palette file, warning about alpha the format cannot keep.
+pub fn write(path: &Path, colors: &[[u8; 4]]) -> io::Result<()> {
+    let format = Format::of(path)?;
+    let lost = match format {
+        Format::Act => colors.iter().filter(|c| c[3] != 0xFF && c[3] != 0).count() + colors.iter().filter(|c| c[3] == 0).count().saturating_sub(1),
+        Format::Gpl => colors.iter().filter(|c| c[3] != 0xFF).count(),
+        Format::Json => 0,
+    };
+    if lost > 0 {
+        eprintln!("warning: {}: {} translucent colors written opaque", paths::display(path), lost);
+    }
+    let data = match format {
+        Format::Act => write_act(colors),
+        Format::Gpl => write_gpl(colors).into_bytes(),
+        Format::Json => write_json(colors).into_bytes(),
+    };
+    fs::write(paths::long(path), data)
+}
+
+#[cfg(feature = "quantize")]
+fn read_act(data: &[u8]) -> Result<Vec<[u8; 4]>, String> {
+    if data.len() != 768 && data.len() != 772 {
+        return Err(format!("{} bytes; an ACT file has 768 or 772", data.len()));
+    }
+    let (count, transparent) = match data.get(768..772) {
+        Some(tail) => (u16::from_be_bytes([tail[0], tail[1]]) as usize, u16::from_be_bytes([tail[2], tail[3]]) as usize),
+        None => (256, usize::MAX),
+    };
+    // Some tools write a count of 0 for a full table.
+    let count = if count == 0 { 256 } else { count.min(256) };
+    Ok(data[..count * 3].chunks_exact(3).enumerate().map(|(i, rgb)| [rgb[0], rgb[1], rgb[2], if i == transparent { 0 } else { 0xFF }]).collect())
+}
+
+fn write_act(colors: &[[u8; 4]]) -> Vec<u8> {
+    let mut data = vec![0; 772];
+    for (i, c) in colors.iter().enumerate() {
+        data[i * 3..i * 3 + 3].copy_from_slice(&c[..3]);
+    }
+    let transparent = colors.iter().position(|c| c[3] == 0).map_or(0xFFFF, |i| i as u16);
+    data[768..770].copy_from_slice(&(colors.len() as u16).to_be_bytes());
+    data[770..772].copy_from_slice(&transparent.to_be_bytes());
+    data
+}
+
+#[cfg(feature = "quantize")]
+fn read_gpl(text: &str) -> Result<Vec<[u8; 4]>, String> {
+    let mut lines = text.lines().enumerate();
+    if lines.next().is_none_or(|(_, line)| line.trim() != "GIMP Palette") {
+        return Err("not a GIMP palette: the first line must be \"GIMP Palette\"".to_string());
+    }
+    let mut colors = Vec::new();
+    for (i, line) in lines {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("Name:") || line.starts_with("Columns:") {
+            continue;
+        }
+        let rgb = line.split_whitespace().take(3).map(|v| v.parse::<u8>().ok()).collect::<Option<Vec<_>>>().filter(|rgb| rgb.len() == 3);
+        let Some(rgb) = rgb else {
+            return Err(format!("line {}: expected \"R G B [name]\", got {:?}", i + 1, line));
+        };
+        colors.push([rgb[0], rgb[1], rgb[2], 0xFF]);
+    }
+    Ok(colors)
+}
+
+fn write_gpl(colors: &[[u8; 4]]) -> String {
+    let mut text = format!("GIMP Palette\nName: compress-png\nColumns: 16\n# {} colors\n", colors.len());
+    for (i, c) in colors.iter().enumerate() {
+        writeln!(text, "{:3} {:3} {:3}\tIndex {}", c[0], c[1], c[2], i).unwrap();
+    }
+    text
+}
+
+#[cfg(feature = "quantize")]
+fn read_json(text: &str) -> Result<Vec<[u8; 4]>, String> {
+    let Some(json::Value::Array(values)) = json::parse(text) else {
+        return Err("expected a JSON array of \"#RRGGBB\" or \"#RRGGBBAA\" strings".to_string());
+    };
+    values.iter().enumerate().map(|(i, value)| value.as_str().and_then(parse_hex).ok_or_else(|| format!("color {}: expected \"#RRGGBB\" or \"#RRGGBBAA\"", i))).collect()
+}
+
+fn write_json(colors: &[[u8; 4]]) -> String {
+    let colors = colors
+        .iter()
+        .map(|c| match c[3] {
+            0xFF => format!("\"#{:02x}{:02x}{:02x}\"", c[0], c[1], c[2]),
+            a => format!("\"#{:02x}{:02x}{:02x}{:02x}\"", c[0], c[1], c[2], a),
+        })
+        .collect::<Vec<_>>();
+    format!("[{}]\n", colors.join(", "))
+}
+
+/// `#RRGGBB` or `#RRGGBBAA`.
+#[cfg(feature = "quantize")]
+pub fn parse_hex(s: &str) -> Option<[u8; 4]> {
+    let hex = s.strip_prefix('#')?;
+    if !hex.is_ascii() || (hex.len() != 6 && hex.len() != 8) {
+        return None;
+    }
+    let byte = |i: usize| hex.get(i * 2..i * 2 + 2).map_or(Some(0xFF), |h| u8::from_str_radix(h, 16).ok());
+    Some([byte(0)?, byte(1)?, byte(2)?, byte(3)?])
+}
@@ -0,0 +1,64 @@
+use std::{
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    path::Path,
+};
+
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4",
+    "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Path usable with `std::fs` even beyond `MAX_PATH`: on Windows absolute paths get the `\\?\` prefix.
+#[cfg(windows)]
+pub fn long(path: &Path) -> Cow<'_, Path> {
+    use std::os::windows::ffi::{OsStrExt, OsStringExt};
+
+    let Ok(abs) = std::path::absolute(path) else {
+        return Cow::Borrowed(path);
+    };
+    let wide = abs.as_os_str().encode_wide().collect::<Vec<_>>();
+    let starts_with = |prefix: &str| wide.starts_with(&prefix.encode_utf16().collect::<Vec<_>>());
+    if wide.len() < 248 || starts_with(r"\\?\") {
+        return Cow::Borrowed(path);
+    }
+    let mut out = r"\\?\".encode_utf16().collect::<Vec<_>>();
+    if starts_with(r"\\") {
+        out.extend(r"UNC\".encode_utf16());
+        out.extend_from_slice(&wide[2..]);
+    } else {
+        out.extend_from_slice(&wide);
+    }
+    Cow::Owned(OsString::from_wide(&out).into())
+}
+
+#[cfg(not(windows))]
+pub fn long(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
+/// Lossy rendering for human readable output; never fails on non-UTF8 names.
+pub fn display(path: &Path) -> Cow<'_, str> {
+    path.as_os_str().to_string_lossy()
+}
+
+/// Whether `name` is a DOS device name (`CON`, `com1.png`, `nul .txt`, ...) that Windows refuses to create.
+pub fn is_reserved(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    let base = name.split('.').next().unwrap_or_default().trim_end_matches([' ', '.']);
+    RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(base))
+}
+
+/// Makes a generated file name safe on every platform: reserved device names get a `_` prefix and
+/// characters Windows rejects are replaced, while anything else (including non-UTF8 bytes) is kept.
+pub fn safe_file_name(name: &OsStr) -> OsString {
+    let mut out = OsString::new();
+    if is_reserved(name) {
+        out.push("_");
+    }
+    match name.to_str() {
+        Some(s) => out.push(s.replace(|c: char| c.is_control() || r#"<>:"/\|?*"#.contains(c), "_")),
+        None => out.push(name),
+    }
+    out
+}
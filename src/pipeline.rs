@@ -0,0 +1,164 @@
+//! Search over the representations an image can be stored in.
+//!
+//! Every [`Transition`] maps one representation to another that holds the same pixels: 16-bit
+//! samples narrowing to 8 bits, unused alpha or color channels dropping, binary alpha becoming
+//! a color key, colors becoming palette indices and all-gray palettes becoming gray samples
+//! again. Starting from the decoded image, transitions apply to each new state in turn, so new
+//! ones compose with the others without any ordering written out by hand.
+//!
+//! Transitions that only drop samples make their source pointless to encode, which prunes the
+//! tree; the states left as leaves are compared by a quick encode.
+//!
+//! Library users add their own transitions by implementing [`Reduction`] and registering it
+//! with [`OptimizeOptionsBuilder::reduction`](crate::OptimizeOptionsBuilder::reduction).
+
+use std::{borrow::Cow, collections::HashSet, hash::{DefaultHasher, Hash, Hasher}, sync::Arc};
+
+use png::{BitDepth, ColorType};
+
+use crate::{calc_pallet, color_key, pixel::Rows, trivial_compress, trivial_compress_sixteen, Image, Reductions};
+
+/// More states than any chain of transitions reaches; a guard against transitions undoing each
+/// other.
+const MAX_STATES: usize = 16;
+
+/// A custom representation that takes part in the search alongside the built-in ones, e.g. a
+/// company-specific channel packing.
+///
+/// The result must hold the same pixels as far as its consumers are concerned; results of
+/// another size, or whose samples do not match their color type and bit depth, are ignored.
+pub trait Reduction: Send + Sync {
+    /// Whether [`apply`](Reduction::apply) can represent `image`.
+    fn applies(&self, image: &Image) -> bool;
+
+    fn apply(&self, image: &Image) -> Image<'static>;
+
+    /// Whether the result always encodes smaller than `image`, which then need not be tried.
+    fn dominates(&self) -> bool {
+        false
+    }
+}
+
+struct Transition {
+    /// Whether the result always beats the source, which then need not be encoded.
+    dominates: bool,
+    apply: fn(&Image, &Reductions) -> Option<Image<'static>>,
+}
+
+const TRANSITIONS: [Transition; 5] = [
+    Transition { dominates: true, apply: narrow },
+    Transition { dominates: true, apply: trivial },
+    Transition { dominates: true, apply: key },
+    Transition { dominates: false, apply: palettize },
+    Transition { dominates: false, apply: gray_palette },
+];
+
+/// The smallest representation of `image` that the transitions reach.
+pub(crate) fn reduce<'a>(image: &'a Image, reductions: &Reductions, custom: &[Arc<dyn Reduction>]) -> Image<'a> {
+    let start = match image.color_type {
+        ColorType::Indexed => image.reduce_indexed(),
+        _ => Image { palette: image.palette.clone(), trns: image.trns.clone(), data: Cow::Borrowed(&image.data), text: image.text.clone(), chunks: image.chunks.clone(), ..*image },
+    };
+    let mut seen = HashSet::from([state(&start)]);
+    let (mut frontier, mut leaves) = (vec![start], Vec::new());
+    while let Some(image) = frontier.pop() {
+        let mut dominated = false;
+        let builtin = TRANSITIONS.iter().filter_map(|transition| Some(((transition.apply)(&image, reductions)?, transition.dominates)));
+        let custom = custom.iter().filter(|reduction| reduction.applies(&image)).map(|reduction| (reduction.apply(&image), reduction.dominates()));
+        let next = builtin.chain(custom.filter(|(next, _)| (next.width, next.height) == (image.width, image.height) && Rows::from_image(next).is_ok())).collect::<Vec<_>>();
+        for (next, dominates) in next {
+            if seen.len() < MAX_STATES && seen.insert(state(&next)) {
+                dominated |= dominates;
+                frontier.push(next);
+            }
+        }
+        if !dominated {
+            leaves.push(image);
+        }
+    }
+    // Leaves are found depth first; ties go to the one found first.
+    leaves.into_iter().map(|leaf| (packed_size(&leaf), leaf)).min_by_key(|(size, _)| *size).map(|(_, leaf)| leaf).expect("the start is a leaf unless a transition applied")
+}
+
+/// What tells representations apart: everything that gets encoded.
+fn state(image: &Image) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (image.color_type as u8, image.bit_depth as u8, &image.palette, &image.trns, &image.data).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// [`Image::quick_size`] at the depth the trials would pack the image to.
+fn packed_size(image: &Image) -> usize {
+    image.packed().map_or_else(|| image.quick_size(), |packed| packed.quick_size())
+}
+
+fn with_samples(image: &Image, color_type: ColorType, bit_depth: BitDepth, data: Vec<u8>) -> Image<'static> {
+    Image {
+        color_type,
+        bit_depth,
+        palette: None,
+        trns: None,
+        data: Cow::Owned(data),
+        text: image.text.clone(),
+        chunks: image.chunks.clone(),
+        ..*image
+    }
+}
+
+/// 16-bit samples that are all `v * 257`, typically upscaled 8-bit data, as 8 bits.
+fn narrow(image: &Image, _: &Reductions) -> Option<Image<'static>> {
+    if image.bit_depth != BitDepth::Sixteen || image.trns.is_some() || !image.data.chunks_exact(2).all(|s| s[0] == s[1]) {
+        return None;
+    }
+    Some(with_samples(image, image.color_type, BitDepth::Eight, image.data.iter().step_by(2).copied().collect()))
+}
+
+/// Drops alpha that is always opaque and color that is always gray.
+fn trivial(image: &Image, reductions: &Reductions) -> Option<Image<'static>> {
+    if image.color_type == ColorType::Indexed || image.trns.is_some() {
+        return None;
+    }
+    let (data, color) = match image.bit_depth {
+        BitDepth::Eight => trivial_compress(&image.data, image.color_type, reductions),
+        BitDepth::Sixteen => trivial_compress_sixteen(&image.data, image.color_type, reductions),
+        _ => return None,
+    };
+    match data {
+        Cow::Owned(data) => Some(with_samples(image, color, image.bit_depth, data)),
+        Cow::Borrowed(_) => None,
+    }
+}
+
+/// Binary alpha as a `tRNS` color key.
+fn key(image: &Image, reductions: &Reductions) -> Option<Image<'static>> {
+    let (data, color, key) = color_key(&image.data, image.color_type, image.bit_depth, reductions)?;
+    Some(Image { trns: Some(key), ..with_samples(image, color, image.bit_depth, data) })
+}
+
+/// At most 256 colors of 8-bit true color as palette indices.
+fn palettize(image: &Image, reductions: &Reductions) -> Option<Image<'static>> {
+    if !reductions.palette || image.bit_depth != BitDepth::Eight || image.trns.is_some() {
+        return None;
+    }
+    let p = calc_pallet(Rows::from_image(image).ok()?, image.color_type)?;
+    Some(Image { palette: Some(p.palette), trns: p.trns, ..with_samples(image, ColorType::Indexed, BitDepth::Eight, p.data) })
+}
+
+/// An indexed image whose palette holds only grays, as gray samples with alpha only when an
+/// entry is translucent.
+fn gray_palette(image: &Image, reductions: &Reductions) -> Option<Image<'static>> {
+    if image.color_type != ColorType::Indexed || image.bit_depth != BitDepth::Eight || !reductions.gray {
+        return None;
+    }
+    let palette = image.palette.as_deref()?;
+    if !palette.chunks_exact(3).all(|rgb| rgb[0] == rgb[1] && rgb[0] == rgb[2]) {
+        return None;
+    }
+    let trns = image.trns.as_deref().unwrap_or_default();
+    let gray = |i: u8| palette.get(i as usize * 3).copied().unwrap_or(0);
+    if trns.is_empty() {
+        return Some(with_samples(image, ColorType::Grayscale, BitDepth::Eight, image.data.iter().map(|&i| gray(i)).collect()));
+    }
+    let alpha = |i: u8| trns.get(i as usize).copied().unwrap_or(0xFF);
+    Some(with_samples(image, ColorType::GrayscaleAlpha, BitDepth::Eight, image.data.iter().flat_map(|&i| [gray(i), alpha(i)]).collect()))
+}
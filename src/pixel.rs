@@ -0,0 +1,246 @@
+//! Iteration over the pixels of sample buffers.
+//!
+//! [`IterPixel`] groups a raw byte slice of 8-bit samples into tuples without knowing the image
+//! it came from; a buffer of the wrong length silently loses its last bytes, and one of the
+//! wrong color type yields shifted channels. [`Pixels`] checks the buffer against the width,
+//! height, color type and bit depth once, up front, and then iterates without further checks.
+//!
+//! [`Rows`] is the scanline view shared by filtering, palettization and checking, so that every
+//! module agrees on where one row ends and the next begins.
+//!
+//! Samples are read at their native depth: 1, 2 and 4-bit samples are packed most significant
+//! bits first with every row padded to a whole byte, and 16-bit samples are big-endian, as
+//! in the PNG data stream.
+
+use itertools::Itertools;
+use png::{BitDepth, ColorType};
+
+use crate::{Error, Image, Result};
+
+/// Groups raw 8-bit samples into pixels. Trailing bytes that do not fill a whole pixel are
+/// dropped; use [`Pixels`] to have the length checked.
+pub trait IterPixel {
+    /// Gray and alpha pairs.
+    fn iter_ga(&self) -> impl Iterator<Item=(u8, u8)>;
+
+    fn iter_rgb(&self) -> impl Iterator<Item=(u8, u8, u8)>;
+
+    fn iter_rgba(&self) -> impl Iterator<Item=(u8, u8, u8, u8)>;
+}
+
+impl IterPixel for [u8] {
+    fn iter_ga(&self) -> impl Iterator<Item=(u8, u8)> {
+        self.iter().copied().tuples()
+    }
+
+    fn iter_rgb(&self) -> impl Iterator<Item=(u8, u8, u8)> {
+        self.iter().copied().tuples()
+    }
+
+    fn iter_rgba(&self) -> impl Iterator<Item=(u8, u8, u8, u8)> {
+        self.iter().copied().tuples()
+    }
+}
+
+/// Color samples whose length was checked against the image dimensions.
+#[derive(Debug, Clone, Copy)]
+pub struct Pixels<'a> {
+    data: &'a [u8],
+    width: u32,
+    height: u32,
+    color_type: ColorType,
+    bit_depth: BitDepth,
+}
+
+impl<'a> Pixels<'a> {
+    /// Fails unless `data` holds exactly `width * height` pixels of 8-bit `color_type`
+    /// samples. Indexed samples are rejected, being palette positions rather than colors.
+    pub fn new(data: &'a [u8], width: u32, height: u32, color_type: ColorType) -> Result<Self> {
+        Pixels::with_depth(data, width, height, color_type, BitDepth::Eight)
+    }
+
+    /// Like [`new`](Pixels::new) for samples of any bit depth PNG allows for `color_type`.
+    pub fn with_depth(data: &'a [u8], width: u32, height: u32, color_type: ColorType, bit_depth: BitDepth) -> Result<Self> {
+        match (color_type, bit_depth) {
+            (ColorType::Indexed, _) => return Err(Error::Format("indexed samples are not colors".to_string())),
+            (ColorType::Grayscale, _) | (_, BitDepth::Eight | BitDepth::Sixteen) => {}
+            _ => return Err(Error::Format(format!("{:?} samples cannot be {:?}", color_type, bit_depth))),
+        }
+        Rows::new(data, width, height, color_type, bit_depth)?;
+        Ok(Pixels { data, width, height, color_type, bit_depth })
+    }
+
+    /// The pixels of a non-indexed image.
+    pub fn from_image(image: &'a Image) -> Result<Self> {
+        Pixels::with_depth(&image.data, image.width, image.height, image.color_type, image.bit_depth)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn color_type(&self) -> ColorType {
+        self.color_type
+    }
+
+    pub fn bit_depth(&self) -> BitDepth {
+        self.bit_depth
+    }
+
+    /// Bytes per row, including the padding of sub-byte depths.
+    pub fn stride(&self) -> usize {
+        stride(self.width, self.color_type.samples() * self.bit_depth as usize)
+    }
+
+    pub fn rows(&self) -> Rows<'a> {
+        Rows { data: self.data, width: self.width, height: self.height, stride: self.stride(), bits_per_pixel: self.color_type.samples() * self.bit_depth as usize }
+    }
+
+    /// Largest sample value, which is also that of opaque alpha.
+    pub fn max_value(&self) -> u16 {
+        ((1u32 << self.bit_depth as u32) - 1) as u16
+    }
+
+    /// Number of pixels.
+    pub fn len(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The raw samples.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Every sample at its native depth, row by row, without the row padding.
+    pub fn samples(&self) -> impl Iterator<Item=u16> + 'a {
+        let (depth, count) = (self.bit_depth, self.width as usize * self.color_type.samples());
+        self.rows().iter().flat_map(move |row| samples(row, depth).take(count))
+    }
+
+    /// Every pixel as RGBA at its native depth, row by row; gray spreads to all three colors
+    /// and missing alpha is [`max_value`](Pixels::max_value).
+    pub fn pixels(&self) -> impl Iterator<Item=[u16; 4]> + 'a {
+        let (width, samples, depth, max) = (self.width as usize, self.color_type.samples(), self.bit_depth, self.max_value());
+        self.rows().iter().flat_map(move |row| (0..width).map(move |x| pixel(row, x, samples, depth, max)))
+    }
+
+    /// Every pixel as 8-bit RGBA, row by row: lower depths are scaled up exactly and 16-bit
+    /// samples keep their high byte.
+    pub fn rgba(&self) -> impl Iterator<Item=[u8; 4]> + 'a {
+        let depth = self.bit_depth;
+        self.pixels().map(move |px| px.map(|v| match depth {
+            BitDepth::Sixteen => (v >> 8) as u8,
+            depth => (v * (255 / ((1 << depth as u16) - 1))) as u8,
+        }))
+    }
+
+    /// The pixel at column `x` of row `y` as RGBA at its native depth, if inside the image.
+    pub fn get(&self, x: u32, y: u32) -> Option<[u16; 4]> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let row = self.rows().get(y)?;
+        Some(pixel(row, x as usize, self.color_type.samples(), self.bit_depth, self.max_value()))
+    }
+}
+
+/// The scanlines of an image, each [`stride`](Rows::stride) bytes long, without filter type
+/// bytes. Unlike [`Pixels`], indexed samples are allowed.
+#[derive(Debug, Clone, Copy)]
+pub struct Rows<'a> {
+    data: &'a [u8],
+    width: u32,
+    height: u32,
+    stride: usize,
+    bits_per_pixel: usize,
+}
+
+impl<'a> Rows<'a> {
+    /// Fails unless `data` holds exactly `height` rows of `width` pixels of `color_type` at
+    /// `bit_depth`.
+    pub fn new(data: &'a [u8], width: u32, height: u32, color_type: ColorType, bit_depth: BitDepth) -> Result<Self> {
+        let bits_per_pixel = color_type.samples() * bit_depth as usize;
+        let stride = (width as usize).checked_mul(bits_per_pixel).map(|bits| bits.div_ceil(8));
+        if stride.and_then(|stride| stride.checked_mul(height as usize)) != Some(data.len()) {
+            return Err(Error::Format(format!("{} bytes do not hold {}x{} {:?} {:?} pixels", data.len(), width, height, color_type, bit_depth)));
+        }
+        Ok(Rows { data, width, height, stride: stride.unwrap(), bits_per_pixel })
+    }
+
+    pub fn from_image(image: &'a Image) -> Result<Self> {
+        Rows::new(&image.data, image.width, image.height, image.color_type, image.bit_depth)
+    }
+
+    /// Pixels per row.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Bytes per row, including the padding of sub-byte depths.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Distance in bytes between a sample and the same sample of the pixel to its left, as the
+    /// filters use it: at least 1.
+    pub fn filter_distance(&self) -> usize {
+        self.bits_per_pixel.div_ceil(8)
+    }
+
+    /// Number of rows.
+    pub fn len(&self) -> usize {
+        self.height as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.height == 0
+    }
+
+    pub fn get(&self, y: u32) -> Option<&'a [u8]> {
+        (y < self.height).then(|| &self.data[y as usize * self.stride..][..self.stride])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&'a [u8]> + 'a {
+        let (data, stride) = (self.data, self.stride);
+        (0..self.height as usize).map(move |y| &data[y * stride..][..stride])
+    }
+}
+
+/// Bytes in a row of `width` pixels of `bits_per_pixel`, padded to a whole byte.
+pub fn stride(width: u32, bits_per_pixel: usize) -> usize {
+    (width as usize * bits_per_pixel).div_ceil(8)
+}
+
+/// Unpacks the samples of one row at `bit_depth`, including any padding at the end.
+pub fn samples(row: &[u8], bit_depth: BitDepth) -> impl Iterator<Item=u16> + '_ {
+    (0..row.len() * 8 / bit_depth as usize).map(move |i| sample(row, i, bit_depth))
+}
+
+/// The `i`-th sample of `row`.
+fn sample(row: &[u8], i: usize, bit_depth: BitDepth) -> u16 {
+    let bits = bit_depth as usize;
+    match bit_depth {
+        BitDepth::Sixteen => u16::from_be_bytes([row[2 * i], row[2 * i + 1]]),
+        BitDepth::Eight => row[i] as u16,
+        _ => (row[i * bits / 8] >> (8 - bits - i * bits % 8) & ((1 << bits) - 1)) as u16,
+    }
+}
+
+/// The pixel at column `x` of `row` widened to RGBA, `max` standing in for missing alpha.
+fn pixel(row: &[u8], x: usize, samples: usize, bit_depth: BitDepth, max: u16) -> [u16; 4] {
+    let at = |k: usize| sample(row, x * samples + k, bit_depth);
+    match samples {
+        1 => [at(0), at(0), at(0), max],
+        2 => [at(0), at(0), at(0), at(1)],
+        3 => [at(0), at(1), at(2), max],
+        _ => [at(0), at(1), at(2), at(3)],
+    }
+}
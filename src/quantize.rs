@@ -0,0 +1,304 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
+
+use png::{BitDepth, ColorType};
+
+use crate::{chunks, histogram::{color_histogram, Histogram}, Image};
+
+const REFINE_ROUNDS: usize = 4;
+
+/// How stored sample values map to linear light.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transfer {
+    Srgb,
+    /// Power law with the encoding exponent of a `gAMA` chunk, e.g. 0.45455.
+    Gamma(f32),
+}
+
+impl Transfer {
+    /// The transfer declared by a PNG file: `sRGB` wins over `gAMA`, and files without either
+    /// are assumed to be sRGB.
+    pub fn from_png(src: &[u8]) -> Transfer {
+        let chunks = chunks::parse(src).chunks;
+        if chunks.iter().any(|c| &c.kind == b"sRGB") {
+            return Transfer::Srgb;
+        }
+        chunks
+            .iter()
+            .find(|c| &c.kind == b"gAMA" && c.data.len() == 4)
+            .map(|c| u32::from_be_bytes(c.data.try_into().unwrap()))
+            .filter(|&g| g != 0)
+            .map_or(Transfer::Srgb, |g| Transfer::Gamma(g as f32 / 100_000.0))
+    }
+
+    fn to_linear(self, v: u8) -> f32 {
+        let v = v as f32 / 255.0;
+        match self {
+            Transfer::Srgb if v <= 0.04045 => v / 12.92,
+            Transfer::Srgb => ((v + 0.055) / 1.055).powf(2.4),
+            Transfer::Gamma(g) => v.powf(1.0 / g),
+        }
+    }
+}
+
+/// CIE76 ΔE below which most observers cannot tell two colors apart.
+pub const JUST_NOTICEABLE: f32 = 2.3;
+
+/// Merges colors closer than `max_delta_e` (CIE76 ΔE in CIELAB, about 2.3 being just noticeable)
+/// into the most representative color of their group, returning how many colors disappeared.
+/// Distances are taken from linear light decoded with `transfer`, so dark gradients, whose
+/// raw values sit closer together than they look, are not flattened into bands.
+/// Only colors with the same alpha are merged; 16-bit images are left alone.
+pub fn merge_close_colors(image: &mut Image, max_delta_e: f32, transfer: Transfer) -> usize {
+    if image.bit_depth != BitDepth::Eight || image.color_type == ColorType::Indexed {
+        return 0;
+    }
+    let colors = color_histogram(&image.data, image.color_type).dominant(usize::MAX);
+    let linear: [f32; 256] = std::array::from_fn(|v| transfer.to_linear(v as u8));
+    let lab = colors.iter().map(|&(rgba, _)| to_lab([rgba[0], rgba[1], rgba[2]].map(|v| linear[v as usize]))).collect::<Vec<_>>();
+    let limit = max_delta_e * max_delta_e;
+
+    // Leader clustering, most frequent colors first, then a few k-means style rounds that move
+    // each center to the member closest to the weighted mean and reassign members.
+    let mut centers: Vec<usize> = Vec::new();
+    let mut grid = Grid::new(max_delta_e);
+    let mut assignment = Vec::with_capacity(colors.len());
+    for i in 0..colors.len() {
+        let c = grid.nearest(&centers, &colors, &lab, i, limit).unwrap_or_else(|| {
+            grid.insert(centers.len(), colors[i].0[3], lab[i]);
+            centers.push(i);
+            centers.len() - 1
+        });
+        assignment.push(c);
+    }
+    for _ in 0..REFINE_ROUNDS {
+        let mut sums = vec![([0f64; 3], 0f64); centers.len()];
+        for (i, &c) in assignment.iter().enumerate() {
+            let w = colors[i].1 as f64;
+            for (sum, &v) in sums[c].0.iter_mut().zip(&lab[i]) {
+                *sum += v as f64 * w;
+            }
+            sums[c].1 += w;
+        }
+        let mut best = vec![(f32::MAX, 0); centers.len()];
+        for (i, &c) in assignment.iter().enumerate() {
+            let mean = sums[c].0.map(|s| (s / sums[c].1) as f32);
+            let d = distance(lab[i], mean);
+            if d < best[c].0 {
+                best[c] = (d, i);
+            }
+        }
+        let moved = best.iter().map(|&(_, i)| i).collect::<Vec<_>>();
+        if moved == centers {
+            break;
+        }
+        centers = moved;
+        grid = Grid::new(max_delta_e);
+        for (k, &c) in centers.iter().enumerate() {
+            grid.insert(k, colors[c].0[3], lab[c]);
+        }
+        for (i, slot) in assignment.iter_mut().enumerate() {
+            // A color that drifted out of every center's reach keeps itself.
+            *slot = grid.nearest(&centers, &colors, &lab, i, limit).unwrap_or_else(|| {
+                grid.insert(centers.len(), colors[i].0[3], lab[i]);
+                centers.push(i);
+                centers.len() - 1
+            });
+        }
+    }
+
+    let target = |i: usize| centers[assignment[i]];
+    let map = (0..colors.len()).filter(|&i| target(i) != i).map(|i| (colors[i].0, colors[target(i)].0)).collect::<HashMap<_, _>>();
+    if map.is_empty() {
+        return 0;
+    }
+    let merged = colors.len() - (0..colors.len()).map(target).collect::<HashSet<_>>().len();
+    let samples = image.color_type.samples();
+    for px in image.data.to_mut().chunks_exact_mut(samples) {
+        let rgba = match samples {
+            1 => [px[0], px[0], px[0], 0xFF],
+            2 => [px[0], px[0], px[0], px[1]],
+            3 => [px[0], px[1], px[2], 0xFF],
+            _ => [px[0], px[1], px[2], px[3]],
+        };
+        if let Some(to) = map.get(&rgba) {
+            match samples {
+                1 | 2 => px[0] = to[0],
+                _ => px[..3].copy_from_slice(&to[..3]),
+            }
+        }
+    }
+    merged
+}
+
+/// One palette of at most 256 RGBA colors for the colors of `histogram`, usually those of a set
+/// of images that are shown together. Exact when there are few enough colors; otherwise median
+/// cut, splitting the box of colors with the widest channel range at its weighted median until
+/// there are 256 boxes, each becoming its weighted mean. Fully transparent colors all become
+/// transparent black. Translucent entries come first so that `tRNS` stays short.
+pub fn shared_palette(histogram: &Histogram) -> Vec<[u8; 4]> {
+    let mut counts = HashMap::new();
+    for (rgba, n) in histogram.iter() {
+        *counts.entry(if rgba[3] == 0 { [0; 4] } else { rgba }).or_insert(0) += n;
+    }
+    let mut colors = counts.into_iter().collect::<Vec<_>>();
+    colors.sort_unstable();
+    let mut palette = if colors.len() <= 256 {
+        colors
+    } else {
+        let mut boxes = vec![colors];
+        while boxes.len() < 256 {
+            let widest = boxes.iter().enumerate().filter(|(_, b)| b.len() > 1).map(|(i, b)| (i, widest_channel(b))).max_by_key(|&(_, (_, range))| range);
+            let Some((i, (channel, _))) = widest else { break };
+            let mut colors = boxes.swap_remove(i);
+            colors.sort_unstable_by_key(|(rgba, _)| rgba[channel]);
+            let half = colors.iter().map(|(_, n)| n).sum::<u64>() / 2;
+            let mut seen = 0;
+            let split = colors.iter().position(|(_, n)| {
+                seen += n;
+                seen > half
+            });
+            let split = split.unwrap_or(0).clamp(1, colors.len() - 1);
+            let upper = colors.split_off(split);
+            boxes.push(colors);
+            boxes.push(upper);
+        }
+        boxes.iter().map(|colors| (weighted_mean(colors), colors.iter().map(|(_, n)| n).sum())).collect()
+    };
+    palette.sort_by_key(|&(rgba, n)| (rgba[3] == 0xFF, std::cmp::Reverse(n)));
+    palette.into_iter().map(|(rgba, _)| rgba).collect()
+}
+
+/// The channel with the widest range among `colors`, and that range.
+fn widest_channel(colors: &[([u8; 4], u64)]) -> (usize, u8) {
+    (0..4)
+        .map(|c| {
+            let (min, max) = colors.iter().fold((u8::MAX, 0), |(min, max), (rgba, _)| (min.min(rgba[c]), max.max(rgba[c])));
+            (c, max - min)
+        })
+        .max_by_key(|&(_, range)| range)
+        .unwrap()
+}
+
+fn weighted_mean(colors: &[([u8; 4], u64)]) -> [u8; 4] {
+    let total = colors.iter().map(|(_, n)| n).sum::<u64>().max(1);
+    std::array::from_fn(|c| ((colors.iter().map(|(rgba, n)| rgba[c] as u64 * n).sum::<u64>() + total / 2) / total) as u8)
+}
+
+/// `image` as 8-bit indices into `palette`, at most 256 colors as made by [`shared_palette`],
+/// keeping the palette whole and in order. Each color becomes the nearest entry by squared
+/// RGBA distance, so colors missing from the palette change, and fully transparent pixels
+/// become transparent black.
+///
+/// # Panics
+///
+/// Panics on indexed or sub-byte images, as [`Image::to_rgba8`] does.
+pub fn index_with_palette(image: &Image, palette: &[[u8; 4]]) -> Image<'static> {
+    assert!(palette.len() <= 256);
+    let rgba = image.to_rgba8();
+    let mut nearest = HashMap::new();
+    let data = rgba
+        .data
+        .chunks_exact(4)
+        .map(|px| {
+            // Like the palette, fully transparent pixels keep no color.
+            let px = if px[3] == 0 { [0; 4] } else { [px[0], px[1], px[2], px[3]] };
+            *nearest.entry(px).or_insert_with(|| {
+                let distance = |entry: &[u8; 4]| (0..4).map(|c| (entry[c] as i32 - px[c] as i32).pow(2)).sum::<i32>();
+                palette.iter().enumerate().min_by_key(|(_, entry)| distance(entry)).map_or(0, |(i, _)| i as u8)
+            })
+        })
+        .collect::<Vec<_>>();
+    // Palettes from elsewhere may have translucent entries anywhere.
+    let trns = palette.iter().map(|rgba| rgba[3]).collect::<Vec<_>>();
+    let trns = &trns[..trns.iter().rposition(|&a| a != 0xFF).map_or(0, |i| i + 1)];
+    Image {
+        color_type: ColorType::Indexed,
+        bit_depth: BitDepth::Eight,
+        palette: Some(palette.iter().flat_map(|rgba| &rgba[..3]).copied().collect()),
+        trns: (!trns.is_empty()).then(|| trns.to_vec()),
+        data: Cow::Owned(data),
+        ..rgba
+    }
+}
+
+/// A rectangle of pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Copies the pixels of `regions` back from `original`, an image of the same size and format,
+/// so that lossy steps leave logos and text untouched. Regions are clipped to the image.
+pub fn restore_regions(image: &mut Image, original: &Image, regions: &[Region]) {
+    assert_eq!((image.width, image.height, image.color_type, image.bit_depth), (original.width, original.height, original.color_type, original.bit_depth));
+    let row_len = image.data.len() / image.height.max(1) as usize;
+    let pixel_len = row_len / image.width.max(1) as usize;
+    let data = image.data.to_mut();
+    for region in regions {
+        let x_end = region.x.saturating_add(region.width).min(image.width) as usize;
+        let y_end = region.y.saturating_add(region.height).min(image.height) as usize;
+        let x = (region.x as usize).min(x_end);
+        for y in region.y as usize..y_end {
+            let span = y * row_len + x * pixel_len..y * row_len + x_end * pixel_len;
+            data[span.clone()].copy_from_slice(&original.data[span]);
+        }
+    }
+}
+
+/// Indices into the cluster centers, bucketed by alpha and by CIELAB cells at least as wide as
+/// the merge distance, so that only the 27 cells around a color can hold centers within reach.
+struct Grid {
+    cell: f32,
+    buckets: HashMap<(u8, [i32; 3]), Vec<usize>>,
+}
+
+impl Grid {
+    fn new(max_delta_e: f32) -> Grid {
+        Grid { cell: max_delta_e.max(0.5), buckets: HashMap::new() }
+    }
+
+    fn cell_of(&self, lab: [f32; 3]) -> [i32; 3] {
+        lab.map(|v| (v / self.cell).floor() as i32)
+    }
+
+    fn insert(&mut self, k: usize, alpha: u8, lab: [f32; 3]) {
+        let cell = self.cell_of(lab);
+        self.buckets.entry((alpha, cell)).or_default().push(k);
+    }
+
+    /// Index into `centers` of the closest center with the same alpha as color `i`, within
+    /// `limit`; the first one of equally close centers.
+    fn nearest(&self, centers: &[usize], colors: &[([u8; 4], u64)], lab: &[[f32; 3]], i: usize, limit: f32) -> Option<usize> {
+        let [x, y, z] = self.cell_of(lab[i]);
+        let alpha = colors[i].0[3];
+        let neighbors = (-1..=1).flat_map(|dx| (-1..=1).flat_map(move |dy| (-1..=1).map(move |dz| [x + dx, y + dy, z + dz])));
+        neighbors
+            .filter_map(|cell| self.buckets.get(&(alpha, cell)))
+            .flatten()
+            .map(|&k| (k, distance(lab[centers[k]], lab[i])))
+            .filter(|&(_, d)| d <= limit)
+            .min_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)))
+            .map(|(k, _)| k)
+    }
+}
+
+/// Squared Euclidean distance.
+fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    (0..3).map(|k| (a[k] - b[k]) * (a[k] - b[k])).sum()
+}
+
+/// CIELAB under D65 of linear-light RGB.
+fn to_lab([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+    let f = |t: f32| if t > 216.0 / 24389.0 { t.cbrt() } else { (24389.0 / 27.0 * t + 16.0) / 116.0 };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
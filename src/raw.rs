@@ -0,0 +1,93 @@
+use std::io::{self, Write};
+
+use png::{BitDepth, ColorType};
+
+use crate::Image;
+
+/// Uncompressed formats understood by netpbm and ImageMagick style tools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RawFormat {
+    /// Binary PGM (`P5`) or PPM (`P6`); cannot hold alpha.
+    Pnm,
+    /// Netpbm arbitrary map (`P7`) with a tuple type matching the color type.
+    Pam,
+    /// farbfeld: always 16-bit RGBA.
+    Farbfeld,
+}
+
+impl RawFormat {
+    pub fn extension(self, color: ColorType) -> &'static str {
+        match self {
+            RawFormat::Pnm if color == ColorType::Grayscale => "pgm",
+            RawFormat::Pnm => "ppm",
+            RawFormat::Pam => "pam",
+            RawFormat::Farbfeld => "ff",
+        }
+    }
+}
+
+/// Writes the samples of a decoded (not indexed) image, keeping 16-bit samples big-endian as
+/// all three formats store them.
+pub fn write_raw<W: Write>(mut w: W, image: &Image, format: RawFormat) -> io::Result<()> {
+    let unsupported = |what: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} cannot hold {}", format, what));
+    if image.color_type == ColorType::Indexed {
+        return Err(unsupported("indexed images"));
+    }
+    let maxval = match image.bit_depth {
+        BitDepth::Sixteen => 65535,
+        BitDepth::Eight => 255,
+        _ => return Err(unsupported("packed samples")),
+    };
+    match format {
+        RawFormat::Pnm => {
+            let magic = match image.color_type {
+                ColorType::Grayscale => "P5",
+                ColorType::Rgb => "P6",
+                _ => return Err(unsupported("alpha")),
+            };
+            write!(w, "{}\n{} {}\n{}\n", magic, image.width, image.height, maxval)?;
+            w.write_all(&image.data)
+        }
+        RawFormat::Pam => {
+            let tuple_type = match image.color_type {
+                ColorType::Grayscale => "GRAYSCALE",
+                ColorType::GrayscaleAlpha => "GRAYSCALE_ALPHA",
+                ColorType::Rgb => "RGB",
+                _ => "RGB_ALPHA",
+            };
+            write!(
+                w,
+                "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL {}\nTUPLTYPE {}\nENDHDR\n",
+                image.width,
+                image.height,
+                image.color_type.samples(),
+                maxval,
+                tuple_type,
+            )?;
+            w.write_all(&image.data)
+        }
+        RawFormat::Farbfeld => {
+            w.write_all(b"farbfeld")?;
+            w.write_all(&image.width.to_be_bytes())?;
+            w.write_all(&image.height.to_be_bytes())?;
+            let wide = image.bit_depth == BitDepth::Sixteen;
+            let samples = image.color_type.samples() * if wide { 2 } else { 1 };
+            let mut row = Vec::with_capacity(image.width as usize * 8);
+            for line in image.data.chunks(samples * image.width.max(1) as usize) {
+                row.clear();
+                for px in line.chunks_exact(samples) {
+                    let sample = |k: usize| if wide { u16::from_be_bytes([px[2 * k], px[2 * k + 1]]) } else { px[k] as u16 * 257 };
+                    let rgba = match image.color_type {
+                        ColorType::Grayscale => [sample(0), sample(0), sample(0), 0xFFFF],
+                        ColorType::GrayscaleAlpha => [sample(0), sample(0), sample(0), sample(1)],
+                        ColorType::Rgb => [sample(0), sample(1), sample(2), 0xFFFF],
+                        _ => [sample(0), sample(1), sample(2), sample(3)],
+                    };
+                    rgba.iter().for_each(|v| row.extend(v.to_be_bytes()));
+                }
+                w.write_all(&row)?;
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{
+    check::{AFTER_PLTE, BEFORE_IDAT, BEFORE_PLTE, UNIQUE},
+    chunks::{self, Chunk, SIGNATURE},
+    Error, Result,
+};
+
+#[derive(Debug, Clone)]
+pub struct Repaired {
+    pub data: Vec<u8>,
+    /// Human readable description of every change, empty when the input was already conformant.
+    pub fixes: Vec<String>,
+}
+
+/// Rewrites recoverable damage: wrong CRCs, data after or a missing/truncated `IEND`,
+/// duplicated unique chunks and chunks in an illegal order.
+///
+/// Image data is copied as is, so files whose IDAT stream is itself damaged stay broken.
+pub fn repair(src: &[u8]) -> Result<Repaired> {
+    let parsed = chunks::parse(src);
+    if !parsed.signature_ok {
+        return Err(Error::Format("not a PNG file".to_string()));
+    }
+    let mut fixes = Vec::new();
+    let mut kept: Vec<(u8, Chunk)> = Vec::new();
+    let mut seen_idat = false;
+    for chunk in &parsed.chunks {
+        if !chunk.kind.iter().all(u8::is_ascii_alphabetic) {
+            fixes.push(format!("dropped chunk with invalid type at offset {:#x}", chunk.offset));
+            continue;
+        }
+        if !chunk.crc_ok() {
+            fixes.push(format!("fixed CRC of {} at offset {:#x}", chunk.name(), chunk.offset));
+        }
+        if UNIQUE.contains(&&chunk.kind) && kept.iter().any(|(_, c)| c.kind == chunk.kind) {
+            fixes.push(format!("dropped duplicate {} at offset {:#x}", chunk.name(), chunk.offset));
+            continue;
+        }
+        seen_idat |= &chunk.kind == b"IDAT";
+        kept.push((slot(chunk, seen_idat), *chunk));
+    }
+    if !kept.iter().any(|(_, c)| &c.kind == b"IHDR") {
+        return Err(Error::Format("no IHDR chunk".to_string()));
+    }
+    if !seen_idat {
+        return Err(Error::Format("no IDAT chunk".to_string()));
+    }
+    if let Some(at) = parsed.truncated_at {
+        fixes.push(format!("dropped {} truncated bytes at offset {:#x}", src.len() - at, at));
+    }
+    if !parsed.trailing.is_empty() {
+        fixes.push(format!("dropped {} bytes after IEND", parsed.trailing.len()));
+    }
+    if !kept.iter().any(|(_, c)| &c.kind == b"IEND") {
+        fixes.push("added missing IEND".to_string());
+    }
+    kept.retain(|(_, c)| &c.kind != b"IEND");
+
+    let before = kept.iter().map(|(_, c)| c.offset).collect::<Vec<_>>();
+    kept.sort_by_key(|&(slot, _)| slot);
+    if kept.iter().map(|(_, c)| c.offset).ne(before) {
+        let order = kept.iter().map(|(_, c)| c.name()).collect::<Vec<_>>();
+        fixes.push(format!("reordered chunks to {}", order.join(",")));
+    }
+
+    let mut data = SIGNATURE.to_vec();
+    for (_, chunk) in &kept {
+        chunks::write_chunk(&mut data, &chunk.kind, chunk.data)?;
+    }
+    chunks::write_chunk(&mut data, b"IEND", &[])?;
+    Ok(Repaired { data, fixes })
+}
+
+/// Position class in a conformant file; chunks without ordering rules stay on their side of IDAT.
+fn slot(chunk: &Chunk, after_idat: bool) -> u8 {
+    match &chunk.kind {
+        b"IHDR" => 0,
+        kind if BEFORE_PLTE.contains(&kind) => 1,
+        b"PLTE" => 2,
+        kind if AFTER_PLTE.contains(&kind) || BEFORE_IDAT.contains(&kind) => 3,
+        b"IDAT" => 4,
+        _ if after_idat => 5,
+        _ => 3,
+    }
+}
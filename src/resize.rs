@@ -0,0 +1,93 @@
+use std::borrow::Cow;
+
+use png::{BitDepth, ColorType};
+
+use crate::Image;
+
+/// Largest size with the same aspect ratio whose longer edge is at most `max_edge`; never upscales.
+pub fn fit(width: u32, height: u32, max_edge: u32) -> (u32, u32) {
+    let longest = width.max(height);
+    if longest <= max_edge {
+        return (width, height);
+    }
+    let scale = |v: u32| ((v as u64 * max_edge as u64 + longest as u64 / 2) / longest as u64).max(1) as u32;
+    (scale(width), scale(height))
+}
+
+/// Resamples by area averaging, weighting color by alpha so transparent pixels do not bleed.
+///
+/// # Panics
+///
+/// Panics on indexed or sub-byte images; [`crate::decode`] never produces them.
+pub fn resize(image: &Image, width: u32, height: u32) -> Image<'static> {
+    assert!(image.color_type != ColorType::Indexed && image.bit_depth as u8 >= 8, "resize needs expanded samples");
+    let channels = image.color_type.samples();
+    // Premultiplied samples are averaged as they are.
+    let has_alpha = matches!(image.color_type, ColorType::GrayscaleAlpha | ColorType::Rgba) && !image.premultiplied;
+    let bytes = image.bit_depth as usize / 8;
+    let max = ((1u32 << (8 * bytes)) - 1) as f32;
+    let (src_w, src_h) = (image.width as usize, image.height as usize);
+    let (dst_w, dst_h) = (width as usize, height as usize);
+
+    let mut src = image.data.chunks_exact(bytes).map(|s| s.iter().fold(0u32, |v, &b| v << 8 | b as u32) as f32 / max).collect::<Vec<_>>();
+    if has_alpha {
+        for px in src.chunks_exact_mut(channels) {
+            let (color, alpha) = px.split_at_mut(channels - 1);
+            color.iter_mut().for_each(|c| *c *= alpha[0]);
+        }
+    }
+
+    let mut horizontal = vec![0f32; dst_w * src_h * channels];
+    let columns = weights(src_w, dst_w);
+    for y in 0..src_h {
+        for (x, column) in columns.iter().enumerate() {
+            let out = &mut horizontal[(y * dst_w + x) * channels..][..channels];
+            for &(sx, w) in column {
+                let px = &src[(y * src_w + sx) * channels..][..channels];
+                out.iter_mut().zip(px).for_each(|(o, &p)| *o += p * w);
+            }
+        }
+    }
+    let mut dst = vec![0f32; dst_w * dst_h * channels];
+    for (y, row) in weights(src_h, dst_h).iter().enumerate() {
+        for &(sy, w) in row {
+            let src_row = &horizontal[sy * dst_w * channels..][..dst_w * channels];
+            dst[y * dst_w * channels..][..dst_w * channels].iter_mut().zip(src_row).for_each(|(o, &p)| *o += p * w);
+        }
+    }
+
+    let mut data = Vec::with_capacity(dst.len() * bytes);
+    for px in dst.chunks_exact(channels) {
+        let alpha = if has_alpha { px[channels - 1] } else { 1.0 };
+        for (i, &v) in px.iter().enumerate() {
+            let v = if has_alpha && i < channels - 1 { if alpha > 0.0 { v / alpha } else { 0.0 } } else { v };
+            let v = (v * max).round().clamp(0.0, max) as u32;
+            data.extend_from_slice(&v.to_be_bytes()[4 - bytes..]);
+        }
+    }
+    Image {
+        width,
+        height,
+        color_type: image.color_type,
+        bit_depth: if bytes == 2 { BitDepth::Sixteen } else { BitDepth::Eight },
+        palette: None,
+        trns: None,
+        data: Cow::Owned(data),
+        text: image.text.clone(),
+        chunks: image.chunks.clone(),
+        premultiplied: image.premultiplied,
+    }
+}
+
+/// For each destination index, the source indices it covers and their normalized coverage.
+fn weights(src: usize, dst: usize) -> Vec<Vec<(usize, f32)>> {
+    let scale = src as f64 / dst as f64;
+    (0..dst)
+        .map(|i| {
+            let (start, end) = (i as f64 * scale, (i + 1) as f64 * scale);
+            let first = start.floor() as usize;
+            let last = (end.ceil() as usize).min(src);
+            (first..last).map(|j| (j, ((end.min(j as f64 + 1.0) - start.max(j as f64)) / scale) as f32)).collect()
+        })
+        .collect()
+}
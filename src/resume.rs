@@ -0,0 +1,178 @@
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    json::{self, Value},
+    paths,
+    walk::Input,
+};
+
+/// Progress of a batch run, kept as JSON lines: `["input", path, rel, same_as]` for every input
+/// found by the first run, then `["done", path]` as each input completes. A resumed run takes
+/// its inputs from the file instead of walking the directories again. Paths are strings, or
+/// arrays of their raw bytes (UTF-16 units on Windows) when they are not valid Unicode; a
+/// missing `same_as` is `null`.
+pub struct State {
+    file: File,
+    done: HashSet<PathBuf>,
+}
+
+impl State {
+    /// Opens the state at `path`, calling `scan` for the inputs only when it does not exist yet.
+    pub fn open(path: &Path, scan: impl FnOnce() -> io::Result<Vec<Input>>) -> io::Result<(State, Vec<Input>)> {
+        let path = paths::long(path);
+        let (mut inputs, mut done) = (Vec::new(), HashSet::new());
+        let existing = match fs::read_to_string(&path) {
+            Ok(text) => Some(text),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        match existing {
+            Some(text) => {
+                for line in text.lines() {
+                    // A run killed mid-write leaves a partial line behind; skip it.
+                    let Some(Value::Array(fields)) = json::parse(line) else {
+                        continue;
+                    };
+                    match &fields[..] {
+                        [Value::String(kind), path, rel, same_as] if kind == "input" => {
+                            let (Some(path), Some(rel)) = (from_json(path), from_json(rel)) else { continue };
+                            inputs.push(Input { path, rel, same_as: from_json(same_as).filter(|p| !p.as_os_str().is_empty()) });
+                        }
+                        [Value::String(kind), path] if kind == "done" => done.extend(from_json(path)),
+                        _ => {}
+                    }
+                }
+                if !text.is_empty() && !text.ends_with('\n') {
+                    writeln!(file)?;
+                }
+            }
+            None => {
+                inputs = scan()?;
+                let mut records = String::new();
+                for input in &inputs {
+                    let same_as = input.same_as.as_deref().map_or_else(|| "null".to_string(), to_json);
+                    records.push_str(&format!("[\"input\", {}, {}, {}]\n", to_json(&input.path), to_json(&input.rel), same_as));
+                }
+                file.write_all(records.as_bytes())?;
+                file.sync_data()?;
+            }
+        }
+        Ok((State { file, done }, inputs))
+    }
+
+    pub fn done(&self) -> usize {
+        self.done.len()
+    }
+
+    pub fn is_done(&self, path: &Path) -> bool {
+        self.done.contains(path)
+    }
+
+    pub fn mark_done(&mut self, path: &Path) -> io::Result<()> {
+        writeln!(self.file, "[\"done\", {}]", to_json(path))?;
+        self.done.insert(path.to_path_buf());
+        Ok(())
+    }
+}
+
+/// A path as a JSON string, or as an array of its raw units when it is not valid Unicode.
+fn to_json(path: &Path) -> String {
+    match path.to_str() {
+        Some(s) => json::string(s),
+        None => format!("[{}]", raw_units(path).iter().map(ToString::to_string).collect::<Vec<_>>().join(",")),
+    }
+}
+
+/// The inverse of [`to_json`].
+fn from_json(value: &Value) -> Option<PathBuf> {
+    match value {
+        Value::String(s) => Some(PathBuf::from(s)),
+        Value::Array(units) => {
+            let units = units
+                .iter()
+                .map(|unit| match unit {
+                    Value::Number(n) if n.fract() == 0.0 && (0.0..=u16::MAX as f64).contains(n) => Some(*n as u16),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()?;
+            from_raw_units(&units).map(PathBuf::from)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(unix)]
+fn raw_units(path: &Path) -> Vec<u16> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().iter().map(|&b| b as u16).collect()
+}
+
+#[cfg(unix)]
+fn from_raw_units(units: &[u16]) -> Option<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    units.iter().map(|&u| u8::try_from(u).ok()).collect::<Option<Vec<_>>>().map(OsString::from_vec)
+}
+
+#[cfg(windows)]
+fn raw_units(path: &Path) -> Vec<u16> {
+    use std::os::windows::ffi::OsStrExt;
+    path.as_os_str().encode_wide().collect()
+}
+
+#[cfg(windows)]
+fn from_raw_units(units: &[u16]) -> Option<OsString> {
+    use std::os::windows::ffi::OsStringExt;
+    Some(OsString::from_wide(units))
+}
+
+/// Other platforms only have Unicode paths, which are written as strings.
+#[cfg(not(any(unix, windows)))]
+fn raw_units(path: &Path) -> Vec<u16> {
+    path.to_string_lossy().encode_utf16().collect()
+}
+
+#[cfg(not(any(unix, windows)))]
+fn from_raw_units(units: &[u16]) -> Option<OsString> {
+    String::from_utf16(units).ok().map(OsString::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_non_utf8_paths_exact() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let dir = std::env::temp_dir().join(format!("compress-png-resume-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let state = dir.join("state.jsonl");
+        let _ = fs::remove_file(&state);
+        let odd = Path::new(OsStr::from_bytes(b"in/a\xff.png"));
+        let lossy = Path::new(OsStr::from_bytes(b"in/a\xef\xbf\xbd.png"));
+        let inputs = vec![
+            Input { path: odd.to_path_buf(), rel: PathBuf::from(OsStr::from_bytes(b"a\xff.png")), same_as: None },
+            Input { path: lossy.to_path_buf(), rel: PathBuf::from("b \"q\".png"), same_as: Some(odd.to_path_buf()) },
+        ];
+
+        let (mut first, scanned) = State::open(&state, || Ok(inputs.clone())).unwrap();
+        assert_eq!(scanned, inputs);
+        first.mark_done(odd).unwrap();
+        drop(first);
+
+        let (second, resumed) = State::open(&state, || panic!("scanned again")).unwrap();
+        assert_eq!(resumed, inputs);
+        assert_eq!(second.done(), 1);
+        assert!(second.is_done(odd));
+        assert!(!second.is_done(lossy));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+//! JSON-RPC 2.0 for editors and build daemons that optimize many files without starting a
+//! process for each.
+//!
+//! Messages are framed as in the Language Server Protocol, a `Content-Length` header followed
+//! by an empty line and the JSON body. Methods:
+//!
+//! - `optimize` with `{"path": ..., "output": ..., "effort": ...}`, only `path` required,
+//!   writes the optimized file to `output` or in place and returns its statistics. An input
+//!   that does not get smaller is copied to `output` as it is, or left alone in place.
+//! - `shutdown` answers and ends the session.
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use compress_png::{optimize_png, OptimizeOptions};
+
+use crate::{
+    batch::{self, Lock},
+    json::{self, Value},
+    paths, EffortArg,
+};
+
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+/// The request was well formed but optimizing failed.
+const FAILED: i32 = -32000;
+
+/// Serves requests on stdin and stdout, or on connections to a unix socket at `socket`.
+pub fn run(socket: Option<&Path>, opts: OptimizeOptions) -> io::Result<()> {
+    match socket {
+        None => serve(io::stdin().lock(), io::stdout().lock(), &opts),
+        Some(path) => listen(path, opts),
+    }
+}
+
+#[cfg(unix)]
+fn listen(path: &Path, opts: OptimizeOptions) -> io::Result<()> {
+    use std::{os::unix::net::UnixListener, sync::Arc, thread};
+
+    // A socket left behind by an earlier run would make binding fail.
+    if fs::metadata(path).is_ok_and(|m| std::os::unix::fs::FileTypeExt::is_socket(&m.file_type())) {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    eprintln!("listening on {}", paths::display(path));
+    let opts = Arc::new(opts);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("accept: {}", e);
+                continue;
+            }
+        };
+        let opts = opts.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(BufReader::new(&stream), &stream, &opts) {
+                eprintln!("connection: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn listen(_path: &Path, _opts: OptimizeOptions) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "unix sockets are not supported on this platform; use stdio"))
+}
+
+/// Answers requests until `shutdown` or the end of the input.
+fn serve(mut reader: impl BufRead, mut writer: impl Write, opts: &OptimizeOptions) -> io::Result<()> {
+    while let Some(body) = read_message(&mut reader)? {
+        let Some(request) = std::str::from_utf8(&body).ok().and_then(json::parse) else {
+            write_message(&mut writer, &error("null", PARSE_ERROR, "invalid JSON"))?;
+            continue;
+        };
+        // Requests without an id are notifications, which get no answer.
+        let id = request.get("id").map(to_json);
+        let method = request.get("method").and_then(Value::as_str);
+        let response = match method {
+            None => error("null", INVALID_REQUEST, "no method"),
+            Some("optimize") => match optimize(request.get("params").unwrap_or(&Value::Null), opts) {
+                Ok(result) => format!("{{\"jsonrpc\": \"2.0\", \"id\": {}, \"result\": {}}}", id.as_deref().unwrap_or("null"), result),
+                Err((code, message)) => error(id.as_deref().unwrap_or("null"), code, &message),
+            },
+            Some("shutdown") => format!("{{\"jsonrpc\": \"2.0\", \"id\": {}, \"result\": null}}", id.as_deref().unwrap_or("null")),
+            Some(method) => error(id.as_deref().unwrap_or("null"), METHOD_NOT_FOUND, &format!("unknown method {}", method)),
+        };
+        if id.is_some() || method.is_none() {
+            write_message(&mut writer, &response)?;
+        }
+        if method == Some("shutdown") {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn optimize(params: &Value, opts: &OptimizeOptions) -> Result<String, (i32, String)> {
+    let path = params.get("path").and_then(Value::as_str).ok_or((INVALID_PARAMS, "params.path must be a string".to_string()))?;
+    let output = match params.get("output") {
+        None | Some(Value::Null) => PathBuf::from(path),
+        Some(Value::String(output)) => PathBuf::from(output),
+        Some(_) => return Err((INVALID_PARAMS, "params.output must be a string".to_string())),
+    };
+    let mut opts = opts.clone();
+    if let Some(effort) = params.get("effort") {
+        let effort = effort.as_str().and_then(|s| EffortArg::from_str(s, true).ok()).ok_or((INVALID_PARAMS, "params.effort must be auto, fast, normal or max".to_string()))?;
+        opts.effort = effort.into();
+    }
+    let failed = |e: &dyn std::fmt::Display| (FAILED, format!("{}: {}", path, e));
+    // Locked like batch inputs, so that other instances do not optimize the same file meanwhile.
+    let in_place = output == Path::new(path) || batch::same_file(Path::new(path), &output);
+    let lock = Lock::acquire(Path::new(path), in_place).map_err(|e| match e.kind() {
+        io::ErrorKind::WouldBlock => failed(&"locked by another process"),
+        _ => failed(&e),
+    })?;
+    let src = lock.read().map_err(|e| failed(&e))?;
+    let (data, mut stats) = optimize_png(&src, &opts).map_err(|e| failed(&e))?;
+    if data.len() < src.len() {
+        batch::replace(&output, &data).map_err(|e| failed(&e))?;
+    } else {
+        stats.new_size = src.len();
+        if !in_place {
+            batch::replace(&output, &src).map_err(|e| failed(&e))?;
+        }
+    }
+    drop(lock);
+    eprintln!("{} {}", path, stats);
+    Ok(format!(
+        "{{\"output\": {}, \"originalSize\": {}, \"newSize\": {}, \"color\": {}, \"trial\": {}, \"elapsedMs\": {}}}",
+        json::string(&paths::display(&output)),
+        stats.original_size,
+        stats.new_size,
+        json::string(&format!("{:?}/{}->{:?}/{}", stats.from.0, stats.from.1 as u8, stats.to.0, stats.to.1 as u8)),
+        json::string(&stats.config.to_string()),
+        stats.elapsed.as_millis(),
+    ))
+}
+
+fn error(id: &str, code: i32, message: &str) -> String {
+    format!("{{\"jsonrpc\": \"2.0\", \"id\": {}, \"error\": {{\"code\": {}, \"message\": {}}}}}", id, code, json::string(message))
+}
+
+/// An id as it was sent; ids are strings or numbers.
+fn to_json(id: &Value) -> String {
+    match id {
+        Value::String(s) => json::string(s),
+        Value::Number(n) => n.to_string(),
+        _ => "null".to_string(),
+    }
+}
+
+/// The body of the next message, or `None` at the end of the input.
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut length = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return match length {
+                None => Ok(None),
+                Some(_) => Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = Some(value.trim().parse::<u64>().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("bad header: {}", line)))?);
+            }
+        }
+    }
+    let length = length.unwrap_or_default();
+    let mut body = Vec::new();
+    reader.take(length).read_to_end(&mut body)?;
+    if body.len() as u64 != length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(body))
+}
+
+fn write_message(writer: &mut impl Write, body: &str) -> io::Result<()> {
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The bodies of the answers to `bodies` sent as one session.
+    fn session(bodies: &[&str]) -> Vec<String> {
+        let input = bodies.iter().map(|body| format!("Content-Length: {}\r\n\r\n{}", body.len(), body)).collect::<String>();
+        let mut output = Vec::new();
+        serve(input.as_bytes(), &mut output, &OptimizeOptions::default()).unwrap();
+        let mut reader = &output[..];
+        std::iter::from_fn(|| read_message(&mut reader).unwrap()).map(|body| String::from_utf8(body).unwrap()).collect()
+    }
+
+    fn code(answer: &str) -> Option<f64> {
+        match json::parse(answer)?.get("error")?.get("code")? {
+            Value::Number(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn answers_requests_but_not_notifications() {
+        let answers = session(&[r#"{"jsonrpc": "2.0", "method": "nonsense"}"#, r#"{"jsonrpc": "2.0", "id": 7, "method": "shutdown"}"#]);
+        assert_eq!(answers, [r#"{"jsonrpc": "2.0", "id": 7, "result": null}"#]);
+    }
+
+    #[test]
+    fn rejects_invalid_and_deeply_nested_json() {
+        let deep = "[".repeat(200_000);
+        let answers = session(&["{\"id\": 1,", &deep]);
+        assert_eq!(answers.iter().map(|answer| code(answer)).collect::<Vec<_>>(), [Some(PARSE_ERROR as f64); 2]);
+    }
+}
@@ -0,0 +1,61 @@
+use std::io;
+
+use compress_png::{check, raw::{self, RawFormat}, OptimizeOptions};
+use png::{BitDepth, ColorType, Encoder};
+
+struct Fixture {
+    name: &'static str,
+    color: ColorType,
+    depth: BitDepth,
+    data: Vec<u8>,
+}
+
+const WIDTH: u32 = 37;
+const HEIGHT: u32 = 23;
+
+/// Small images built in memory that take the main reduction paths: palette with tRNS, true
+/// color, 16-bit gray and 1-bit gray.
+fn fixtures() -> Vec<Fixture> {
+    let xy = || (0..HEIGHT).flat_map(|y| (0..WIDTH).map(move |x| (x, y)));
+    let scattered = |x: u32, y: u32| ((x * 31 + y * 17) ^ (x * y)) % 40;
+    vec![
+        Fixture {
+            name: "palette+tRNS",
+            color: ColorType::Rgba,
+            depth: BitDepth::Eight,
+            data: xy().flat_map(|(x, y)| [(scattered(x, y) * 6) as u8, (scattered(y, x) % 4 * 80) as u8, 200, if scattered(x, y) < 5 { 0 } else { 0xFF }]).collect(),
+        },
+        Fixture { name: "rgb", color: ColorType::Rgb, depth: BitDepth::Eight, data: xy().flat_map(|(x, y)| [(x * 7) as u8, (y * 11) as u8, (x * y) as u8]).collect() },
+        Fixture { name: "gray16", color: ColorType::Grayscale, depth: BitDepth::Sixteen, data: xy().flat_map(|(x, y)| ((x * 1700 + y * 900) as u16).to_be_bytes()).collect() },
+        Fixture { name: "gray1", color: ColorType::Grayscale, depth: BitDepth::Eight, data: xy().map(|(x, y)| if (x / 3 + y / 2) % 2 == 0 { 0 } else { 0xFF }).collect() },
+    ]
+}
+
+/// Runs every fixture through decoding, reduction, the trials, encoding and checking, and fails
+/// unless the optimized file is valid and decodes to exactly the original samples.
+pub fn run() -> io::Result<()> {
+    let failed = |name: &str, what: String| io::Error::other(format!("self-test {}: {}", name, what));
+    let fixtures = fixtures();
+    for Fixture { name, color, depth, data } in &fixtures {
+        let mut src = Vec::new();
+        let mut encoder = Encoder::new(&mut src, WIDTH, HEIGHT);
+        encoder.set_color(*color);
+        encoder.set_depth(*depth);
+        encoder.write_header()?.write_image_data(data)?;
+        let (optimized, stats) = compress_png::optimize_png(&src, &OptimizeOptions::default())?;
+        if let Some(violation) = check::check(&optimized).first() {
+            return Err(failed(name, format!("invalid output: {}", violation)));
+        }
+        let [before, after] = [&src, &optimized].map(|png| {
+            let mut samples = Vec::new();
+            raw::write_raw(&mut samples, &compress_png::decode(png)?, RawFormat::Farbfeld)?;
+            io::Result::Ok(samples)
+        });
+        if before? != after? {
+            return Err(failed(name, "pixels changed".to_string()));
+        }
+        println!("self-test {}: ok {}", name, stats);
+    }
+    println!("self-test: {} fixtures ok", fixtures.len());
+    Ok(())
+}
@@ -0,0 +1,202 @@
+//! A minimal HTTP/1.1 server for using the optimizer as a sidecar.
+//!
+//! `POST /optimize` takes a PNG as the request body and answers with the optimized PNG, its
+//! statistics in `X-` headers. Connections are served by a fixed number of worker threads and
+//! closed after one request; as many more as there are workers wait for one, and the rest are
+//! answered `503 Service Unavailable` right away. Bodies need a `Content-Length`.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    num::NonZeroUsize,
+    sync::{
+        mpsc::{self, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use compress_png::{optimize_png, Error, OptimizeOptions};
+
+/// How long a client may take to send its whole request, and each write of the response.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Largest request line and headers taken together.
+const MAX_HEADER_BYTES: u64 = 16 << 10;
+/// How long turning a connection away may hold up accepting the next one.
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn text(status: &'static str, message: impl Into<String>) -> Response {
+        let mut body = message.into().into_bytes();
+        body.push(b'\n');
+        Response { status, headers: Vec::new(), content_type: "text/plain; charset=utf-8", body }
+    }
+}
+
+pub fn run(listen: SocketAddr, opts: OptimizeOptions, max_body: u64, jobs: NonZeroUsize) -> io::Result<()> {
+    let listener = TcpListener::bind(listen)?;
+    println!("listening on http://{} with {} workers", listener.local_addr()?, jobs);
+    let opts = Arc::new(opts);
+    let (queue, waiting) = mpsc::sync_channel::<TcpStream>(jobs.get());
+    let waiting = Arc::new(Mutex::new(waiting));
+    for _ in 0..jobs.get() {
+        let (opts, waiting) = (opts.clone(), waiting.clone());
+        thread::spawn(move || loop {
+            let Ok(stream) = waiting.lock().unwrap().recv() else { return };
+            let peer = stream.peer_addr().map_or_else(|_| "?".to_string(), |addr| addr.to_string());
+            if let Err(e) = handle(stream, &opts, max_body) {
+                eprintln!("{}: {}", peer, e);
+            }
+        });
+    }
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("accept: {}", e);
+                continue;
+            }
+        };
+        if let Err(TrySendError::Full(stream)) = queue.try_send(stream) {
+            let response = Response { headers: vec![("Retry-After", "1".to_string())], ..Response::text("503 Service Unavailable", "too many requests at once; try again") };
+            let _ = stream.set_write_timeout(Some(REJECT_TIMEOUT)).and_then(|()| write_response(&stream, &response));
+        }
+    }
+    Ok(())
+}
+
+fn handle(stream: TcpStream, opts: &OptimizeOptions, max_body: u64) -> io::Result<()> {
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(Deadline { stream: &stream, until: Instant::now() + TIMEOUT });
+    let response = respond(&mut reader, &stream, opts, max_body)?;
+    write_response(&stream, &response)
+}
+
+/// Reads from a stream until a point in time, however the client spreads its bytes out.
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    until: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request not received in time"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        (&*self.stream).read(buf)
+    }
+}
+
+fn write_response(stream: &TcpStream, response: &Response) -> io::Result<()> {
+    let mut out = io::BufWriter::new(stream);
+    write!(out, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n", response.status, response.content_type, response.body.len())?;
+    for (name, value) in &response.headers {
+        write!(out, "{}: {}\r\n", name, value)?;
+    }
+    out.write_all(b"\r\n")?;
+    out.write_all(&response.body)?;
+    out.flush()
+}
+
+/// Reads one request and answers it; `interim` gets the `100 Continue` a client may wait for.
+fn respond(reader: &mut impl BufRead, mut interim: impl Write, opts: &OptimizeOptions, max_body: u64) -> io::Result<Response> {
+    let mut head = reader.by_ref().take(MAX_HEADER_BYTES);
+    let mut line = String::new();
+    head.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default().to_string(), parts.next().unwrap_or_default().to_string());
+    let (mut length, mut chunked, mut expect_continue) = (None, false, false);
+    loop {
+        line.clear();
+        if head.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else { continue };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => length = value.parse::<u64>().ok(),
+            "transfer-encoding" => chunked = !value.eq_ignore_ascii_case("identity"),
+            "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            _ => {}
+        }
+    }
+    if head.limit() == 0 {
+        return Ok(Response::text("431 Request Header Fields Too Large", format!("headers are over the limit of {} bytes", MAX_HEADER_BYTES)));
+    }
+    let path = target.split('?').next().unwrap_or_default();
+    if path != "/optimize" {
+        return Ok(Response::text("404 Not Found", format!("{}: not found; POST a PNG to /optimize", path)));
+    }
+    if method != "POST" {
+        return Ok(Response { headers: vec![("Allow", "POST".to_string())], ..Response::text("405 Method Not Allowed", "POST a PNG to /optimize") });
+    }
+    let length = match length {
+        Some(length) if !chunked => length,
+        _ => return Ok(Response::text("411 Length Required", "the body needs a Content-Length")),
+    };
+    if length > max_body {
+        return Ok(Response::text("413 Content Too Large", format!("{} bytes is over the limit of {}", length, max_body)));
+    }
+    if expect_continue {
+        interim.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+    let mut body = Vec::with_capacity(length as usize);
+    reader.take(length).read_to_end(&mut body)?;
+    if body.len() as u64 != length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("body ended after {} of {} bytes", body.len(), length)));
+    }
+    match optimize_png(&body, opts) {
+        Ok((data, stats)) => {
+            println!("POST /optimize {}", stats);
+            let headers = vec![
+                ("X-Original-Size", stats.original_size.to_string()),
+                ("X-Optimized-Size", stats.new_size.to_string()),
+                ("X-Color", format!("{:?}/{}->{:?}/{}", stats.from.0, stats.from.1 as u8, stats.to.0, stats.to.1 as u8)),
+                ("X-Trial", stats.config.to_string()),
+                ("X-Elapsed-Ms", stats.elapsed.as_millis().to_string()),
+            ];
+            Ok(Response { status: "200 OK", headers, content_type: "image/png", body: data })
+        }
+        Err(e @ (Error::Format(_) | Error::Decode(_))) => {
+            println!("POST /optimize rejected: {}", e);
+            Ok(Response::text("400 Bad Request", e.to_string()))
+        }
+        Err(e) => {
+            eprintln!("POST /optimize failed: {}", e);
+            Ok(Response::text("500 Internal Server Error", e.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(request: &str) -> &'static str {
+        respond(&mut request.as_bytes(), io::sink(), &OptimizeOptions::default(), 16).unwrap().status
+    }
+
+    #[test]
+    fn rejects_bodies_without_a_length_or_over_the_limit() {
+        assert_eq!(status("POST /optimize HTTP/1.1\r\n\r\n"), "411 Length Required");
+        assert_eq!(status("POST /optimize HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"), "411 Length Required");
+        assert_eq!(status("POST /optimize HTTP/1.1\r\nContent-Length: 17\r\n\r\n"), "413 Content Too Large");
+        assert_eq!(status("POST /optimize HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc"), "400 Bad Request");
+    }
+
+    #[test]
+    fn rejects_oversized_headers() {
+        let request = format!("POST /optimize HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "a".repeat(MAX_HEADER_BYTES as usize));
+        assert_eq!(status(&request), "431 Request Header Fields Too Large");
+    }
+}
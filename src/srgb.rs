@@ -0,0 +1,227 @@
+use std::fmt;
+
+use png::{BitDepth, ColorType};
+
+use crate::{chunks, Error, Image, Result};
+
+/// XYZ (D50, the ICC connection space) to linear sRGB, Bradford adapted.
+const XYZ_D50_TO_SRGB: [[f32; 3]; 3] = [
+    [3.133_856, -1.616_867, -0.490_615],
+    [-0.978_768, 1.916_142, 0.033_454],
+    [0.071_945, -0.228_991, 1.405_243],
+];
+
+/// Color space a file declared before [`convert_to_srgb`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// Tagged `sRGB`; nothing to convert.
+    Srgb,
+    /// No color information; assumed to be sRGB.
+    Untagged,
+    /// `gAMA` alone; primaries are assumed to be those of sRGB.
+    Gamma,
+    /// An embedded matrix/TRC ICC profile.
+    Icc,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Source::Srgb => "sRGB",
+            Source::Untagged => "untagged",
+            Source::Gamma => "gAMA",
+            Source::Icc => "ICC profile",
+        })
+    }
+}
+
+/// Rewrites the samples of `image`, decoded from `src`, from the color space `src` declares to
+/// sRGB, following the PNG precedence of `sRGB` over `iCCP` over `gAMA`. The caller should tag
+/// the output with an `sRGB` chunk and drop the profile. Alpha is left alone.
+///
+/// Only matrix/TRC profiles are supported; profiles built from lookup tables are an error.
+pub fn convert_to_srgb(image: &mut Image, src: &[u8]) -> Result<Source> {
+    let parsed = chunks::parse(src);
+    let find = |kind: &[u8; 4]| parsed.chunks.iter().find(|c| &c.kind == kind);
+    if find(b"sRGB").is_some() {
+        return Ok(Source::Srgb);
+    }
+    let (source, transform) = if let Some(iccp) = find(b"iCCP") {
+        let nul = iccp.data.iter().position(|&b| b == 0).ok_or_else(|| Error::Format("iCCP without profile name".to_string()))?;
+        let profile = iccp
+            .data
+            .get(nul + 2..)
+            .and_then(|data| miniz_oxide::inflate::decompress_to_vec_zlib(data).ok())
+            .ok_or_else(|| Error::Format("iCCP profile does not decompress".to_string()))?;
+        (Source::Icc, Transform::from_icc(&profile)?)
+    } else if let Some(gama) = find(b"gAMA").filter(|c| c.data.len() == 4) {
+        let gamma = u32::from_be_bytes(gama.data.try_into().unwrap());
+        if gamma == 0 {
+            return Ok(Source::Untagged);
+        }
+        let curve = Curve::Gamma(100_000.0 / gamma as f32);
+        (Source::Gamma, Transform { curves: [curve.clone(), curve.clone(), curve], matrix: None })
+    } else {
+        return Ok(Source::Untagged);
+    };
+    transform.apply(image)?;
+    Ok(source)
+}
+
+#[derive(Debug, Clone)]
+enum Curve {
+    Gamma(f32),
+    /// Evenly spaced samples over `0.0..=1.0`.
+    Table(Vec<f32>),
+    /// ICC parametric curve type 4, which covers types 0 to 3: `(a·x + b)^g + e` for
+    /// `x >= d`, else `c·x + f`.
+    Parametric { g: f32, a: f32, b: f32, c: f32, d: f32, e: f32, f: f32 },
+}
+
+impl Curve {
+    fn eval(&self, x: f32) -> f32 {
+        match self {
+            Curve::Gamma(g) => x.powf(*g),
+            Curve::Table(table) => {
+                let pos = x * (table.len() - 1) as f32;
+                let i = (pos.floor() as usize).min(table.len() - 2);
+                table[i] + (table[i + 1] - table[i]) * (pos - i as f32)
+            }
+            &Curve::Parametric { g, a, b, c, d, e, f } => {
+                if x >= d {
+                    (a * x + b).max(0.0).powf(g) + e
+                } else {
+                    c * x + f
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Transform {
+    /// Per-channel decoding to linear light; gray images use the first.
+    curves: [Curve; 3],
+    /// Linear RGB to XYZ (D50), for RGB profiles.
+    matrix: Option<[[f32; 3]; 3]>,
+}
+
+impl Transform {
+    fn from_icc(profile: &[u8]) -> Result<Transform> {
+        let format = |msg: &str| Error::Format(format!("ICC profile: {}", msg));
+        if profile.len() < 132 {
+            return Err(format("truncated"));
+        }
+        // No more tags than the profile has room for, whatever the count says.
+        let count = (u32_at(profile, 128) as usize).min((profile.len() - 132) / 12);
+        let tag = |sig: &[u8; 4]| {
+            (0..count).find_map(|i| {
+                let entry = profile.get(132 + 12 * i..144 + 12 * i)?;
+                (&entry[..4] == sig).then(|| profile.get(u32_at(entry, 4) as usize..)?.get(..u32_at(entry, 8) as usize))?
+            })
+        };
+        let curve = |sig: &[u8; 4]| tag(sig).and_then(parse_curve).ok_or_else(|| format(&format!("missing or unsupported {} curve", String::from_utf8_lossy(sig))));
+        match &profile[16..20] {
+            b"GRAY" => {
+                let k = curve(b"kTRC")?;
+                Ok(Transform { curves: [k.clone(), k.clone(), k], matrix: None })
+            }
+            b"RGB " => {
+                let column = |sig: &[u8; 4]| {
+                    tag(sig).filter(|t| t.len() >= 20 && &t[..4] == b"XYZ ").map(|t| [8, 12, 16].map(|at| s15_fixed16(t, at))).ok_or_else(|| format("missing colorant"))
+                };
+                let (r, g, b) = (column(b"rXYZ")?, column(b"gXYZ")?, column(b"bXYZ")?);
+                let matrix = [0, 1, 2].map(|row| [r[row], g[row], b[row]]);
+                Ok(Transform { curves: [curve(b"rTRC")?, curve(b"gTRC")?, curve(b"bTRC")?], matrix: Some(matrix) })
+            }
+            other => Err(format(&format!("{} color space is not supported", String::from_utf8_lossy(other)))),
+        }
+    }
+
+    fn apply(&self, image: &mut Image) -> Result<()> {
+        let max = match image.bit_depth {
+            BitDepth::Eight => 255,
+            BitDepth::Sixteen => 65535,
+            _ => return Err(Error::Format("conversion needs 8 or 16-bit samples".to_string())),
+        };
+        let wide = max == 65535;
+        let (color_channels, samples) = match image.color_type {
+            ColorType::Grayscale => (1, 1),
+            ColorType::GrayscaleAlpha => (1, 2),
+            ColorType::Rgb => (3, 3),
+            ColorType::Rgba => (3, 4),
+            ColorType::Indexed => return Err(Error::Format("conversion needs expanded samples".to_string())),
+        };
+        // Decoding tables for every stored value.
+        let tables = self.curves.each_ref().map(|curve| (0..=max).map(|v| curve.eval(v as f32 / max as f32)).collect::<Vec<_>>());
+        let matrix = self.matrix.filter(|_| color_channels == 3).map(|m| multiply(&XYZ_D50_TO_SRGB, &m));
+        let bytes = if wide { 2 } else { 1 };
+        let read = |px: &[u8], k: usize| if wide { u16::from_be_bytes([px[2 * k], px[2 * k + 1]]) as usize } else { px[k] as usize };
+        for px in image.data.to_mut().chunks_exact_mut(samples * bytes) {
+            let mut linear = [0.0; 3];
+            for (k, value) in linear.iter_mut().enumerate().take(color_channels) {
+                *value = tables[k][read(px, k)];
+            }
+            if let Some(m) = &matrix {
+                linear = [0, 1, 2].map(|row| m[row][0] * linear[0] + m[row][1] * linear[1] + m[row][2] * linear[2]);
+            }
+            for (k, &value) in linear.iter().enumerate().take(color_channels) {
+                let encoded = (encode_srgb(value.clamp(0.0, 1.0)) * max as f32).round() as u16;
+                if wide {
+                    px[2 * k..2 * k + 2].copy_from_slice(&encoded.to_be_bytes());
+                } else {
+                    px[k] = encoded as u8;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_curve(tag: &[u8]) -> Option<Curve> {
+    match tag.get(..4)? {
+        b"curv" => {
+            let count = u32_at(tag.get(..12)?, 8) as usize;
+            let entries = tag.get(12..12 + 2 * count)?;
+            match count {
+                0 => Some(Curve::Gamma(1.0)),
+                1 => Some(Curve::Gamma(u16::from_be_bytes([entries[0], entries[1]]) as f32 / 256.0)),
+                _ => Some(Curve::Table(entries.chunks_exact(2).map(|v| u16::from_be_bytes([v[0], v[1]]) as f32 / 65535.0).collect())),
+            }
+        }
+        b"para" => {
+            let kind = u16::from_be_bytes([*tag.get(8)?, *tag.get(9)?]);
+            let arity = [1, 3, 4, 5, 7].get(kind as usize)?;
+            let p = (0..*arity).map(|i| tag.get(12 + 4 * i..16 + 4 * i).map(|_| s15_fixed16(tag, 12 + 4 * i))).collect::<Option<Vec<_>>>()?;
+            let g = p[0];
+            Some(match kind {
+                0 => Curve::Gamma(g),
+                1 => Curve::Parametric { g, a: p[1], b: p[2], c: 0.0, d: -p[2] / p[1], e: 0.0, f: 0.0 },
+                2 => Curve::Parametric { g, a: p[1], b: p[2], c: 0.0, d: -p[2] / p[1], e: p[3], f: p[3] },
+                3 => Curve::Parametric { g, a: p[1], b: p[2], c: p[3], d: p[4], e: 0.0, f: 0.0 },
+                _ => Curve::Parametric { g, a: p[1], b: p[2], c: p[3], d: p[4], e: p[5], f: p[6] },
+            })
+        }
+        _ => None,
+    }
+}
+
+fn encode_srgb(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn multiply(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    [0, 1, 2].map(|i| [0, 1, 2].map(|j| (0..3).map(|k| a[i][k] * b[k][j]).sum()))
+}
+
+fn u32_at(src: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(src[at..at + 4].try_into().unwrap())
+}
+
+fn s15_fixed16(src: &[u8], at: usize) -> f32 {
+    u32_at(src, at) as i32 as f32 / 65536.0
+}
@@ -0,0 +1,61 @@
+use std::{
+    io,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Lowers the scheduling priority of the process so other workloads on the machine go first.
+#[cfg(unix)]
+pub fn lower_priority() -> io::Result<()> {
+    extern "C" {
+        fn setpriority(which: std::ffi::c_int, who: std::ffi::c_uint, prio: std::ffi::c_int) -> std::ffi::c_int;
+    }
+    const PRIO_PROCESS: std::ffi::c_int = 0;
+    // SAFETY: plain system call on the calling process.
+    if unsafe { setpriority(PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn lower_priority() -> io::Result<()> {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentProcess() -> *mut std::ffi::c_void;
+        fn SetPriorityClass(process: *mut std::ffi::c_void, class: u32) -> i32;
+    }
+    const IDLE_PRIORITY_CLASS: u32 = 0x40;
+    // SAFETY: the pseudo handle of the current process needs no closing.
+    if unsafe { SetPriorityClass(GetCurrentProcess(), IDLE_PRIORITY_CLASS) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn lower_priority() -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--nice is not supported on this platform"))
+}
+
+/// Keeps the average rate of bytes read and written under a limit by sleeping between files.
+pub struct Throttle {
+    bytes_per_sec: f64,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: f64) -> Self {
+        Throttle { bytes_per_sec, started: Instant::now(), bytes: 0 }
+    }
+
+    /// Accounts for `bytes` of I/O and waits until the total is back within the limit.
+    pub fn pace(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        let due = Duration::try_from_secs_f64(self.bytes as f64 / self.bytes_per_sec).unwrap_or(Duration::MAX);
+        if let Some(wait) = due.checked_sub(self.started.elapsed()) {
+            thread::sleep(wait);
+        }
+    }
+}
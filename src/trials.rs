@@ -0,0 +1,193 @@
+//! Which trial configurations are generated for an image.
+//!
+//! Without a [`TrialSpec`], the matrix follows [`OptimizeOptions::effort`], with these pruning
+//! heuristics:
+//!
+//! - [`Effort::Auto`] resolves by pixel count: exhaustive up to 128×128 pixels, only the
+//!   adaptive filter from 4 megapixels on, every filter with the default strategy in between.
+//! - When at least half the rows repeat the row above, Up and no filtering are tried first,
+//!   and are the only filters [`Effort::Normal`] tries.
+//! - Images that pack below 8 bits try every configuration packed, then at 8 bits.
+//!
+//! A spec replaces the effort: exactly the configurations it names are tried, in its order,
+//! with only the repeated-rows reordering applied.
+
+use std::{fmt, str::FromStr};
+
+use png::FilterType;
+
+use crate::{Effort, Error, FilterMode, Image, OptimizeOptions, Strategy, TrialConfig, REPEATED_ROWS_RATIO};
+
+/// Bit depth candidates of a [`TrialSpec`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Depth {
+    /// [`Image::packed_depth`] when the image packs. Otherwise skipped, or the reduced depth when
+    /// it is the only depth selected.
+    Min,
+    /// The depth of the reduced image, normally 8.
+    Eight,
+}
+
+/// An explicit trial matrix, written like `filters=all;strategies=filtered,default;depths=min,8`.
+/// Keys may be left out; they default to every filter, the default strategy and both depths.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrialSpec {
+    pub filters: Vec<FilterMode>,
+    pub strategies: Vec<Strategy>,
+    pub depths: Vec<Depth>,
+}
+
+impl Default for TrialSpec {
+    fn default() -> Self {
+        TrialSpec { filters: FilterMode::ALL.to_vec(), strategies: vec![Strategy::Default], depths: vec![Depth::Min, Depth::Eight] }
+    }
+}
+
+impl TrialSpec {
+    /// The matrix an effort stands for, before the repeated-rows heuristic; `Auto` must be
+    /// resolved first.
+    pub fn for_effort(effort: Effort) -> TrialSpec {
+        TrialSpec {
+            filters: match effort {
+                Effort::Fast => vec![FilterMode::Adaptive],
+                _ => FilterMode::ALL.to_vec(),
+            },
+            strategies: match effort {
+                Effort::Max => Strategy::ALL.to_vec(),
+                _ => vec![Strategy::Default],
+            },
+            ..TrialSpec::default()
+        }
+    }
+
+    /// Every configuration, depth first, then strategy, then filter. Packed configurations are
+    /// left out unless `packs`, falling back to the reduced depth when that leaves no depth.
+    pub fn configs(&self, packs: bool) -> Vec<TrialConfig> {
+        let mut depths = self.depths.iter().filter(|&&d| packs || d == Depth::Eight).map(|&d| d == Depth::Min).collect::<Vec<_>>();
+        if depths.is_empty() && !self.depths.is_empty() {
+            depths.push(false);
+        }
+        depths
+            .into_iter()
+            .flat_map(|packed| self.strategies.iter().flat_map(move |&strategy| self.filters.iter().map(move |&filter| TrialConfig { filter, strategy, packed })))
+            .collect()
+    }
+}
+
+impl FromStr for TrialSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let format = |msg: String| Error::Format(format!("trial spec {:?}: {}", s, msg));
+        let mut spec = TrialSpec::default();
+        for part in s.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, values) = part.split_once('=').ok_or_else(|| format(format!("expected key=values, got {:?}", part)))?;
+            let values = values.split(',').map(str::trim).collect::<Vec<_>>();
+            let all = values.contains(&"all");
+            match key.trim() {
+                "filters" if all => spec.filters = FilterMode::ALL.to_vec(),
+                "filters" => spec.filters = values.iter().map(|v| FilterMode::ALL.into_iter().find(|f| f.to_string() == *v).ok_or_else(|| format(format!("unknown filter {:?}", v)))).collect::<Result<_, _>>()?,
+                "strategies" if all => spec.strategies = Strategy::ALL.to_vec(),
+                "strategies" => spec.strategies = values.iter().map(|v| Strategy::ALL.into_iter().find(|st| st.to_string() == *v).ok_or_else(|| format(format!("unknown strategy {:?}", v)))).collect::<Result<_, _>>()?,
+                "depths" if all => spec.depths = vec![Depth::Min, Depth::Eight],
+                "depths" => {
+                    spec.depths = values
+                        .iter()
+                        .map(|v| match *v {
+                            "min" => Ok(Depth::Min),
+                            "8" => Ok(Depth::Eight),
+                            _ => Err(format(format!("unknown depth {:?}, expected min or 8", v))),
+                        })
+                        .collect::<Result<_, _>>()?
+                }
+                other => return Err(format(format!("unknown key {:?}, expected filters, strategies or depths", other))),
+            }
+        }
+        if spec.filters.is_empty() || spec.strategies.is_empty() || spec.depths.is_empty() {
+            return Err(format("selects no trials".to_string()));
+        }
+        Ok(spec)
+    }
+}
+
+impl fmt::Display for TrialSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |items: Vec<String>| items.join(",");
+        write!(
+            f,
+            "filters={};strategies={};depths={}",
+            join(self.filters.iter().map(ToString::to_string).collect()),
+            join(self.strategies.iter().map(ToString::to_string).collect()),
+            join(self.depths.iter().map(|d| if *d == Depth::Min { "min" } else { "8" }.to_string()).collect()),
+        )
+    }
+}
+
+/// The effort used for `image` and the configurations to try, in order.
+pub(crate) fn plan(image: &Image, opts: &OptimizeOptions) -> (Effort, Vec<TrialConfig>) {
+    let effort = opts.effort.resolve(image.width as u64 * image.height as u64);
+    let packs = image.packed_depth().is_some();
+    let mut configs = match &opts.trials {
+        Some(spec) => spec.configs(packs),
+        None => TrialSpec::for_effort(effort).configs(packs),
+    };
+    if image.repeated_rows() as f64 >= image.height as f64 * REPEATED_ROWS_RATIO {
+        let cheap = |c: &TrialConfig| matches!(c.filter, FilterMode::Fixed(FilterType::Up | FilterType::NoFilter));
+        if opts.trials.is_none() && effort == Effort::Normal {
+            configs.retain(cheap);
+        } else {
+            configs.sort_by_key(|c| !cheap(c));
+        }
+    }
+    (effort, configs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_prints_a_spec() {
+        let spec = "filters=none,paeth;strategies=filtered;depths=min".parse::<TrialSpec>().unwrap();
+        assert_eq!(spec.filters, [FilterMode::Fixed(FilterType::NoFilter), FilterMode::Fixed(FilterType::Paeth)]);
+        assert_eq!(spec.strategies, [Strategy::Filtered]);
+        assert_eq!(spec.depths, [Depth::Min]);
+        assert_eq!(spec.to_string().parse::<TrialSpec>().unwrap(), spec);
+        assert_eq!("".parse::<TrialSpec>().unwrap(), TrialSpec::default());
+    }
+
+    #[test]
+    fn rejects_bad_specs() {
+        for bad in ["filters=", "filters=sideways", "depths=4", "colors=all", "filters"] {
+            assert!(bad.parse::<TrialSpec>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn min_depth_alone_falls_back_to_the_reduced_depth() {
+        let spec = "filters=up;depths=min".parse::<TrialSpec>().unwrap();
+        assert_eq!(spec.configs(true), [TrialConfig { filter: FilterMode::Fixed(FilterType::Up), strategy: Strategy::Default, packed: true }]);
+        assert_eq!(spec.configs(false), [TrialConfig { filter: FilterMode::Fixed(FilterType::Up), strategy: Strategy::Default, packed: false }]);
+        let both = "filters=up;depths=min,8".parse::<TrialSpec>().unwrap();
+        assert_eq!(both.configs(false).len(), 1);
+    }
+
+    #[test]
+    fn min_depth_runs_on_images_that_do_not_pack() {
+        let image = Image {
+            width: 2,
+            height: 2,
+            color_type: png::ColorType::Rgb,
+            bit_depth: png::BitDepth::Eight,
+            palette: None,
+            trns: None,
+            data: std::borrow::Cow::Owned((0..12).map(|i| i * 20).collect()),
+            text: Vec::new(),
+            chunks: Vec::new(),
+            premultiplied: false,
+        };
+        let opts = OptimizeOptions::builder().trials(Some("depths=min".parse().unwrap())).build();
+        let trials = image.trials(&opts).unwrap();
+        assert!(!trials.best().unwrap().config.packed);
+    }
+}
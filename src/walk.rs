@@ -0,0 +1,166 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, Metadata},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::paths;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Input {
+    pub path: PathBuf,
+    /// Path relative to the root it was found under, used to mirror the tree into an output directory.
+    pub rel: PathBuf,
+    /// Set when the same file was already collected under another name (hardlink or symlink).
+    pub same_as: Option<PathBuf>,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum FileId {
+    #[cfg(unix)]
+    Inode(u64, u64),
+    #[cfg(not(unix))]
+    Path(PathBuf),
+}
+
+#[cfg(unix)]
+fn file_id(_path: &Path, meta: &Metadata) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    Some(FileId::Inode(meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(path: &Path, _meta: &Metadata) -> Option<FileId> {
+    fs::canonicalize(paths::long(path)).ok().map(FileId::Path)
+}
+
+pub struct Walker {
+    pub recursive: bool,
+    pub follow_symlinks: bool,
+    visited_dirs: HashSet<FileId>,
+    seen_files: HashMap<FileId, PathBuf>,
+    pub inputs: Vec<Input>,
+}
+
+impl Walker {
+    pub fn new(recursive: bool, follow_symlinks: bool) -> Self {
+        Walker {
+            recursive,
+            follow_symlinks,
+            visited_dirs: HashSet::new(),
+            seen_files: HashMap::new(),
+            inputs: Vec::new(),
+        }
+    }
+
+    /// Adds a command line argument; symlinks given explicitly are always followed.
+    pub fn add_root(&mut self, root: &Path) -> io::Result<()> {
+        let meta = fs::metadata(paths::long(root))?;
+        if meta.is_dir() {
+            if !self.recursive {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is a directory (use --recursive)", paths::display(root))));
+            }
+            self.walk_dir(root, root, &meta)
+        } else {
+            let rel = root.file_name().map_or_else(|| root.to_path_buf(), PathBuf::from);
+            self.add_file(root, rel, &meta);
+            Ok(())
+        }
+    }
+
+    fn walk_dir(&mut self, root: &Path, dir: &Path, meta: &Metadata) -> io::Result<()> {
+        if let Some(id) = file_id(dir, meta) {
+            if !self.visited_dirs.insert(id) {
+                eprintln!("{}: directory cycle, skipped", paths::display(dir));
+                return Ok(());
+            }
+        }
+        let mut entries = fs::read_dir(paths::long(dir))?.map(|e| e.map(|e| e.path())).collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        for path in entries {
+            let mut meta = fs::symlink_metadata(paths::long(&path))?;
+            if meta.file_type().is_symlink() {
+                if !self.follow_symlinks {
+                    continue;
+                }
+                meta = match fs::metadata(paths::long(&path)) {
+                    Ok(meta) => meta,
+                    Err(e) => {
+                        eprintln!("{}: {}", paths::display(&path), e);
+                        continue;
+                    }
+                };
+            }
+            if meta.is_dir() {
+                self.walk_dir(root, &path, &meta)?;
+            } else if meta.is_file() && is_input(&path) {
+                let rel = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+                self.add_file(&path, rel, &meta);
+            }
+        }
+        Ok(())
+    }
+
+    fn add_file(&mut self, path: &Path, rel: PathBuf, meta: &Metadata) {
+        let same_as = file_id(path, meta).and_then(|id| match self.seen_files.get(&id) {
+            Some(first) => Some(first.clone()),
+            None => {
+                self.seen_files.insert(id, path.to_path_buf());
+                None
+            }
+        });
+        self.inputs.push(Input { path: path.to_path_buf(), rel, same_as });
+    }
+}
+
+/// Reorders `inputs` by `key`, then renames the first of several names for the same file as
+/// the one the others refer to, so it is still processed before them.
+pub fn sort_by_key<K: Ord>(inputs: &mut [Input], key: impl FnMut(&Input) -> K) {
+    inputs.sort_by_cached_key(key);
+    let mut first = HashMap::new();
+    for input in inputs {
+        let id = input.same_as.take().unwrap_or_else(|| input.path.clone());
+        match first.get(&id) {
+            Some(path) => input.same_as = Some(PathBuf::clone(path)),
+            None => {
+                first.insert(id, input.path.clone());
+            }
+        }
+    }
+}
+
+fn is_input(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png") || ext.eq_ignore_ascii_case("ico"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reports list inputs in the order they are processed, which must not depend on the order
+    /// the file system returns directory entries in.
+    #[test]
+    fn walks_directories_in_sorted_order() {
+        let dir = std::env::temp_dir().join(format!("compress-png-walk-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for name in ["b/2.png", "c.png", "a.PNG", "b/1.ico", "b/skip.txt", "0.png"] {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, b"").unwrap();
+        }
+        let mut walker = Walker::new(true, false);
+        walker.add_root(&dir).unwrap();
+        let rel = walker.inputs.iter().map(|input| input.rel.clone()).collect::<Vec<_>>();
+        assert_eq!(rel, ["0.png", "a.PNG", "b/1.ico", "b/2.png", "c.png"].map(PathBuf::from));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sorting_keeps_the_first_name_of_a_file_first() {
+        let input = |path: &str, same_as: Option<&str>| Input { path: PathBuf::from(path), rel: PathBuf::from(path), same_as: same_as.map(PathBuf::from) };
+        let mut inputs = vec![input("a.png", None), input("b.png", None), input("c.png", Some("a.png"))];
+        sort_by_key(&mut inputs, |input| std::cmp::Reverse(input.path.clone()));
+        assert_eq!(inputs, [input("c.png", None), input("b.png", None), input("a.png", Some("c.png"))]);
+    }
+}